@@ -0,0 +1,47 @@
+/*
+    Copyright 2013 Jesse 'Jeaye' Wilkerson
+    See licensing in LICENSE file, or at:
+        http://www.opensource.org/licenses/BSD-3-Clause
+
+    File: data/shaders/bsp.frag
+    Author: Jesse 'Jeaye' Wilkerson
+    Description:
+      The fragment shader program for rendering
+      textured, lightmapped BSP geometry. Faces without
+      a lightmap fall back to their vertex colors. Fogged
+      faces fade linearly into the fog color.
+*/
+
+#version 330
+
+uniform sampler2D texture0;
+uniform sampler2D lightmap0;
+uniform int use_lightmap = 1;
+uniform vec3 fog_color;
+uniform float fog_distance = 1.0f;
+uniform int use_fog = 0;
+
+in vec4 trans_color;
+in vec2 trans_lightmap_coord;
+in vec2 trans_coord;
+in float trans_depth;
+
+out vec4 out_color;
+
+void main() 
+{
+  vec4 light;
+  if(use_lightmap != 0)
+  { light = vec4(texture(lightmap0, trans_lightmap_coord).rgb, 1.0f); }
+  else
+  { light = vec4(trans_color.rgb, 1.0f); }
+
+  out_color = texture(texture0, trans_coord) * light;
+
+  if(use_fog != 0)
+  {
+    float fog = clamp(trans_depth / fog_distance, 0.0f, 1.0f);
+    out_color = vec4(mix(out_color.rgb, fog_color, fog), out_color.a);
+  }
+}
+
@@ -0,0 +1,37 @@
+/*
+    Copyright 2013 Jesse 'Jeaye' Wilkerson
+    See licensing in LICENSE file, or at:
+        http://www.opensource.org/licenses/BSD-3-Clause
+
+    File: data/shaders/bsp.vert
+    Author: Jesse 'Jeaye' Wilkerson
+    Description:
+      The vertex shader program for rendering
+      textured, lightmapped BSP geometry.
+*/
+
+#version 330
+
+uniform mat4x4 proj;
+uniform mat4x4 world;
+
+layout (location = 0) in vec4 in_position;
+layout (location = 1) in vec4 in_color;
+layout (location = 2) in vec2 in_lightmap_coord;
+layout (location = 3) in vec2 in_coord;
+
+out vec4 trans_color;
+out vec2 trans_lightmap_coord;
+out vec2 trans_coord;
+out float trans_depth;
+
+void main()
+{
+  vec4 view_position = world * in_position;
+  gl_Position = proj * view_position;
+  trans_depth = length(view_position.xyz);
+  trans_color = in_color;
+  trans_lightmap_coord = in_lightmap_coord;
+  trans_coord = in_coord;
+}
+
@@ -16,9 +16,9 @@
 
 extern mod std;
 extern mod opengles;
-use std::{ str, io };
 use gl = opengles::gl2;
-use math::{ Mat4x4 };
+use math::{ Mat4x4, Vec3f };
+use vfs::Vfs;
 pub use Shader = self::Shaderable;
 
 #[cfg(debug_shader)]
@@ -35,6 +35,7 @@ pub trait Shaderable
   pub fn get_uniform_location(&self, uniform: &str) -> gl::GLint;
   pub fn update_uniform_i32(&self, location: gl::GLint, i: i32);
   pub fn update_uniform_f32(&self, location: gl::GLint, i: f32);
+  pub fn update_uniform_vec3f(&self, location: gl::GLint, v: Vec3f);
   pub fn update_uniform_mat(&self, location: gl::GLint, mat: &Mat4x4);
 }
 
@@ -88,22 +89,11 @@ impl Debug_Shader
       frag_file_time: 0,
       valid: false,
     };
-    shader.vert_file_time = match Path(new_vert_file).stat()
-    {
-      Some(ref st) => st.st_mtime,
-      None => 0
-    };
-    shader.frag_file_time = match Path(new_frag_file).stat()
-    {
-      Some(ref st) => st.st_mtime,
-      None => 0
-    };
+    shader.vert_file_time = file_time(new_vert_file);
+    shader.frag_file_time = file_time(new_frag_file);
 
-    let fio = io::file_reader(&Path(new_vert_file)).unwrap();
-    let vert_src = str::from_bytes(fio.read_whole_stream());
-
-    let fio = io::file_reader(&Path(new_frag_file)).unwrap();
-    let frag_src = str::from_bytes(fio.read_whole_stream());
+    let vert_src = read_source(new_vert_file);
+    let frag_src = read_source(new_frag_file);
 
     assert!(shared::load(shader, vert_src, frag_src));
     shader.valid = true;
@@ -118,25 +108,14 @@ impl Shader for Debug_Shader
   pub fn bind(&mut self)
   {
     /* Get the time stamp on the files. */
-    let vert_time = match Path(self.vert_file).stat()
-    {
-      Some(ref st) => st.st_mtime,
-      None => 0
-    };
-    let frag_time = match Path(self.frag_file).stat()
-    {
-      Some(ref st) => st.st_mtime,
-      None => 0
-    };
+    let vert_time = file_time(self.vert_file);
+    let frag_time = file_time(self.frag_file);
 
     /* Check if the files are newer than before. */
     if vert_time > self.vert_file_time || frag_time > self.frag_file_time
     {
-      let fio = io::file_reader(&Path(self.vert_file)).unwrap();
-      let vert_src = str::from_bytes(fio.read_whole_stream());
-
-      let fio = io::file_reader(&Path(self.frag_file)).unwrap();
-      let frag_src = str::from_bytes(fio.read_whole_stream());
+      let vert_src = read_source(self.vert_file);
+      let frag_src = read_source(self.frag_file);
 
       self.valid = shared::load(self, vert_src, frag_src);
 
@@ -160,6 +139,10 @@ impl Shader for Debug_Shader
   pub fn update_uniform_f32(&self, location: gl::GLint, i: f32)
   { if self.valid { shared::update_uniform_f32(location, i); } }
 
+  #[inline(always)]
+  pub fn update_uniform_vec3f(&self, location: gl::GLint, v: Vec3f)
+  { if self.valid { shared::update_uniform_vec3f(location, v); } }
+
   #[inline(always)]
   pub fn update_uniform_mat(&self, location: gl::GLint, mat: &Mat4x4)
   { if self.valid { shared::update_uniform_mat(location, mat); } }
@@ -191,11 +174,8 @@ impl Release_Shader
   {
     let shader = @mut Release_Shader{ prog: 0, vert_obj: 0, frag_obj: 0 };
 
-    let fio = io::file_reader(&Path(vert_file)).unwrap();
-    let vert_src = str::from_bytes(fio.read_whole_stream());
-
-    let fio = io::file_reader(&Path(frag_file)).unwrap();
-    let frag_src = str::from_bytes(fio.read_whole_stream());
+    let vert_src = read_source(vert_file);
+    let frag_src = read_source(frag_file);
 
     assert!(shared::load(shader, vert_src, frag_src));
 
@@ -222,16 +202,41 @@ impl Shader for Release_Shader
   pub fn update_uniform_f32(&self, location: gl::GLint, i: f32)
   { shared::update_uniform_f32(location, i); }
 
+  #[inline(always)]
+  pub fn update_uniform_vec3f(&self, location: gl::GLint, v: Vec3f)
+  { shared::update_uniform_vec3f(location, v); }
+
   #[inline(always)]
   pub fn update_uniform_mat(&self, location: gl::GLint, mat: &Mat4x4)
   { shared::update_uniform_mat(location, mat) }
 }
 
+/* Shader sources come through the VFS, so they can live in pk3s. */
+priv fn read_source(file: &str) -> ~str
+{
+  match Vfs::get().read_str(file)
+  {
+    Some(src) => { src }
+    None => { fail!(fmt!("Failed to read shader %s", file)) }
+  }
+}
+
+/* Only loose files can be reloaded; anything else reads as 0. */
+#[cfg(debug_shader)]
+priv fn file_time(file: &str) -> libc::time_t
+{
+  match Vfs::get().modified_time(file)
+  {
+    Some(time) => { time }
+    None => { 0 }
+  }
+}
+
 mod shared
 {
   use gl = opengles::gl2;
   use std::{ str, cast };
-  use math::{ Mat4x4 };
+  use math::{ Mat4x4, Vec3f };
 
   #[path = "../util.rs"]
   mod util;
@@ -339,6 +344,10 @@ mod shared
   pub fn update_uniform_f32(location: gl::GLint, i: f32)
   { check!(gl::uniform_1f(location, i)); }
 
+  #[inline(always)]
+  pub fn update_uniform_vec3f(location: gl::GLint, v: Vec3f)
+  { check!(gl::uniform_3f(location, v.x, v.y, v.z)); }
+
   #[inline(always)]
   pub fn update_uniform_mat(location: gl::GLint, mat: &Mat4x4)
   { 
@@ -12,9 +12,10 @@
 extern mod std;
 extern mod opengles;
 extern mod stb_image;
-use std::{ vec, cast };
+use std::{ uint, vec, cast };
 use gl = opengles::gl2;
 use math::Vec2i;
+use vfs::Vfs;
 
 mod util;
 
@@ -33,13 +34,71 @@ impl Texture
 {
   #[inline(always)]
   pub fn new(targ: gl::GLenum, file: &str) -> Texture
+  {
+    match Texture::load(targ, file)
+    {
+      Some(tex) => { tex }
+      None => fail!(fmt!("Failed to load texture %s", file))
+    }
+  }
+
+  /* Like new, but leaves handling a missing or bad file to the caller. */
+  pub fn load(targ: gl::GLenum, file: &str) -> Option<Texture>
+  {
+    let buffer = match Vfs::get().read(file)
+    {
+      Some(buffer) => { buffer }
+      None => { return None; }
+    };
+
+    match stb_image::image::load_from_memory(buffer)
+    {
+      stb_image::image::ImageU8(ref image) => 
+      {
+        debug!(fmt!("Loaded image %s with %?x%?:%?", 
+                    file, image.width, image.height, image.depth));
+
+        let data = copy image.data;
+        let mut tex = Texture::new_with_data(targ, Vec2i::new(image.width as i32, image.height as i32),
+                                             image.depth as u32, data);
+        tex.filename = file.to_managed();
+        Some(tex)
+      }
+      _ => { None }
+    }
+  }
+
+  /* A magenta and black checkerboard; stands in for missing textures. */
+  pub fn new_checkerboard(targ: gl::GLenum) -> Texture
+  {
+    let size = 64;
+    let square = 8;
+    let mut data = vec::with_capacity(size * size * 3);
+    for uint::range(0, size) |y|
+    {
+      for uint::range(0, size) |x|
+      {
+        if ((x / square) + (y / square)) % 2 == 0
+        { data.push(255u8); data.push(0u8); data.push(255u8); }
+        else
+        { data.push(0u8); data.push(0u8); data.push(0u8); }
+      }
+    }
+
+    let tex = Texture::new_with_data(targ, Vec2i::new(size as i32, size as i32), 3, data);
+    tex.set_repeat(true);
+    tex
+  }
+
+  /* Creates a texture from raw, tightly packed, pixels of the given depth. */
+  pub fn new_with_data(targ: gl::GLenum, new_size: Vec2i, depth: u32, data: &[u8]) -> Texture
   {
     let mut tex = Texture
     {
       target: targ,
       obj: 0,
-      filename: file.to_managed(),
-      size: Vec2i::zero(),
+      filename: @"",
+      size: new_size,
     };
 
     let name = check!(gl::gen_textures(1));
@@ -47,55 +106,53 @@ impl Texture
     tex.obj = name[0];
     tex.bind(0);
 
-    check!(gl::pixel_store_i(gl::UNPACK_ALIGNMENT, 4));
+    check!(gl::pixel_store_i(gl::UNPACK_ALIGNMENT, 1));
     check!(gl::tex_parameter_i(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as gl::GLint));
     check!(gl::tex_parameter_i(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as gl::GLint));
     check!(gl::tex_parameter_i(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as gl::GLint));
     check!(gl::tex_parameter_i(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as gl::GLint));
 
-    match stb_image::image::load(file.to_owned())
+    let format = match depth
     {
-      stb_image::image::ImageU8(ref image) => 
-      {
-        debug!(fmt!("Loaded image %s with %?x%?:%?", 
-                    tex.filename, image.width, image.height, image.depth));
-
-        tex.size = Vec2i::new(image.width as i32, image.height as i32);
-        let format = match image.depth
-        {
-          3 => { gl::RGB },
-          4 => { gl::RGBA },
-          x => { error!(fmt!("Invalid texture depth %?", x)); gl::RGBA }
-        };
+      3 => { gl::RGB },
+      4 => { gl::RGBA },
+      x => { error!(fmt!("Invalid texture depth %?", x)); gl::RGBA }
+    };
+    assert!(data.len() >= (tex.size.x * tex.size.y) as uint * (depth as uint));
 
-        let data = copy image.data;
-        unsafe {
-          check!(gl::glTexImage2D
-          (
-            /* target */ gl::TEXTURE_2D, 
-            /* mipmap */ 0, 
-            /* internal */ gl::RGBA8 as gl::GLint, 
-            /* size */ tex.size.x as gl::GLsizei, tex.size.y as gl::GLsizei, 
-            /* border */ 0, 
-            /* external */ format, 
-            /* size type */ gl::UNSIGNED_BYTE, 
-            /* data */ cast::transmute(vec::raw::to_ptr(data))
-          ));
-        }
-      }
-      _ => fail!(fmt!("Failed to load texture %s", tex.filename))
+    unsafe {
+      check!(gl::glTexImage2D
+      (
+        /* target */ gl::TEXTURE_2D, 
+        /* mipmap */ 0, 
+        /* internal */ gl::RGBA8 as gl::GLint, 
+        /* size */ tex.size.x as gl::GLsizei, tex.size.y as gl::GLsizei, 
+        /* border */ 0, 
+        /* external */ format, 
+        /* size type */ gl::UNSIGNED_BYTE, 
+        /* data */ cast::transmute(vec::raw::to_ptr(data))
+      ));
     }
 
     tex
   }
 
   #[inline(always)]
-  pub fn bind(&self, _unit: gl::GLenum)
+  pub fn bind(&self, unit: gl::GLenum)
   {
-    //check!(gl::active_texture(gl::TEXTURE0 + unit));
+    check!(gl::active_texture(gl::TEXTURE0 + unit));
     check!(gl::bind_texture(gl::TEXTURE_2D, self.obj));
   }
 
+  /* Tiles the texture instead of clamping to its edges. */
+  pub fn set_repeat(&self, repeat: bool)
+  {
+    let wrap = if repeat { gl::REPEAT } else { gl::CLAMP_TO_EDGE };
+    self.bind(0);
+    check!(gl::tex_parameter_i(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, wrap as gl::GLint));
+    check!(gl::tex_parameter_i(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, wrap as gl::GLint));
+  }
+
   #[inline(always)]
   pub fn unbind(&self)
   { check!(gl::bind_texture(gl::TEXTURE_2D, 0)); }
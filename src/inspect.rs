@@ -0,0 +1,193 @@
+/*
+    Copyright 2013 Jesse 'Jeaye' Wilkerson
+    See licensing in LICENSE file, or at:
+        http://www.opensource.org/licenses/BSD-3-Clause
+
+    File: inspect.rs
+    Author: Jesse 'Jeaye' Wilkerson
+    Description:
+      Entry point of the headless map inspector;
+      no window and no GL, so it runs on build
+      boxes without a display.
+
+      Ex: q3-inspect maps/q3ctf1.bsp
+      Ex: q3-inspect --game-dir data --voxelize 200 maps/q3ctf1.bsp
+      Ex: q3-inspect --voxelize 400 --compare maps/q3ctf1.bsp
+      Ex: q3-inspect --voxelize 400 --workers 8 maps/q3ctf1.bsp
+*/
+
+extern mod extra;
+
+use std::{ os, uint };
+use std::iterator::IteratorUtil;
+
+#[path = "math/mod.rs"]
+mod math;
+
+#[path = "obj/mod.rs"]
+mod obj;
+
+#[path = "obj/bsp/mod.rs"]
+mod bsp;
+
+#[path = "obj/primitive/mod.rs"]
+mod primitive;
+
+#[path = "obj/voxel/mod.rs"]
+mod voxel;
+
+#[path = "obj/export/mod.rs"]
+mod export;
+
+#[path = "vfs/mod.rs"]
+mod vfs;
+
+static Face_Kind_Names: [&'static str, ..5] = [ "unknown", "polygon", "patch", "mesh", "billboard" ];
+
+fn main()
+{
+  let args = os::args();
+  let mut game_dir = ~"data";
+  let mut resolution = 0u32;
+  let mut compare = false;
+  let mut workers = voxel::Default_Workers;
+  let mut file = ~"";
+
+  let mut i = 1;
+  while i < args.len()
+  {
+    match args[i]
+    {
+      ~"--game-dir" if i + 1 < args.len() => { game_dir = copy args[i + 1]; i += 1; }
+      ~"--voxelize" if i + 1 < args.len() =>
+      {
+        resolution = match uint::from_str(args[i + 1])
+        {
+          Some(res) if res > 0 => { res as u32 }
+          _ => { usage(); return; }
+        };
+        i += 1;
+      }
+      ~"--workers" if i + 1 < args.len() =>
+      {
+        workers = match uint::from_str(args[i + 1])
+        {
+          Some(count) if count > 0 => { count }
+          _ => { usage(); return; }
+        };
+        i += 1;
+      }
+      ~"--compare" => { compare = true; }
+      ref arg if !arg.starts_with("--") && file.len() == 0 => { file = copy *arg; }
+      _ => { usage(); return; }
+    }
+    i += 1;
+  }
+
+  if file.len() == 0
+  { usage(); return; }
+
+  let vfs = vfs::Vfs::new();
+  if !vfs.mount_game_dir(game_dir)
+  { println(fmt!("Some of %s couldn't be mounted; see the warnings above", game_dir)); }
+
+  let st = extra::time::precise_time_s();
+  let map = match bsp::Map::load(file)
+  {
+    Ok(map) => { map }
+    Err(err) => { println(fmt!("Failed to load %s: %s", file, err.to_str())); os::set_exit_status(1); return; }
+  };
+  let et = extra::time::precise_time_s();
+
+  println(fmt!("%s (%s), loaded in %? seconds", file, map.get_version(), (et - st)));
+
+  println("\nLumps:");
+  for map.get_lump_info().iter().advance |&(ref name, length, count)|
+  {
+    match count
+    {
+      Some(count) => { println(fmt!("  %-14s %10u bytes %8u elements", *name, length, count)); }
+      None => { println(fmt!("  %-14s %10u bytes", *name, length)); }
+    }
+  }
+
+  println("\nFaces:");
+  let mut kinds = [0u, ..5];
+  for map.faces.iter().advance |face|
+  {
+    let kind = if face.kind > 0 && face.kind < 5 { face.kind as uint } else { 0 };
+    kinds[kind] += 1;
+  }
+  for uint::range(0, kinds.len()) |k|
+  {
+    if kinds[k] > 0
+    { println(fmt!("  %-14s %8u", Face_Kind_Names[k], kinds[k])); }
+  }
+  println(fmt!("  %-14s %8u", "triangles", map.tris.len()));
+
+  let (min, max) = (map.bb.min(), map.bb.max());
+  println(fmt!("\nBounds: (%f, %f, %f) to (%f, %f, %f)",
+               min.x as float, min.y as float, min.z as float,
+               max.x as float, max.y as float, max.z as float));
+
+  println(fmt!("\nEntities (%u):", map.entities.len()));
+  for map.entities.iter().advance |ent|
+  {
+    match ent.origin
+    {
+      Some(origin) =>
+      {
+        println(fmt!("  %s at (%f, %f, %f)", ent.class_name,
+                     origin.x as float, origin.y as float, origin.z as float));
+      }
+      None => { println(fmt!("  %s", ent.class_name)); }
+    }
+  }
+
+  if resolution > 0
+  {
+    if map.tris.len() == 0
+    { println("\nNothing to voxelize."); return; }
+
+    let st = extra::time::precise_time_s();
+    let mut grid = voxel::Voxel_Grid::new_with_workers(map.tris, resolution, workers);
+    let et = extra::time::precise_time_s();
+    grid.partition(|pos| map.find_cluster(pos));
+    let pt = extra::time::precise_time_s();
+
+    println(fmt!("\nVoxels at %? resolution, with %u workers:", resolution, workers));
+    println(fmt!("  %-14s %f", "voxel size", grid.voxel_size as float));
+    println(fmt!("  %-14s %u", "enabled", grid.len()));
+    println(fmt!("  %-14s %u", "regions", grid.regions.len()));
+    println(fmt!("  %-14s %? seconds", "voxelization", (et - st)));
+    println(fmt!("  %-14s %u", "tests", grid.tests));
+    println(fmt!("  %-14s %? seconds", "partitioning", (pt - et)));
+
+    /* What each of voxel::Map's draw modes would send to the GPU. */
+    let faces = grid.to_faces(false);
+    let greedy = grid.to_faces(true);
+    println("\nVoxel faces:");
+    println(fmt!("  %-14s %10u faces %10u triangles", "instanced", grid.len() * 6, grid.len() * 12));
+    println(fmt!("  %-14s %10u faces %10u triangles", "exposed", faces.faces, faces.tris()));
+    println(fmt!("  %-14s %10u faces %10u triangles", "greedy", greedy.faces, greedy.tris()));
+
+    /* Against testing every cell in each triangle's bounding box. */
+    if compare
+    {
+      let xs = extra::time::precise_time_s();
+      let exhaustive = voxel::Voxel_Grid::new_exhaustive(map.tris, resolution);
+      let xt = extra::time::precise_time_s();
+
+      println(fmt!("  %-14s %? seconds, %u tests", "exhaustive", (xt - xs), exhaustive.tests));
+      println(fmt!("  %-14s %fx", "speedup", ((xt - xs) / (et - st)) as float));
+      if exhaustive.len() != grid.len()
+      { println(fmt!("  Mismatch: %u voxels without the octree", exhaustive.len())); os::set_exit_status(1); }
+    }
+  }
+}
+
+fn usage()
+{
+  println("Usage: q3-inspect [--game-dir <dir>] [--voxelize <resolution> [--workers <count>] [--compare]] <map.bsp>");
+  os::set_exit_status(2);
+}
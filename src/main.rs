@@ -13,7 +13,8 @@ extern mod extra;
 extern mod opengles;
 extern mod glfw;
 
-use std::libc;
+use std::{ libc, f32 };
+use std::iterator::IteratorUtil;
 
 #[path = "gl/mod.rs"]
 mod gl;
@@ -25,6 +26,9 @@ mod math;
 #[path = "gl/check.rs"]
 mod check;
 
+#[path = "obj/mod.rs"]
+mod obj;
+
 #[path = "obj/bsp/mod.rs"]
 mod bsp; 
 
@@ -37,6 +41,12 @@ mod primitive;
 #[path = "obj/voxel/mod.rs"]
 mod voxel;
 
+#[path = "obj/export/mod.rs"]
+mod export;
+
+#[path = "vfs/mod.rs"]
+mod vfs;
+
 fn main()
 {
   glfw::set_error_callback(error_callback);
@@ -55,6 +65,11 @@ fn main()
     };
     window.make_context_current();
 
+    /* Everything is read through the VFS; pk3s in data override loose files. */
+    let vfs = vfs::Vfs::new();
+    if !vfs.mount_game_dir("data")
+    { error!("Some of the data directory couldn't be mounted"); }
+
     let camera = @mut gl::Camera::new(window);
     camera.init();
 
@@ -82,19 +97,98 @@ fn main()
 
     let ui_renderer = @mut ui::Renderer::new();
 
-    let map = bsp::Map::new("data/maps/q3ctf1.bsp");
+    let map = match bsp::Map::load("maps/q3ctf1.bsp")
+    {
+      Ok(map) => { @mut map }
+      Err(err) => { fail!(fmt!("Failed to load map: %s", err.to_str())) }
+    };
+    let bsp_renderer = bsp::Renderer::new(map);
 
     let st = extra::time::precise_time_s();
-    let vox_map = voxel::Map::new(map.tris, 200);
+    let mut grid = voxel::Voxel_Grid::new(map.tris, 200);
     let et = extra::time::precise_time_s();
     println(fmt!("Voxel map creation took %? seconds.", (et - st)));
+    grid.partition(|pos| map.find_cluster(pos));
+    let vox_map = voxel::Map::new(grid);
+
+    console_activator.add_accessor("map.wireframe", |_|
+    { vox_map.wireframe.to_str() });
+    console_activator.add_mutator("map.wireframe", |_, x|
+    {
+      vox_map.wireframe = if x == "true" { true }
+                          else { false };
+      None
+    });
+    console_activator.add_accessor("map.drawn_voxels", |_|
+    { vox_map.drawn_voxels.to_str() });
+    console_activator.add_accessor("map.drawn_faces", |_|
+    { vox_map.drawn_faces.to_str() });
+    console_activator.add_accessor("map.drawn_tris", |_|
+    { vox_map.drawn_tris.to_str() });
+
+    /* Whole instanced cubes, or only their exposed faces (merged, with greedy). */
+    console_activator.add_accessor("map.mode", |_|
+    {
+      match vox_map.mode
+      {
+        voxel::Draw_Instanced => { ~"instanced" }
+        voxel::Draw_Faces => { ~"faces" }
+        voxel::Draw_Greedy => { ~"greedy" }
+      }
+    });
+    console_activator.add_mutator("map.mode", |_, x|
+    {
+      match x
+      {
+        "instanced" => { vox_map.set_mode(voxel::Draw_Instanced); None }
+        "faces" => { vox_map.set_mode(voxel::Draw_Faces); None }
+        "greedy" => { vox_map.set_mode(voxel::Draw_Greedy); None }
+        _ => { Some(fmt!("Error: Unknown mode '%s'; expected instanced, faces, or greedy", x)) }
+      }
+    });
+    console_activator.add_accessor("map.total_voxels", |_|
+    { vox_map.total_voxels.to_str() });
+    console_activator.add_accessor("map.resident_chunks", |_|
+    { vox_map.resident_chunks().to_str() });
+    console_activator.add_accessor("map.paged_chunks", |_|
+    { vox_map.paged_chunks().to_str() });
+
+    /* Chunks further than this from the camera are paged out to disk. */
+    console_activator.add_accessor("map.page_distance", |_|
+    { vox_map.page_distance.to_str() });
+    console_activator.add_mutator("map.page_distance", |_, x|
+    {
+      match f32::from_str(x)
+      {
+        Some(distance) =>
+        {
+          match vox_map.enable_paging("cache/voxels", distance)
+          {
+            Ok(()) => { None }
+            Err(err) => { Some(fmt!("Error: %s", err)) }
+          }
+        }
+        None => { Some(fmt!("Error: Invalid distance '%s'", x)) }
+      }
+    });
+
+    /* Doors, platforms, and other movers. */
+    let mut movers = ~[];
+    for map.entities.iter().advance |ent|
+    {
+      match map.get_entity_model(ent)
+      {
+        Some(model) => { movers.push(model); }
+        None => { }
+      }
+    }
 
     /* Temp test for font loading. */
-    let font = ui::Font::new("data/fonts/test.ttf", 30);
+    let font = ui::Font::new("fonts/test.ttf", 30);
 
     /* Shader Creation. */
-    let vox_shader = @mut gl::Shader_Builder::new_with_files("data/shaders/voxel.vert", "data/shaders/voxel.frag");
-    let color_shader = @mut gl::Shader_Builder::new_with_files("data/shaders/color.vert", "data/shaders/color.frag");
+    let vox_shader = @mut gl::Shader_Builder::new_with_files("shaders/voxel.vert", "shaders/voxel.frag");
+    let color_shader = @mut gl::Shader_Builder::new_with_files("shaders/color.vert", "shaders/color.frag");
     vox_shader.bind();
 
     let proj_loc = vox_shader.get_uniform_location("proj");
@@ -112,6 +206,95 @@ fn main()
     {
       fmt!("%s.%s", env!("VERSION"), env!("COMMIT"))
     });
+    console_activator.add_accessor("bsp.drawn_tris",
+    |_|
+    { map.drawn_tris.to_str() });
+    console_activator.add_accessor("bsp.total_tris",
+    |_|
+    { map.total_tris.to_str() });
+
+    /* Ex: export obj out.obj
+       Ex: export gltf voxels.gltf voxel */
+    console_activator.add_function("export",
+    |_, params|
+    {
+      let args: ~[&str] = params.split_iter(' ').filter(|x| x.len() > 0).collect();
+      if args.len() < 2 || args.len() > 3
+      { Some(~"Usage: export <obj|gltf> <file> [bsp|voxel]") }
+      else
+      {
+        let mesh = match if args.len() == 3 { args[2] } else { "bsp" }
+        {
+          "bsp" => { Some(map.to_mesh()) }
+          "voxel" => { Some(vox_map.to_grid().to_mesh()) }
+          _ => { None }
+        };
+
+        match mesh
+        {
+          Some(mesh) =>
+          {
+            match export::write(&mesh, args[0], args[1])
+            {
+              Ok(()) => { console_activator.add_log(fmt!("Exported %s", args[1])); None }
+              Err(err) => { Some(fmt!("Error: %s", err)) }
+            }
+          }
+          None => { Some(fmt!("Error: Unknown map '%s'; expected bsp or voxel", args[2])) }
+        }
+      }
+    });
+
+    /* Ex: carve sphere 50
+       Ex: carve box 100 200
+       Ex: carve ray 1000 */
+    console_activator.add_function("carve",
+    |_, params|
+    {
+      let args: ~[&str] = params.split_iter(' ').filter(|x| x.len() > 0).collect();
+      let sizes: ~[f32] = args.iter().skip(1).filter_map(|x| f32::from_str(*x)).collect();
+      if args.len() < 2 || args.len() > 3 || sizes.len() != args.len() - 1
+      { Some(~"Usage: carve <sphere|box> <size> [distance] | carve ray <length>") }
+      else
+      {
+        /* Shapes are centered in front of the camera; just far enough
+           away to not include it, unless told otherwise. */
+        let forward = camera.view.get_forward();
+        let distance = if sizes.len() == 2 { sizes[1] } else { sizes[0] };
+        let center = camera.position + (forward * distance);
+        let removed = match args[0]
+        {
+          "sphere" => { Some(vox_map.carve_sphere(center, sizes[0])) }
+          "box" =>
+          {
+            let h = sizes[0] / 2.0;
+            let half = math::Vec3f::new(h, h, h);
+            Some(vox_map.carve_box(&math::BB3::new(center - half, center + half)))
+          }
+          "ray" => { Some(vox_map.carve_ray(camera.position, forward, sizes[0])) }
+          _ => { None }
+        };
+
+        match removed
+        {
+          Some(removed) => { console_activator.add_log(fmt!("Carved %u voxels", removed.len())); None }
+          None => { Some(fmt!("Error: Unknown shape '%s'; expected sphere, box, or ray", args[0])) }
+        }
+      }
+    });
+
+    /* Draw the lightmapped BSP instead of the voxels. */
+    let draw_bsp = @mut false;
+    console_activator.add_accessor("bsp.draw",
+    |_|
+    { draw_bsp.to_str() });
+    console_activator.add_mutator("bsp.draw",
+    |_, x|
+    {
+      *draw_bsp = if x == "true" { true }
+                  else { false };
+      None
+    });
 
     while !window.should_close()
     {
@@ -125,6 +308,12 @@ fn main()
       console.update(delta);
       camera.update(delta);
 
+      /* Potentially visible set from the camera's cluster. */
+      let cluster = map.find_cluster(camera.position);
+      map.cull(camera.position);
+      vox_map.page(camera.position);
+      vox_map.cull(camera.position, camera.view.get_forward(), |c| map.cluster_visible(cluster, c));
+
       vox_shader.bind();
       vox_shader.update_uniform_mat(proj_loc, &camera.projection);
       vox_shader.update_uniform_mat(world_loc, &camera.view);
@@ -138,11 +327,24 @@ fn main()
 
       check!(gl::clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT));
       {
-        //color_shader.bind();
-        //map.draw();
-
-        vox_shader.bind();
-        vox_map.draw();
+        if *draw_bsp
+        {
+          bsp_renderer.draw(map, camera);
+          for movers.iter().advance |mover|
+          { bsp_renderer.draw_model(map, mover, camera); }
+        }
+        else
+        {
+          match vox_map.mode
+          {
+            voxel::Draw_Instanced => { vox_shader.bind(); }
+            _ => { color_shader.bind(); }
+          }
+          vox_map.draw();
+        }
+
+        color_shader.bind();
+        bsp_renderer.draw_billboards(map, camera);
 
         ui_renderer.begin(camera);
         
@@ -9,6 +9,7 @@
       A 3D bounding box.
 */
 
+use std::cmp;
 use math::vec3::Vec3f;
 
 pub struct BB3 /* TODO: Check math on this shit. */
@@ -31,6 +32,24 @@ impl BB3
     BB3{  top_left: Vec3f::zero(), bottom_right: Vec3f::zero() } 
   }
 
+  /* Component-wise minimum corner. */
+  #[inline(always)]
+  pub fn min(&self) -> Vec3f
+  {
+    Vec3f::new( cmp::min(self.top_left.x, self.bottom_right.x),
+                cmp::min(self.top_left.y, self.bottom_right.y),
+                cmp::min(self.top_left.z, self.bottom_right.z))
+  }
+
+  /* Component-wise maximum corner. */
+  #[inline(always)]
+  pub fn max(&self) -> Vec3f
+  {
+    Vec3f::new( cmp::max(self.top_left.x, self.bottom_right.x),
+                cmp::max(self.top_left.y, self.bottom_right.y),
+                cmp::max(self.top_left.z, self.bottom_right.z))
+  }
+
   #[inline(always)]
   pub fn center(&self) -> Vec3f
   { Vec3f::new( (self.bottom_right.x - self.top_left.x) / 2.0,
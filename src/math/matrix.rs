@@ -88,6 +88,7 @@ impl Mat4x4
     mat
   }
   
+  /* Clockwise, looking from +x toward the origin. */
   #[inline(always)]
   pub fn new_rotation_x(deg: Component) -> Mat4x4
   {
@@ -102,6 +103,7 @@ impl Mat4x4
     mat
   }
 
+  /* Counter-clockwise, looking from +y toward the origin. */
   #[inline(always)]
   pub fn new_rotation_y(deg: Component) -> Mat4x4
   {
@@ -116,6 +118,7 @@ impl Mat4x4
     mat
   }
 
+  /* Clockwise, looking from +z toward the origin; (1, 0, 0) goes to (0, -1, 0) at 90 degrees. */
   #[inline(always)]
   pub fn new_rotation_z(deg: Component) -> Mat4x4
   {
@@ -0,0 +1,49 @@
+/*
+    Copyright 2013 Jesse 'Jeaye' Wilkerson
+    See licensing in LICENSE file, or at:
+        http://www.opensource.org/licenses/BSD-3-Clause
+
+    File: obj/bsp/billboard.rs
+    Author: Jesse 'Jeaye' Wilkerson
+    Description:
+      A camera-facing sprite (flare) decoded
+      from a billboard face.
+*/
+
+use math::Vec3f;
+use primitive::Vertex_PC;
+
+/* Half the width of a billboard quad, in world units. */
+pub static Billboard_Size: f32 = 0.5;
+
+pub struct Billboard
+{
+  position: Vec3f,
+  /* RGB in [0, 1]. */
+  color: Vec3f,
+  /* The direction the flare is visible from. */
+  normal: Vec3f,
+}
+
+impl Billboard
+{
+  #[inline(always)]
+  pub fn new(pos: Vec3f, col: Vec3f, norm: Vec3f) -> Billboard
+  { Billboard { position: pos, color: col, normal: norm } }
+
+  /* Pushes two triangles spanning the given camera axes. */
+  pub fn build(&self, right: Vec3f, up: Vec3f, out: &mut ~[Vertex_PC])
+  {
+    let r = right * Billboard_Size;
+    let u = up * Billboard_Size;
+
+    let bottom_left = Vertex_PC::new(self.position - r - u, self.color);
+    let bottom_right = Vertex_PC::new(self.position + r - u, self.color);
+    let top_right = Vertex_PC::new(self.position + r + u, self.color);
+    let top_left = Vertex_PC::new(self.position - r + u, self.color);
+
+    out.push(bottom_left); out.push(bottom_right); out.push(top_right);
+    out.push(bottom_left); out.push(top_right); out.push(top_left);
+  }
+}
+
@@ -0,0 +1,221 @@
+/*
+    Copyright 2013 Jesse 'Jeaye' Wilkerson
+    See licensing in LICENSE file, or at:
+        http://www.opensource.org/licenses/BSD-3-Clause
+
+    File: obj/bsp/entity.rs
+    Author: Jesse 'Jeaye' Wilkerson
+    Description:
+      Parser for the key/value entity
+      descriptions in a BSP's entity lump.
+*/
+
+use std::{ f32, util };
+use std::hashmap::HashMap;
+use std::iterator::IteratorUtil;
+use math::Vec3f;
+
+pub struct Entity
+{
+  class_name: ~str,
+  /* Already converted into our coordinate system. */
+  origin: Option<Vec3f>,
+  /* Yaw, in degrees. */
+  angle: f32,
+  target: ~str,
+  target_name: ~str,
+
+  /* Every key/value pair, including those above. */
+  pairs: HashMap<~str, ~str>,
+}
+
+impl Entity
+{
+  pub fn new(new_pairs: HashMap<~str, ~str>) -> Entity
+  {
+    let mut ent = Entity
+    {
+      class_name: ~"",
+      origin: None,
+      angle: 0.0,
+      target: ~"",
+      target_name: ~"",
+      pairs: new_pairs,
+    };
+
+    match ent.pairs.find(&~"classname")
+    {
+      Some(s) => { ent.class_name = copy *s; }
+      None => { }
+    }
+    match ent.pairs.find(&~"target")
+    {
+      Some(s) => { ent.target = copy *s; }
+      None => { }
+    }
+    match ent.pairs.find(&~"targetname")
+    {
+      Some(s) => { ent.target_name = copy *s; }
+      None => { }
+    }
+    match ent.pairs.find(&~"angle")
+    {
+      Some(s) =>
+      {
+        match f32::from_str(*s)
+        {
+          Some(a) => { ent.angle = a; }
+          None => { warn!("BSP: Invalid entity angle '%s'", *s); }
+        }
+      }
+      None => { }
+    }
+    match ent.pairs.find(&~"origin")
+    {
+      Some(s) => { ent.origin = parse_position(*s); }
+      None => { }
+    }
+
+    ent
+  }
+
+  /* Parses the whole entity lump. */
+  pub fn parse(buffer: &str) -> ~[Entity]
+  {
+    let mut entities = ~[];
+    let mut pairs = HashMap::new::<~str, ~str>();
+    let mut key: Option<~str> = None;
+    let mut token = ~"";
+    let mut in_entity = false;
+    let mut in_quote = false;
+
+    for buffer.iter().advance |ch|
+    {
+      if in_quote
+      {
+        if ch != '"'
+        { token.push_char(ch); loop; }
+
+        in_quote = false;
+        match util::replace(&mut key, None)
+        {
+          /* First string of the pair is the key. */
+          None => { key = Some(util::replace(&mut token, ~"")); }
+          Some(k) => { pairs.insert(k, util::replace(&mut token, ~"")); }
+        }
+        loop;
+      }
+
+      match ch
+      {
+        '{' =>
+        {
+          if in_entity
+          { warn!("BSP: Nested entity; discarding the outer one"); }
+          in_entity = true;
+          pairs = HashMap::new::<~str, ~str>();
+          key = None;
+        }
+        '}' =>
+        {
+          if !in_entity
+          { warn!("BSP: Unmatched entity brace"); loop; }
+          in_entity = false;
+          entities.push(Entity::new(util::replace(&mut pairs, HashMap::new::<~str, ~str>())));
+        }
+        '"' => { in_quote = true; }
+        _ => { /* Whitespace and trailing NULs. */ }
+      }
+    }
+
+    entities
+  }
+
+  #[inline(always)]
+  pub fn get<'a>(&'a self, key: &str) -> Option<&'a ~str>
+  { self.pairs.find(&key.to_owned()) }
+}
+
+/* Reads "x y z" and converts it like the vertex positions. */
+priv fn parse_position(s: &str) -> Option<Vec3f>
+{
+  let mut comps: ~[f32] = ~[];
+  for s.split_iter(' ').advance |x|
+  {
+    if x.len() == 0
+    { loop; }
+
+    match f32::from_str(x)
+    {
+      Some(f) => { comps.push(f); }
+      None => { warn!("BSP: Invalid entity position '%s'", s); return None; }
+    }
+  }
+
+  if comps.len() != 3
+  { warn!("BSP: Invalid entity position '%s'", s); return None; }
+
+  /* BSP likes Z to be up; we like Y to be up. Also apply the global size scale. */
+  Some(Vec3f::new(comps[0] / 32.0, comps[2] / 32.0, -comps[1] / 32.0))
+}
+
+
+#[cfg(test)]
+mod test
+{
+  use super::Entity;
+  use super::super::lump;
+
+  #[test]
+  fn quoted()
+  {
+    let entities = Entity::parse(
+      "{\n\"classname\" \"worldspawn\"\n\"message\" \"Braces { and } stay quoted\"\n}\n\
+       {\"classname\"\"info_player_deathmatch\" \"origin\" \"32 64 96\"  \"angle\" \"90\"\n\
+       \"target name\" \"\"}\n\x00\x00");
+    assert_eq!(entities.len(), 2);
+
+    assert_eq!(entities[0].class_name.as_slice(), "worldspawn");
+    assert_eq!(entities[0].get("message"), Some(&~"Braces { and } stay quoted"));
+
+    let spawn = &entities[1];
+    assert_eq!(spawn.class_name.as_slice(), "info_player_deathmatch");
+    assert_eq!(spawn.angle, 90.0);
+    assert_eq!(spawn.get("target name"), Some(&~""));
+    assert_eq!(spawn.get("missing"), None);
+    match spawn.origin
+    {
+      Some(origin) => { assert_eq!((origin.x, origin.y, origin.z), (1.0, 3.0, -2.0)); }
+      None => { fail!(~"Expected an origin"); }
+    }
+  }
+
+  #[test]
+  fn unterminated()
+  {
+    /* A stray closing brace is skipped; an open entity is dropped. */
+    let entities = Entity::parse("}\n{ \"classname\" \"light\" }\n{ \"classname\" \"item_armor_body\"\n");
+    assert_eq!(entities.len(), 1);
+    assert_eq!(entities[0].class_name.as_slice(), "light");
+
+    /* As is one whose quote never closes, swallowing its brace. */
+    let entities = Entity::parse("{ \"classname\" \"light }\n");
+    assert_eq!(entities.len(), 0);
+
+    /* A brace opening within an entity discards the outer one. */
+    let entities = Entity::parse("{ \"classname\" \"outer\"\n{ \"classname\" \"inner\" }\n");
+    assert_eq!(entities.len(), 1);
+    assert_eq!(entities[0].class_name.as_slice(), "inner");
+  }
+
+  #[test]
+  fn latin1()
+  {
+    /* Not UTF-8, but still text. */
+    let bytes = [ '{' as u8, '"' as u8, 'a' as u8, '"' as u8, ' ' as u8,
+                  '"' as u8, 0xE9, 0xFF, '"' as u8, '}' as u8 ];
+    let entities = Entity::parse(lump::from_latin1(bytes));
+    assert_eq!(entities.len(), 1);
+    assert_eq!(entities[0].get("a"), Some(&~"éÿ"));
+  }
+}
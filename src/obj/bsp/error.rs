@@ -0,0 +1,55 @@
+/*
+    Copyright 2013 Jesse 'Jeaye' Wilkerson
+    See licensing in LICENSE file, or at:
+        http://www.opensource.org/licenses/BSD-3-Clause
+
+    File: obj/bsp/error.rs
+    Author: Jesse 'Jeaye' Wilkerson
+    Description:
+      Reasons a BSP map can fail to load.
+*/
+
+pub enum Load_Error
+{
+  /* The file couldn't be read at all. */
+  Io_Error(~str),
+  /* The file is smaller than the header; (file size, header size) */
+  Truncated_Header(uint, uint),
+  Bad_Magic(~str),
+  Unsupported_Version(i32),
+  /* The lump lies outside of the file; (lump, offset, length) */
+  Lump_Out_Of_Range(~str, i32, i32),
+  /* The lump isn't a whole number of elements; (lump, length, element size) */
+  Misaligned_Lump(~str, uint, uint),
+  /* The lump is shorter than its own contents claim; (lump, needed, length) */
+  Truncated_Lump(~str, uint, uint),
+  /* A required lump has no elements. */
+  Empty_Lump(~str),
+  /* An element references something that doesn't exist; (lump, element, field, value) */
+  Bad_Index(~str, uint, ~str, i32),
+}
+
+impl ToStr for Load_Error
+{
+  pub fn to_str(&self) -> ~str
+  {
+    match *self
+    {
+      Io_Error(ref err) => { fmt!("Unable to read file: %s", *err) }
+      Truncated_Header(size, needed) =>
+      { fmt!("File is %? bytes; the header alone is %?", size, needed) }
+      Bad_Magic(ref magic) => { fmt!("Bad magic '%s'", *magic) }
+      Unsupported_Version(version) => { fmt!("Unsupported version 0x%x", version as uint) }
+      Lump_Out_Of_Range(ref lump, offset, length) =>
+      { fmt!("Lump '%s' at %? with length %? is out of range", *lump, offset, length) }
+      Misaligned_Lump(ref lump, length, size) =>
+      { fmt!("Lump '%s' has length %?; not a multiple of %?", *lump, length, size) }
+      Truncated_Lump(ref lump, needed, length) =>
+      { fmt!("Lump '%s' needs %? bytes but has %?", *lump, needed, length) }
+      Empty_Lump(ref lump) => { fmt!("Lump '%s' is empty", *lump) }
+      Bad_Index(ref lump, element, ref field, value) =>
+      { fmt!("Element %? of lump '%s' has an invalid %s: %?", element, *lump, *field, value) }
+    }
+  }
+}
+
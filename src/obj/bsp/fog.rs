@@ -0,0 +1,54 @@
+/*
+    Copyright 2013 Jesse 'Jeaye' Wilkerson
+    See licensing in LICENSE file, or at:
+        http://www.opensource.org/licenses/BSD-3-Clause
+
+    File: obj/bsp/fog.rs
+    Author: Jesse 'Jeaye' Wilkerson
+    Description:
+      A fog volume; the convex brush of a
+      fog effect, with the fogparms of its
+      shader.
+*/
+
+use std::iterator::IteratorUtil;
+use math::Vec3f;
+use super::lump;
+
+pub struct Fog
+{
+  /* The shader, like "textures/sfx/fog_timdm1". */
+  name: ~str,
+  /* Outward facing sides of the brush, already converted. */
+  planes: ~[lump::Plane],
+  /* RGB in [0, 1]. */
+  color: Vec3f,
+  /* Distance, in world units, at which the fog becomes opaque. */
+  distance: f32,
+}
+
+impl Fog
+{
+  #[inline(always)]
+  pub fn new(new_name: ~str, new_planes: ~[lump::Plane], new_color: Vec3f, q3_distance: f32) -> Fog
+  {
+    Fog
+    {
+      name: new_name,
+      planes: new_planes,
+      color: new_color,
+      distance: q3_distance / 32.0,
+    }
+  }
+
+  /* Whether the point is behind every side of the brush. */
+  pub fn contains(&self, point: Vec3f) -> bool
+  {
+    for self.planes.iter().advance |plane|
+    {
+      if plane.normal.dot(&point) - plane.distance > 0.0
+      { return false; }
+    }
+    true
+  }
+}
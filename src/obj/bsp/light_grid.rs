@@ -0,0 +1,164 @@
+/*
+    Copyright 2013 Jesse 'Jeaye' Wilkerson
+    See licensing in LICENSE file, or at:
+        http://www.opensource.org/licenses/BSD-3-Clause
+
+    File: obj/bsp/light_grid.rs
+    Author: Jesse 'Jeaye' Wilkerson
+    Description:
+      The light volume grid baked into a BSP,
+      used to light objects that move around
+      the map.
+*/
+
+use std::{ f32, uint };
+use math::Vec3f;
+use super::lump;
+
+/* Spacing of the samples, in Q3 units (Z up), unless
+   worldspawn says otherwise with 'gridsize'. */
+pub static Default_Grid_Size: [f32, ..3] = [64.0, 64.0, 128.0];
+
+pub struct Light_Grid
+{
+  /* In Q3 units and coordinates, like the lump. */
+  origin: [f32, ..3],
+  size: [f32, ..3],
+  /* Sample count along each axis. */
+  bounds: [i32, ..3],
+  /* X varies fastest, then Y, then Z. Colors are already overbright shifted. */
+  samples: ~[lump::Light_Vol],
+}
+
+impl Light_Grid
+{
+  #[inline(always)]
+  pub fn new() -> Light_Grid
+  {
+    Light_Grid
+    {
+      origin: [0.0, ..3],
+      size: Default_Grid_Size,
+      bounds: [0, ..3],
+      samples: ~[],
+    }
+  }
+
+  /* Lays the grid over the bounds of the world model. */
+  pub fn load(&mut self, world: &lump::Model, size: [f32, ..3], samples: ~[lump::Light_Vol], overbright_bits: uint)
+  {
+    self.size = size;
+    let mut count = 1;
+    for uint::range(0, 3) |i|
+    {
+      self.origin[i] = size[i] * f32::ceil(world.mins[i] / size[i]);
+      let max = size[i] * f32::floor(world.maxs[i] / size[i]);
+      self.bounds[i] = ((max - self.origin[i]) / size[i]) as i32 + 1;
+      count *= self.bounds[i];
+    }
+
+    if count <= 0 || samples.len() < count as uint
+    {
+      warn!("BSP: Light grid has %? samples; expected %?", samples.len(), count);
+      self.samples = ~[];
+      return;
+    }
+
+    self.samples = samples;
+    for self.samples.mut_iter().advance |sample|
+    {
+      lump::shift_color(&mut sample.ambient, overbright_bits);
+      lump::shift_color(&mut sample.directional, overbright_bits);
+    }
+
+    debug!("BSP: Light grid is %?x%?x%?.", self.bounds[0], self.bounds[1], self.bounds[2]);
+  }
+
+  /* Trilinearly interpolated (ambient, directional, direction) at the
+     point. Colors are in [0, 1]; the direction points toward the light. */
+  pub fn sample(&self, position: Vec3f) -> (Vec3f, Vec3f, Vec3f)
+  {
+    if self.samples.len() == 0
+    { return (Vec3f::new(1.0, 1.0, 1.0), Vec3f::zero(), Vec3f::new(0.0, 1.0, 0.0)); }
+
+    /* Back into Q3 coordinates. */
+    let point = [position.x * 32.0, -position.z * 32.0, position.y * 32.0];
+    let mut cell = [0i32, ..3];
+    let mut frac = [0.0f32, ..3];
+    for uint::range(0, 3) |i|
+    {
+      let v = (point[i] - self.origin[i]) / self.size[i];
+      let floor = f32::floor(v);
+      cell[i] = floor as i32;
+      frac[i] = v - floor;
+
+      if cell[i] < 0
+      { cell[i] = 0; frac[i] = 0.0; }
+      else if cell[i] >= self.bounds[i] - 1
+      { cell[i] = self.bounds[i] - 1; frac[i] = 0.0; }
+    }
+
+    let mut ambient = Vec3f::zero();
+    let mut directional = Vec3f::zero();
+    let mut direction = Vec3f::zero();
+    let mut total = 0.0f32;
+
+    for uint::range(0, 8) |corner|
+    {
+      let mut factor = 1.0f32;
+      let mut index = [0i32, ..3];
+      for uint::range(0, 3) |i|
+      {
+        if corner & (1 << i) != 0
+        {
+          factor *= frac[i];
+          index[i] = cell[i] + 1;
+        }
+        else
+        {
+          factor *= 1.0 - frac[i];
+          index[i] = cell[i];
+        }
+      }
+
+      if factor <= 0.0 || index[0] >= self.bounds[0] || index[1] >= self.bounds[1] || index[2] >= self.bounds[2]
+      { loop; }
+
+      let sample = &self.samples[ (index[2] * self.bounds[0] * self.bounds[1]) +
+                                  (index[1] * self.bounds[0]) + index[0]];
+
+      /* Samples inside of solid geometry are black; ignore them. */
+      if sample.ambient[0] == 0 && sample.ambient[1] == 0 && sample.ambient[2] == 0
+      { loop; }
+
+      total += factor;
+      ambient = ambient + (to_color(sample.ambient) * factor);
+      directional = directional + (to_color(sample.directional) * factor);
+
+      let lng = (sample.direction[0] as f32) * (2.0 * f32::consts::pi / 256.0);
+      let lat = (sample.direction[1] as f32) * (2.0 * f32::consts::pi / 256.0);
+      direction = direction + (Vec3f::new(f32::cos(lat) * f32::sin(lng),
+                                           f32::sin(lat) * f32::sin(lng),
+                                           f32::cos(lng)) * factor);
+    }
+
+    /* Everything around is solid. */
+    if total <= 0.0
+    { return (Vec3f::zero(), Vec3f::zero(), Vec3f::new(0.0, 1.0, 0.0)); }
+
+    /* Renormalize when some of the corners were skipped. */
+    if total < 0.99
+    {
+      ambient = ambient * (1.0 / total);
+      directional = directional * (1.0 / total);
+    }
+    direction.normalize();
+
+    (ambient, directional, lump::convert_direction(direction))
+  }
+}
+
+#[inline(always)]
+priv fn to_color(color: [u8, ..3]) -> Vec3f
+{ Vec3f::new((color[0] as f32) / 255.0, (color[1] as f32) / 255.0, (color[2] as f32) / 255.0) }
+
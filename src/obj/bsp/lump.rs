@@ -9,6 +9,8 @@
       Lump definitions for Q3 BSP maps.
 */
 
+use std::{ uint, cmp, cast };
+use std::iterator::IteratorUtil;
 use math::{ Vec2i, Vec2f, Vec3f, Vec4u8 };
 
 pub enum Lump_Type
@@ -29,10 +31,62 @@ pub enum Lump_Type
   Face_Type = 0x0D,
   Light_Map_Type = 0x0E,
   Light_Vol_Type = 0x0F,
-  Vis_Data_Type = 0x10
+  Vis_Data_Type = 0x10,
+  /* Quake Live advertisements; RBSP light grid array. */
+  Extra_Type = 0x11
 }
 
-static version: i32 = 0x2E;
+/* The flavors of BSP we can read. */
+pub enum Format
+{
+  /* IBSP 0x2E */
+  Quake3_Format,
+  /* IBSP 0x2F; an extra lump of advertisements. */
+  Quake_Live_Format,
+  /* RBSP 0x01; an extra lump indexing the light grid, with
+     multiple lightmap styles per vertex and face. */
+  Raven_Format,
+}
+
+pub static Quake3_Version: i32 = 0x2E;
+pub static Quake_Live_Version: i32 = 0x2F;
+pub static Raven_Version: i32 = 0x01;
+pub static Max_Lumps: uint = 18;
+
+/* Lightmap styles of RBSP vertices and faces. */
+pub static Max_Light_Styles: uint = 4;
+
+/* Texture content flags. */
+pub static Contents_Solid: i32 = 0x1;
+pub static Contents_Lava: i32 = 0x8;
+pub static Contents_Slime: i32 = 0x10;
+pub static Contents_Water: i32 = 0x20;
+pub static Contents_Fog: i32 = 0x40;
+pub static Contents_Player_Clip: i32 = 0x10000;
+pub static Contents_Monster_Clip: i32 = 0x20000;
+pub static Contents_Body: i32 = 0x2000000;
+pub static Contents_Trigger: i32 = 0x40000000;
+
+/* Texture surface flags. */
+pub static Surf_No_Damage: i32 = 0x1;
+pub static Surf_Slick: i32 = 0x2;
+pub static Surf_Sky: i32 = 0x4;
+pub static Surf_Ladder: i32 = 0x8;
+pub static Surf_No_Impact: i32 = 0x10;
+pub static Surf_No_Marks: i32 = 0x20;
+pub static Surf_Flesh: i32 = 0x40;
+pub static Surf_No_Draw: i32 = 0x80;
+pub static Surf_Hint: i32 = 0x100;
+pub static Surf_Skip: i32 = 0x200;
+pub static Surf_No_Lightmap: i32 = 0x400;
+pub static Surf_Metal_Steps: i32 = 0x1000;
+pub static Surf_No_Steps: i32 = 0x2000;
+pub static Surf_Non_Solid: i32 = 0x4000;
+
+/* Common trace masks. */
+pub static Mask_Solid: i32 = Contents_Solid;
+pub static Mask_Player_Solid: i32 = Contents_Solid | Contents_Player_Clip | Contents_Body;
+pub static Mask_Shot: i32 = Contents_Solid | Contents_Body;
 
 #[packed]
 pub struct Lump
@@ -52,18 +106,19 @@ impl Lump
 #[packed]
 pub struct Header
 {
-  /* Always "IBSP" */
+  /* "IBSP" or "RBSP" */
   magic: [i8, ..4],
-  /* Should be 0x2e for Q3 files. */
+  /* 0x2E for Q3, 0x2F for Quake Live, 0x01 for RBSP. */
   version: i32,
-  /* Fixed number of lumps. */
-  lumps: [Lump, ..17]
+  /* 17 lumps for Q3; 18 otherwise. */
+  num_lumps: uint,
+  lumps: [Lump, ..18]
 }
 impl Header
 {
   #[inline(always)]
   pub fn new() -> Header
-  { Header{ magic: [0, ..4], version: 0, lumps: [Lump::new(), ..17] } }
+  { Header{ magic: [0, ..4], version: 0, num_lumps: 0, lumps: [Lump::new(), ..18] } }
 }
 
 #[packed]
@@ -71,7 +126,8 @@ pub struct Entity
 {
   /* Size of the buffer. */
   size: i32,
-  buffer: ~[i8] /* TODO: Read binary into this? */
+  /* Raw entity descriptions; see entity.rs. */
+  buffer: ~[u8]
 }
 impl Entity
 {
@@ -86,6 +142,13 @@ pub struct Texture
   surface_flags: i32,
   content_flags: i32
 }
+impl Texture
+{
+  /* The name up to the first NUL, like "textures/base_wall/concrete". */
+  #[inline(always)]
+  pub fn get_name(&self) -> ~str
+  { from_c_name(&self.name) }
+}
 
 #[packed]
 pub struct Plane
@@ -115,8 +178,8 @@ pub struct Leaf
   /* Areaportal area. */
   area: i32,
   /* Bounding box. */ /* TODO: Struct? */
-  mins: [i32, ..2],
-  maxs: [i32, ..2],
+  mins: [i32, ..3],
+  maxs: [i32, ..3],
   /* First leaf face. */
   face: i32,
   num_faces: i32,
@@ -143,8 +206,8 @@ pub struct Leaf_Brush
 pub struct Model
 {
   /* Bounding box. */
-  mins: [f32, ..2],
-  maxs: [f32, ..2],
+  mins: [f32, ..3],
+  maxs: [f32, ..3],
   /* First face. */
   face: i32,
   num_faces: i32,
@@ -212,6 +275,13 @@ pub struct Effect
   /* Always seems to be 5. */
   unknown: i32
 }
+impl Effect
+{
+  /* The shader name, like "textures/sfx/fog_timdm1". */
+  #[inline(always)]
+  pub fn get_name(&self) -> ~str
+  { from_c_name(&self.name) }
+}
 
 #[packed]
 pub struct Face
@@ -263,8 +333,10 @@ impl Face
 #[packed]
 pub struct Light_Map
 {
-  data: [[[u8, ..128], ..128], ..3]
+  /* Rows of RGB texels. */
+  data: [[[u8, ..3], ..128], ..128]
 }
+pub static Light_Map_Size: i32 = 128;
 
 #[packed]
 pub struct Light_Vol
@@ -277,6 +349,23 @@ pub struct Light_Vol
   direction: [u8, ..2] /* 0 = phi; 1 = theta */
 }
 
+/* The RBSP vertex data beyond the first light style. */
+pub struct Raven_Vertex
+{
+  lightmap_coords: [Vec2f, ..4],
+  colors: [Vec4u8, ..4]
+}
+
+/* The RBSP face data beyond the first light style. */
+pub struct Raven_Face
+{
+  /* 255 means the style is unused. */
+  lightmap_styles: [u8, ..4],
+  vertex_styles: [u8, ..4],
+  lightmaps: [i32, ..4],
+  lightmap_corners: [Vec2i, ..4]
+}
+
 #[packed]
 pub struct Vis_Data
 {
@@ -284,4 +373,410 @@ pub struct Vis_Data
   bytes_per_cluster: i32,
   buffer: ~[u8]
 }
+impl Vis_Data
+{
+  #[inline(always)]
+  pub fn new() -> Vis_Data
+  { Vis_Data { num_clusters: 0, bytes_per_cluster: 0, buffer: ~[] } }
+
+  /* Whether cluster 'to' is potentially visible from cluster 'from'. */
+  pub fn is_visible(&self, from: i32, to: i32) -> bool
+  {
+    /* Outside the map, or no vis data at all; assume everything is visible. */
+    if from < 0 || self.buffer.len() == 0
+    { return true; }
+    if to < 0 || from >= self.num_clusters || to >= self.num_clusters
+    { return false; }
+
+    let byte = self.buffer[(from * self.bytes_per_cluster) + (to / 8)];
+    (byte & (1 << (to % 8)) as u8) != 0
+  }
+}
+
+/* BSP likes Z to be up; we like Y to be up. Also applies the global size scale. */
+#[inline(always)]
+pub fn convert_position(pos: Vec3f) -> Vec3f
+{ Vec3f::new(pos.x / 32.0, pos.z / 32.0, -pos.y / 32.0) }
+
+#[inline(always)]
+pub fn convert_direction(dir: Vec3f) -> Vec3f
+{ Vec3f::new(dir.x, dir.z, -dir.y) }
+
+#[inline(always)]
+pub fn convert_plane(plane: Plane) -> Plane
+{ Plane { normal: convert_direction(plane.normal), distance: plane.distance / 32.0 } }
+
+/* Q3 stores lighting at a fraction of its intensity so that it can
+   go overbright; scale it back up, keeping the hue when it saturates. */
+pub fn shift_color(color: &mut [u8], bits: uint)
+{
+  let mut r = (color[0] as uint) << bits;
+  let mut g = (color[1] as uint) << bits;
+  let mut b = (color[2] as uint) << bits;
+
+  let max = cmp::max(r, cmp::max(g, b));
+  if max > 255
+  {
+    r = (r * 255) / max;
+    g = (g * 255) / max;
+    b = (b * 255) / max;
+  }
+
+  color[0] = r as u8;
+  color[1] = g as u8;
+  color[2] = b as u8;
+}
+
+/* Indexed by Lump_Type. */
+pub static Lump_Names: [&'static str, ..18] =
+[
+  "entities", "textures", "planes", "nodes", "leaves", "leaf faces",
+  "leaf brushes", "models", "brushes", "brush sides", "vertices",
+  "mesh verts", "effects", "faces", "lightmaps", "light volumes", "vis data",
+  "extra"
+];
+
+/* Lump count of each format. */
+#[inline(always)]
+pub fn num_lumps(format: Format) -> uint
+{
+  match format
+  {
+    Quake3_Format => { 17 }
+    Quake_Live_Format | Raven_Format => { 18 }
+  }
+}
+
+#[inline(always)]
+pub fn header_size(num_lumps: uint) -> uint
+{ 8 + (num_lumps * 8) }
+
+#[inline(always)]
+pub fn lump_name(kind: Lump_Type) -> ~str
+{ Lump_Names[kind as uint].to_owned() }
+
+/***** Decoding *****/
+
+/* Sizes, in bytes, of each element as stored on disk. */
+pub static Texture_Size: uint = 72;
+pub static Plane_Size: uint = 16;
+pub static Node_Size: uint = 36;
+pub static Leaf_Size: uint = 48;
+pub static Leaf_Face_Size: uint = 4;
+pub static Leaf_Brush_Size: uint = 4;
+pub static Model_Size: uint = 40;
+pub static Brush_Size: uint = 12;
+pub static Brush_Side_Size: uint = 8;
+pub static Vertex_Size: uint = 44;
+pub static Mesh_Vert_Size: uint = 4;
+pub static Effect_Size: uint = 72;
+pub static Face_Size: uint = 104;
+pub static Light_Map_Bytes: uint = 128 * 128 * 3;
+pub static Light_Vol_Size: uint = 8;
+pub static Raven_Brush_Side_Size: uint = 12;
+pub static Raven_Vertex_Size: uint = 80;
+pub static Raven_Face_Size: uint = 148;
+pub static Raven_Light_Vol_Size: uint = 30;
+pub static Light_Array_Size: uint = 2;
+
+/* Element sizes of each lump, in lump order; 0 where elements aren't a fixed size. */
+pub static Element_Sizes: [uint, ..18] =
+[
+  0, Texture_Size, Plane_Size, Node_Size, Leaf_Size, Leaf_Face_Size, Leaf_Brush_Size,
+  Model_Size, Brush_Size, Brush_Side_Size, Vertex_Size, Mesh_Vert_Size, Effect_Size,
+  Face_Size, Light_Map_Bytes, Light_Vol_Size, 0, 0
+];
+pub static Raven_Element_Sizes: [uint, ..18] =
+[
+  0, Texture_Size, Plane_Size, Node_Size, Leaf_Size, Leaf_Face_Size, Leaf_Brush_Size,
+  Model_Size, Brush_Size, Raven_Brush_Side_Size, Raven_Vertex_Size, Mesh_Vert_Size, Effect_Size,
+  Raven_Face_Size, Light_Map_Bytes, Raven_Light_Vol_Size, 0, Light_Array_Size
+];
+
+#[inline(always)]
+pub fn element_size(format: Format, index: uint) -> uint
+{
+  match format
+  {
+    Raven_Format => { Raven_Element_Sizes[index] }
+    _ => { Element_Sizes[index] }
+  }
+}
+
+/* A NUL padded name field, up to the first NUL. */
+pub fn from_c_name(name: &[i8]) -> ~str
+{
+  let mut out = ~"";
+  for name.iter().advance |&c|
+  {
+    if c == 0
+    { break; }
+    out.push_char(c as u8 as char);
+  }
+  out
+}
+
+/* Q3 treats text as bytes; reading them as Latin-1, like
+   from_c_name, means any editor's encoding still loads. */
+pub fn from_latin1(bytes: &[u8]) -> ~str
+{
+  let mut out = ~"";
+  for bytes.iter().advance |&b|
+  { out.push_char(b as char); }
+  out
+}
+
+/* Little endian readers; 'at' is a byte offset into the data. */
+#[inline(always)]
+pub fn read_u32(data: &[u8], at: uint) -> u32
+{
+  (data[at] as u32) | ((data[at + 1] as u32) << 8) |
+  ((data[at + 2] as u32) << 16) | ((data[at + 3] as u32) << 24)
+}
+
+#[inline(always)]
+pub fn read_i32(data: &[u8], at: uint) -> i32
+{ read_u32(data, at) as i32 }
+
+#[inline(always)]
+pub fn read_f32(data: &[u8], at: uint) -> f32
+{ unsafe { cast::transmute::<u32, f32>(read_u32(data, at)) } }
+
+#[inline(always)]
+pub fn read_u16(data: &[u8], at: uint) -> u16
+{ (data[at] as u16) | ((data[at + 1] as u16) << 8) }
+
+#[inline(always)]
+pub fn read_vec2i(data: &[u8], at: uint) -> Vec2i
+{ Vec2i::new(read_i32(data, at), read_i32(data, at + 4)) }
+
+#[inline(always)]
+pub fn read_vec2f(data: &[u8], at: uint) -> Vec2f
+{ Vec2f::new(read_f32(data, at), read_f32(data, at + 4)) }
+
+#[inline(always)]
+pub fn read_vec3f(data: &[u8], at: uint) -> Vec3f
+{ Vec3f::new(read_f32(data, at), read_f32(data, at + 4), read_f32(data, at + 8)) }
+
+#[inline(always)]
+pub fn read_i32x3(data: &[u8], at: uint) -> [i32, ..3]
+{ [read_i32(data, at), read_i32(data, at + 4), read_i32(data, at + 8)] }
+
+#[inline(always)]
+pub fn read_f32x3(data: &[u8], at: uint) -> [f32, ..3]
+{ [read_f32(data, at), read_f32(data, at + 4), read_f32(data, at + 8)] }
+
+pub fn read_name(data: &[u8], at: uint) -> [i8, ..64]
+{
+  let mut name = [0i8, ..64];
+  for uint::range(0, 64) |i|
+  { name[i] = data[at + i] as i8; }
+  name
+}
+
+/* The header is the only thing without a lump; expects at least header_size(count) bytes. */
+pub fn decode_header(data: &[u8], count: uint) -> Header
+{
+  let mut header = Header::new();
+  for uint::range(0, 4) |i|
+  { header.magic[i] = data[i] as i8; }
+  header.version = read_i32(data, 4);
+  header.num_lumps = count;
+  for uint::range(0, count) |i|
+  {
+    header.lumps[i].offset = read_i32(data, 8 + (i * 8));
+    header.lumps[i].length = read_i32(data, 12 + (i * 8));
+  }
+  header
+}
+
+pub fn decode_texture(data: &[u8]) -> Texture
+{
+  Texture
+  {
+    name: read_name(data, 0),
+    surface_flags: read_i32(data, 64),
+    content_flags: read_i32(data, 68)
+  }
+}
+
+pub fn decode_plane(data: &[u8]) -> Plane
+{ Plane { normal: read_vec3f(data, 0), distance: read_f32(data, 12) } }
+
+pub fn decode_node(data: &[u8]) -> Node
+{
+  Node
+  {
+    plane: read_i32(data, 0),
+    children: [read_i32(data, 4), read_i32(data, 8)],
+    mins: read_i32x3(data, 12),
+    maxs: read_i32x3(data, 24)
+  }
+}
+
+pub fn decode_leaf(data: &[u8]) -> Leaf
+{
+  Leaf
+  {
+    cluster: read_i32(data, 0),
+    area: read_i32(data, 4),
+    mins: read_i32x3(data, 8),
+    maxs: read_i32x3(data, 20),
+    face: read_i32(data, 32),
+    num_faces: read_i32(data, 36),
+    brush: read_i32(data, 40),
+    num_brushes: read_i32(data, 44)
+  }
+}
+
+pub fn decode_leaf_face(data: &[u8]) -> Leaf_Face
+{ Leaf_Face { face: read_i32(data, 0) } }
+
+pub fn decode_leaf_brush(data: &[u8]) -> Leaf_Brush
+{ Leaf_Brush { brush: read_i32(data, 0) } }
+
+pub fn decode_model(data: &[u8]) -> Model
+{
+  Model
+  {
+    mins: read_f32x3(data, 0),
+    maxs: read_f32x3(data, 12),
+    face: read_i32(data, 24),
+    num_faces: read_i32(data, 28),
+    brush: read_i32(data, 32),
+    num_brushes: read_i32(data, 36)
+  }
+}
+
+pub fn decode_brush(data: &[u8]) -> Brush
+{ Brush { side: read_i32(data, 0), num_sides: read_i32(data, 4), texture: read_i32(data, 8) } }
+
+pub fn decode_brush_side(data: &[u8]) -> Brush_Side
+{ Brush_Side { plane: read_i32(data, 0), texture: read_i32(data, 4) } }
+
+pub fn decode_vertex(data: &[u8]) -> Vertex
+{
+  Vertex
+  {
+    position: read_vec3f(data, 0),
+    tex_coords: [read_vec2f(data, 12), read_vec2f(data, 20)],
+    normal: read_vec3f(data, 28),
+    color: Vec4u8::new(data[40], data[41], data[42], data[43])
+  }
+}
+
+pub fn decode_mesh_vert(data: &[u8]) -> Mesh_Vert
+{ Mesh_Vert { offset: read_i32(data, 0) } }
+
+pub fn decode_effect(data: &[u8]) -> Effect
+{ Effect { name: read_name(data, 0), brush: read_i32(data, 64), unknown: read_i32(data, 68) } }
+
+pub fn decode_face(data: &[u8]) -> Face
+{
+  Face
+  {
+    texture: read_i32(data, 0),
+    effect: read_i32(data, 4),
+    kind: read_i32(data, 8),
+    start_vertex: read_i32(data, 12),
+    num_vertices: read_i32(data, 16),
+    start_mesh_vertex: read_i32(data, 20),
+    num_mesh_vertices: read_i32(data, 24),
+    lightmap: read_i32(data, 28),
+    lightmap_corner: read_vec2i(data, 32),
+    lightmap_size: read_vec2i(data, 40),
+    lightmap_origin: read_vec3f(data, 48),
+    lightmap_vecs: [read_vec3f(data, 60), read_vec3f(data, 72)],
+    normal: read_vec3f(data, 84),
+    patch_size: read_vec2i(data, 96),
+  }
+}
+
+pub fn decode_light_vol(data: &[u8]) -> Light_Vol
+{
+  Light_Vol
+  {
+    ambient: [data[0], data[1], data[2]],
+    directional: [data[3], data[4], data[5]],
+    direction: [data[6], data[7]]
+  }
+}
+
+/***** RBSP Decoding *****/
+
+pub fn decode_raven_brush_side(data: &[u8]) -> Brush_Side
+{ Brush_Side { plane: read_i32(data, 0), texture: read_i32(data, 4) } }
+
+pub fn decode_raven_vertex(data: &[u8]) -> (Vertex, Raven_Vertex)
+{
+  let mut ext = Raven_Vertex
+  {
+    lightmap_coords: [Vec2f::zero(), ..4],
+    colors: [Vec4u8::new(0, 0, 0, 0), ..4]
+  };
+  for uint::range(0, Max_Light_Styles) |i|
+  {
+    ext.lightmap_coords[i] = read_vec2f(data, 20 + (i * 8));
+    ext.colors[i] = Vec4u8::new(data[64 + (i * 4)], data[65 + (i * 4)],
+                                data[66 + (i * 4)], data[67 + (i * 4)]);
+  }
+
+  let vert = Vertex
+  {
+    position: read_vec3f(data, 0),
+    tex_coords: [read_vec2f(data, 12), ext.lightmap_coords[0]],
+    normal: read_vec3f(data, 52),
+    color: ext.colors[0]
+  };
+  (vert, ext)
+}
+
+pub fn decode_raven_face(data: &[u8]) -> (Face, Raven_Face)
+{
+  let mut ext = Raven_Face
+  {
+    lightmap_styles: [data[28], data[29], data[30], data[31]],
+    vertex_styles: [data[32], data[33], data[34], data[35]],
+    lightmaps: [0, ..4],
+    lightmap_corners: [Vec2i::zero(), ..4]
+  };
+  for uint::range(0, Max_Light_Styles) |i|
+  {
+    ext.lightmaps[i] = read_i32(data, 36 + (i * 4));
+    ext.lightmap_corners[i] = Vec2i::new(read_i32(data, 52 + (i * 4)), read_i32(data, 68 + (i * 4)));
+  }
+
+  let face = Face
+  {
+    texture: read_i32(data, 0),
+    effect: read_i32(data, 4),
+    kind: read_i32(data, 8),
+    start_vertex: read_i32(data, 12),
+    num_vertices: read_i32(data, 16),
+    start_mesh_vertex: read_i32(data, 20),
+    num_mesh_vertices: read_i32(data, 24),
+    lightmap: ext.lightmaps[0],
+    lightmap_corner: ext.lightmap_corners[0],
+    lightmap_size: read_vec2i(data, 84),
+    lightmap_origin: read_vec3f(data, 92),
+    lightmap_vecs: [read_vec3f(data, 104), read_vec3f(data, 116)],
+    normal: read_vec3f(data, 128),
+    patch_size: read_vec2i(data, 140),
+  };
+  (face, ext)
+}
+
+/* Only the first light style is kept; the grid is shared through the light array. */
+pub fn decode_raven_light_vol(data: &[u8]) -> Light_Vol
+{
+  Light_Vol
+  {
+    ambient: [data[0], data[1], data[2]],
+    directional: [data[12], data[13], data[14]],
+    direction: [data[28], data[29]]
+  }
+}
+
+pub fn decode_light_array(data: &[u8]) -> u16
+{ read_u16(data, 0) }
 
@@ -9,103 +9,275 @@
       Loader and handler of BSP maps.
 */
 
-use std::{ i32, path, io, sys, cast };
+use std::{ i32, f32, uint, vec };
+use std::iterator::IteratorUtil;
+use std::hashmap::HashMap;
+use extra::sort;
 use math::{ Vec3f, Vec4u8, BB3 };
 use primitive::{ Triangle, Vertex_PC };
+use vfs::Vfs;
+use export::Mesh;
+use obj::traits::Movable;
+use super::{ Entity, Billboard, Trace, Material_Table, Model };
+use super::model;
+use super::material;
+use super::error;
+use super::error::Load_Error;
+
+/* Returns early with the error, if there is one. */
+macro_rules! try_load(
+  ($e:expr) =>
+  (
+    match $e
+    {
+      Ok(x) => x,
+      Err(e) => { return Err(e); }
+    }
+  )
+)
 
 #[path = "lump.rs"]
 mod lump;
-#[path = "../../gl/mod.rs"]
-mod gl;
+#[path = "patch.rs"]
+mod patch;
+#[path = "tree.rs"]
+mod tree;
+#[path = "trace.rs"]
+pub mod trace;
+#[path = "light_grid.rs"]
+mod light_grid;
+#[path = "fog.rs"]
+mod fog;
+#[path = "validate.rs"]
+mod validate;
+/* Everything but the renderer builds without GL, for the inspector. */
+#[cfg(not(headless))]
+#[path = "renderer.rs"]
+pub mod renderer;
 
-#[path = "../../gl/check.rs"]
-mod check;
+pub static Default_Patch_Level: i32 = 5;
+/* Left shift applied to the baked lighting (lightmaps and vertex colors). */
+pub static Overbright_Bits: uint = 1;
+/* Extensions tried when resolving texture names to images. */
+pub static Texture_Extensions: [&'static str, ..2] = [".tga", ".jpg"];
+
+/* A run of render verts sharing a texture, lightmap, and fog. */
+pub struct Batch
+{
+  start: uint,
+  count: uint,
+  texture: i32,
+  lightmap: i32,
+  /* Index into the fogs; negative when not fogged. */
+  fog: i32,
+}
 
 pub struct Map
 {
   header: lump::Header,
+  format: lump::Format,
   entity: lump::Entity,
+  entities: ~[Entity],
   tris: ~[Triangle],
+  /* Times each Bezier patch is split per side. */
+  patch_level: i32,
   verts: ~[lump::Vertex],
   faces: ~[lump::Face],
   mesh_verts: ~[lump::Mesh_Vert], 
-  vao: gl::GLuint,
-  vbo: ~[gl::GLuint], /* TODO: No need for the array */
+  /* Extra light styles, parallel to verts and faces; only for RBSP. */
+  raven_verts: ~[lump::Raven_Vertex],
+  raven_faces: ~[lump::Raven_Face],
+  tree: tree::Tree,
+  vis: lump::Vis_Data,
+  textures: ~[lump::Texture],
+  brushes: ~[lump::Brush],
+  brush_sides: ~[lump::Brush_Side],
+  /* The first is the world; the rest are brush entities. */
+  models: ~[lump::Model],
+  /* Drawables for each of the models past the world. */
+  inline_models: ~[Model],
+  light_grid: light_grid::Light_Grid,
+  effects: ~[lump::Effect],
+  /* Parallel to the effects; None for those that aren't fog. */
+  fogs: ~[Option<fog::Fog>],
+  /* Already overbright shifted RGB texels of every lightmap. */
+  lightmap_data: ~[u8],
+  /* Unique surface image files; the first is empty, for missing ones. */
+  surface_images: ~[~str],
+  /* Index into surface_images for each texture in the lump. */
+  materials: ~[uint],
+  /* Every material from the shader scripts. */
+  material_table: Material_Table,
+  /* Faces sorted by texture and lightmap; the order of the render verts. */
+  draw_order: ~[uint],
+  /* (first vert, vert count) of each face in the render verts. */
+  face_ranges: ~[(uint, uint)],
+  /* Batches that passed the last cull. */
+  visible_ranges: ~[Batch],
+  drawn_tris: uint,
+  total_tris: uint,
+  billboards: ~[Billboard],
+
   position: Vec3f, /* TODO: Trait for positional objects. */
   bb: BB3
 }
 
 impl Map
 {
-  pub fn new(file: &str) -> Map
+  /* Parses and triangulates the map without touching GL; a Renderer draws it. */
+  #[inline(always)]
+  pub fn load(file: &str) -> Result<Map, Load_Error>
+  { Map::load_with_patch_level(file, Default_Patch_Level) }
+
+  pub fn load_with_patch_level(file: &str, level: i32) -> Result<Map, Load_Error>
+  {
+    let data = match Vfs::get().read(file)
+    {
+      Some(data) => { data }
+      None => { return Err(error::Io_Error(fmt!("%s not found", file))); }
+    };
+    Map::load_from_bytes(data, level)
+  }
+
+  /* The whole .bsp file, already read. */
+  pub fn load_from_bytes(data: &[u8], level: i32) -> Result<Map, Load_Error>
   {
     let mut map = Map
     {
       header: lump::Header::new(),
+      format: lump::Quake3_Format,
       entity: lump::Entity::new(),
+      entities: ~[],
       tris: ~[],
+      patch_level: level,
       verts: ~[],
       faces: ~[],
       mesh_verts: ~[],
-      vao: 0,
-      vbo: ~[],
+      raven_verts: ~[],
+      raven_faces: ~[],
+      tree: tree::Tree::new(),
+      vis: lump::Vis_Data::new(),
+      textures: ~[],
+      brushes: ~[],
+      brush_sides: ~[],
+      models: ~[],
+      inline_models: ~[],
+      light_grid: light_grid::Light_Grid::new(),
+      effects: ~[],
+      fogs: ~[],
+      lightmap_data: ~[],
+      surface_images: ~[],
+      materials: ~[],
+      material_table: Material_Table::new(),
+      draw_order: ~[],
+      face_ranges: ~[],
+      visible_ranges: ~[],
+      drawn_tris: 0,
+      total_tris: 0,
+      billboards: ~[],
       position: Vec3f::zero(),
       bb: BB3::zero(),
     };
 
-    let fio = io::file_reader(@path::PosixPath(file)).unwrap();
-    unsafe {  fio.read( cast::transmute((&map.header, sys::size_of::<lump::Header>())),
-                        sys::size_of::<lump::Header>()); }
-
-    assert!( map.header.magic[0] == 'I' as i8 &&
-             map.header.magic[1] == 'B' as i8 &&
-             map.header.magic[2] == 'S' as i8 &&
-             map.header.magic[3] == 'P' as i8);
+    try_load!(map.read_header(data));
+    try_load!(map.read_entities(data));
+    try_load!(map.read_verts(data));
+    try_load!(map.read_faces(data));
+    try_load!(map.read_mesh_verts(data));
+    try_load!(map.read_tree(data));
+    try_load!(map.read_vis_data(data));
+    try_load!(map.read_brushes(data));
+    try_load!(map.read_lightmaps(data));
+    try_load!(map.read_light_grid(data));
+    try_load!(map.read_effects(data));
 
-    map.read_verts(fio);
-    map.read_faces(fio);
-    map.read_mesh_verts(fio);
+    /* Nothing past here needs to worry about bad indices. */
+    let num_lightmaps = map.lightmap_data.len() / lump::Light_Map_Bytes;
+    try_load!(validate::validate(&map, num_lightmaps));
 
+    map.material_table = Material_Table::load();
+    map.load_fogs();
+    map.resolve_images();
     map.triangulate();
-    map.upload();
-    
-    map
-  }
 
+    Ok(map)
+  }
 
-  /* TODO: Make this generic. */
-  priv fn read_verts(&mut self, fio: @io::Reader)
+  priv fn read_header(&mut self, data: &[u8]) -> Result<(), Load_Error>
   {
-    fio.seek(self.header.lumps[lump::Vertex_Type as int].offset as int, io::SeekSet);
-    let num_verts = (self.header.lumps[lump::Vertex_Type as int].length) /
-                    (sys::size_of::<lump::Vertex>() as i32);
-    assert!(num_verts > 0);
+    if data.len() < 8
+    { return Err(error::Truncated_Header(data.len(), 8)); }
 
-    let mut vert = lump::Vertex::new();
-    for i32::range(0, num_verts) |i|
+    /* The magic and version determine the layout of everything else. */
+    let version = lump::read_i32(data, 4);
+    let is_ibsp = data[0] == 'I' as u8 && data[1] == 'B' as u8 && data[2] == 'S' as u8 && data[3] == 'P' as u8;
+    let is_rbsp = data[0] == 'R' as u8 && data[1] == 'B' as u8 && data[2] == 'S' as u8 && data[3] == 'P' as u8;
+    if !is_ibsp && !is_rbsp
     {
-      unsafe { fio.read( cast::transmute((&vert, sys::size_of::<lump::Vertex>())),
-                sys::size_of::<lump::Vertex>()); }
-      
-      /* BSP likes Z to be up; we like Y to be up. */
-      let temp = vert.position.y;
-      vert.position.y = vert.position.z;
-      vert.position.z = -temp;
+      let mut magic = ~"";
+      for uint::range(0, 4) |i|
+      { magic.push_char(data[i] as char); }
+      return Err(error::Bad_Magic(magic));
+    }
+
+    self.format = if is_ibsp && version == lump::Quake3_Version { lump::Quake3_Format }
+                  else if is_ibsp && version == lump::Quake_Live_Version { lump::Quake_Live_Format }
+                  else if is_rbsp && version == lump::Raven_Version { lump::Raven_Format }
+                  else { return Err(error::Unsupported_Version(version)); };
+
+    let count = lump::num_lumps(self.format);
+    if data.len() < lump::header_size(count)
+    { return Err(error::Truncated_Header(data.len(), lump::header_size(count))); }
+    self.header = lump::decode_header(data, count);
+
+    /* Every lump has to lie within the file. */
+    for uint::range(0, self.header.num_lumps) |i|
+    {
+      let lump = self.header.lumps[i];
+      if lump.offset < 0 || lump.length < 0 ||
+         (lump.offset as uint) + (lump.length as uint) > data.len()
+      { return Err(error::Lump_Out_Of_Range(lump::Lump_Names[i].to_owned(), lump.offset, lump.length)); }
+    }
 
-      /* TODO: Color hack. */
-      if vert.color.x == 0 { vert.color.x += 100; }
-      if vert.color.y == 0 { vert.color.y += 100; }
-      if vert.color.z == 0 { vert.color.z += 100; }
+    debug!("BSP: Format is %?.", self.format);
+    Ok(())
+  }
+
+  priv fn read_entities(&mut self, data: &[u8]) -> Result<(), Load_Error>
+  {
+    let bytes = self.lump_data(data, lump::Entity_Type);
+    self.entity.size = bytes.len() as i32;
+    self.entity.buffer = bytes.to_owned();
+    self.entities = Entity::parse(lump::from_latin1(bytes));
+    debug!("BSP: Parsed %? entities.", self.entities.len());
+    Ok(())
+  }
+
+  priv fn read_verts(&mut self, data: &[u8]) -> Result<(), Load_Error>
+  {
+    let verts = match self.format
+    {
+      lump::Raven_Format =>
+      {
+        let pairs = try_load!(self.decode_lump(data, lump::Vertex_Type, lump::Raven_Vertex_Size,
+                                               lump::decode_raven_vertex));
+        self.raven_verts = pairs.map(|&(_, ext)| ext);
+        pairs.map(|&(vert, _)| vert)
+      }
+      _ => { try_load!(self.decode_lump(data, lump::Vertex_Type, lump::Vertex_Size, lump::decode_vertex)) }
+    };
+    if verts.len() == 0
+    { return Err(error::Empty_Lump(lump::lump_name(lump::Vertex_Type))); }
 
-      if vert.color.x == 255 { vert.color.x -= 100; }
-      if vert.color.y == 255 { vert.color.y -= 100; }
-      if vert.color.z == 255 { vert.color.z -= 100; }
-      vert.color.w = 1;
+    for uint::range(0, verts.len()) |i|
+    {
+      let mut vert = verts[i];
+      vert.position = lump::convert_position(vert.position);
+      vert.normal = lump::convert_direction(vert.normal);
 
-      /* Global size scale. */
-      vert.position.x /= 32.0;
-      vert.position.y /= 32.0;
-      vert.position.z /= 32.0;
+      let mut color = [vert.color.x, vert.color.y, vert.color.z];
+      lump::shift_color(&mut color, Overbright_Bits);
+      vert.color = Vec4u8::new(color[0], color[1], color[2], vert.color.w);
 
       /* Create bounding box based on first vert; this'll update as more come. */
       match i
@@ -134,115 +306,660 @@ impl Map
 
       self.verts.push(vert);
     }
+    Ok(())
   }
 
-  priv fn read_faces(&mut self, fio: @io::Reader)
+  priv fn read_faces(&mut self, data: &[u8]) -> Result<(), Load_Error>
   {
-    fio.seek(self.header.lumps[lump::Face_Type as int].offset as int, io::SeekSet);
-    let num_faces = (self.header.lumps[lump::Face_Type as int].length) /
-                    (sys::size_of::<lump::Face>() as i32);
-    assert!(num_faces > 0);
+    self.faces = match self.format
+    {
+      lump::Raven_Format =>
+      {
+        let pairs = try_load!(self.decode_lump(data, lump::Face_Type, lump::Raven_Face_Size,
+                                               lump::decode_raven_face));
+        self.raven_faces = pairs.map(|&(_, ext)| ext);
+        pairs.map(|&(face, _)| face)
+      }
+      _ => { try_load!(self.decode_lump(data, lump::Face_Type, lump::Face_Size, lump::decode_face)) }
+    };
+    if self.faces.len() == 0
+    { return Err(error::Empty_Lump(lump::lump_name(lump::Face_Type))); }
+    Ok(())
+  }
+
+  priv fn read_mesh_verts(&mut self, data: &[u8]) -> Result<(), Load_Error>
+  {
+    self.mesh_verts = try_load!(self.decode_lump(data, lump::Mesh_Vert_Type, lump::Mesh_Vert_Size,
+                                                 lump::decode_mesh_vert));
+    Ok(())
+  }
+
+  priv fn read_tree(&mut self, data: &[u8]) -> Result<(), Load_Error>
+  {
+    let planes = try_load!(self.decode_lump(data, lump::Plane_Type, lump::Plane_Size, lump::decode_plane));
+    self.tree.planes = planes.map(|p| lump::convert_plane(*p));
+    self.tree.nodes = try_load!(self.decode_lump(data, lump::Node_Type, lump::Node_Size, lump::decode_node));
+    self.tree.leaves = try_load!(self.decode_lump(data, lump::Leaf_Type, lump::Leaf_Size, lump::decode_leaf));
+    self.tree.leaf_faces = try_load!(self.decode_lump(data, lump::Leaf_Face_Type, lump::Leaf_Face_Size,
+                                                      lump::decode_leaf_face));
+    self.tree.leaf_brushes = try_load!(self.decode_lump(data, lump::Leaf_Brush_Type, lump::Leaf_Brush_Size,
+                                                        lump::decode_leaf_brush));
+
+    debug!("BSP: Tree has %? nodes and %? leaves.", self.tree.nodes.len(), self.tree.leaves.len());
+    Ok(())
+  }
 
-    let face = lump::Face::new();
-    for i32::range(0, num_faces) |_|
+  priv fn read_vis_data(&mut self, data: &[u8]) -> Result<(), Load_Error>
+  {
+    let bytes = self.lump_data(data, lump::Vis_Data_Type);
+
+    /* Maps compiled without vis have an empty lump. */
+    if bytes.len() < 8
+    { warn!("BSP: No vis data; everything will be drawn"); return Ok(()); }
+
+    let num_clusters = lump::read_i32(bytes, 0);
+    let bytes_per_cluster = lump::read_i32(bytes, 4);
+    let name = lump::lump_name(lump::Vis_Data_Type);
+    if num_clusters < 0 || bytes_per_cluster < 0 || (bytes_per_cluster as uint) * 8 < (num_clusters as uint)
+    { return Err(error::Truncated_Lump(name, (num_clusters as uint) / 8, bytes_per_cluster as uint)); }
+
+    let needed = (num_clusters as uint) * (bytes_per_cluster as uint);
+    if needed > bytes.len() - 8
+    { return Err(error::Truncated_Lump(name, needed + 8, bytes.len())); }
+
+    self.vis.num_clusters = num_clusters;
+    self.vis.bytes_per_cluster = bytes_per_cluster;
+    self.vis.buffer = bytes.slice(8, 8 + needed).to_owned();
+
+    debug!("BSP: Vis data has %? clusters.", self.vis.num_clusters);
+    Ok(())
+  }
+
+  priv fn read_brushes(&mut self, data: &[u8]) -> Result<(), Load_Error>
+  {
+    self.textures = try_load!(self.decode_lump(data, lump::Texture_Type, lump::Texture_Size,
+                                               lump::decode_texture));
+    self.brushes = try_load!(self.decode_lump(data, lump::Brush_Type, lump::Brush_Size, lump::decode_brush));
+    self.brush_sides = match self.format
     {
-      unsafe { fio.read( cast::transmute((&face, sys::size_of::<lump::Face>())),
-                sys::size_of::<lump::Face>()); }
-      self.faces.push(face);
+      lump::Raven_Format =>
+      {
+        try_load!(self.decode_lump(data, lump::Brush_Side_Type, lump::Raven_Brush_Side_Size,
+                                   lump::decode_raven_brush_side))
+      }
+      _ =>
+      {
+        try_load!(self.decode_lump(data, lump::Brush_Side_Type, lump::Brush_Side_Size,
+                                   lump::decode_brush_side))
+      }
+    };
+
+    debug!("BSP: Read %? brushes with %? sides.", self.brushes.len(), self.brush_sides.len());
+    Ok(())
+  }
+
+  priv fn read_lightmaps(&mut self, data: &[u8]) -> Result<(), Load_Error>
+  {
+    let bytes = self.lump_data(data, lump::Light_Map_Type);
+    if bytes.len() % lump::Light_Map_Bytes != 0
+    {
+      return Err(error::Misaligned_Lump(lump::lump_name(lump::Light_Map_Type),
+                                        bytes.len(), lump::Light_Map_Bytes));
     }
+
+    self.lightmap_data = bytes.to_owned();
+    for uint::range(0, self.lightmap_data.len() / 3) |i|
+    { lump::shift_color(self.lightmap_data.mut_slice(i * 3, (i * 3) + 3), Overbright_Bits); }
+
+    debug!("BSP: Read %? lightmaps.", self.lightmap_data.len() / lump::Light_Map_Bytes);
+    Ok(())
   }
 
-  priv fn read_mesh_verts(&mut self, fio: @io::Reader)
+  priv fn read_light_grid(&mut self, data: &[u8]) -> Result<(), Load_Error>
   {
-    fio.seek(self.header.lumps[lump::Mesh_Vert_Type as int].offset as int, io::SeekSet);
-    let num_obj = (self.header.lumps[lump::Mesh_Vert_Type as int].length) /
-                    (sys::size_of::<lump::Mesh_Vert>() as i32);
-    assert!(num_obj > 0);
+    self.models = try_load!(self.decode_lump(data, lump::Model_Type, lump::Model_Size, lump::decode_model));
+    /* The first model is the world; without it, nothing can tell world faces from brush entities. */
+    if self.models.len() == 0
+    { return Err(error::Empty_Lump(lump::lump_name(lump::Model_Type))); }
 
-    let obj = lump::Mesh_Vert::new();
-    for i32::range(0, num_obj) |_|
+    /* Worldspawn may override the grid spacing. */
+    let mut size = light_grid::Default_Grid_Size;
+    match self.find_entity("worldspawn")
     {
-      unsafe { fio.read( cast::transmute((&obj, sys::size_of::<lump::Mesh_Vert>())),
-                sys::size_of::<lump::Mesh_Vert>()); }
-      self.mesh_verts.push(obj);
+      Some(world) =>
+      {
+        match world.get("gridsize")
+        {
+          Some(s) =>
+          {
+            let comps: ~[f32] = s.split_iter(' ').filter_map(|x| f32::from_str(x)).collect();
+            if comps.len() == 3 && comps.iter().all(|x| *x > 0.0)
+            { size = [comps[0], comps[1], comps[2]]; }
+            else
+            { warn!("BSP: Invalid gridsize '%s'", *s); }
+          }
+          None => { }
+        }
+      }
+      None => { }
     }
+
+    let samples = match self.format
+    {
+      /* RBSP stores unique samples once; the light array indexes them per grid cell. */
+      lump::Raven_Format =>
+      {
+        let points = try_load!(self.decode_lump(data, lump::Light_Vol_Type, lump::Raven_Light_Vol_Size,
+                                                lump::decode_raven_light_vol));
+        let array = try_load!(self.decode_lump(data, lump::Extra_Type, lump::Light_Array_Size,
+                                               lump::decode_light_array));
+        let mut samples = vec::with_capacity(array.len());
+        for uint::range(0, array.len()) |i|
+        {
+          if (array[i] as uint) >= points.len()
+          {
+            return Err(error::Bad_Index(lump::lump_name(lump::Extra_Type), i,
+                                        ~"light volume", array[i] as i32));
+          }
+          samples.push(points[array[i]]);
+        }
+        samples
+      }
+      _ =>
+      {
+        try_load!(self.decode_lump(data, lump::Light_Vol_Type, lump::Light_Vol_Size,
+                                   lump::decode_light_vol))
+      }
+    };
+    let world = self.models[0];
+    self.light_grid.load(&world, size, samples, Overbright_Bits);
+    Ok(())
+  }
+
+  priv fn read_effects(&mut self, data: &[u8]) -> Result<(), Load_Error>
+  {
+    self.effects = try_load!(self.decode_lump(data, lump::Effect_Type, lump::Effect_Size, lump::decode_effect));
+    Ok(())
+  }
+
+  /* Turns each fog effect's brush into a volume, colored by its shader's fogparms. */
+  priv fn load_fogs(&mut self)
+  {
+    let mut fogs = ~[];
+    for self.effects.iter().advance |effect|
+    {
+      let name = effect.get_name();
+      let parms = match self.material_table.find(name)
+      {
+        Some(material) => { material.fog_parms }
+        None => { None }
+      };
+
+      match parms
+      {
+        Some((color, distance)) =>
+        {
+          let mut planes = ~[];
+          if effect.brush >= 0
+          {
+            let brush = &self.brushes[effect.brush];
+            for i32::range(brush.side, brush.side + brush.num_sides) |side|
+            { planes.push(self.tree.planes[self.brush_sides[side].plane]); }
+          }
+          fogs.push(Some(fog::Fog::new(name, planes, color, distance)));
+        }
+        None => { fogs.push(None); }
+      }
+    }
+    self.fogs = fogs;
+    debug!("BSP: Found %? fog volumes.", self.fogs.iter().count(|f| f.is_some()));
+  }
+
+  /* The fog a face is in; negative for none. */
+  #[inline(always)]
+  priv fn face_fog(&self, face: uint) -> i32
+  {
+    let effect = self.faces[face].effect;
+    if effect >= 0 && self.fogs[effect].is_some()
+    { effect }
+    else
+    { -1 }
+  }
+
+  /* The fog volume containing the point; negative for none. */
+  pub fn find_fog(&self, point: Vec3f) -> i32
+  {
+    for uint::range(0, self.fogs.len()) |i|
+    {
+      match self.fogs[i]
+      {
+        Some(ref fog) if fog.contains(point) => { return i as i32; }
+        _ => { }
+      }
+    }
+    -1
+  }
+
+  /* The bytes of a lump; the header has already been checked against the file. */
+  #[inline(always)]
+  priv fn lump_data<'a>(&self, data: &'a [u8], kind: lump::Lump_Type) -> &'a [u8]
+  {
+    let lump = self.header.lumps[kind as int];
+    data.slice(lump.offset as uint, (lump.offset + lump.length) as uint)
+  }
+
+  /* Decodes every element of a lump of fixed size elements. */
+  priv fn decode_lump<T>(&self, data: &[u8], kind: lump::Lump_Type, size: uint,
+                         decode: &fn(&[u8]) -> T) -> Result<~[T], Load_Error>
+  {
+    let bytes = self.lump_data(data, kind);
+    if bytes.len() % size != 0
+    { return Err(error::Misaligned_Lump(lump::lump_name(kind), bytes.len(), size)); }
+
+    let count = bytes.len() / size;
+    let mut out = vec::with_capacity(count);
+    for uint::range(0, count) |i|
+    { out.push(decode(bytes.slice(i * size, (i + 1) * size))); }
+    Ok(out)
   }
 
   priv fn triangulate(&mut self)
   {
+    /* Faces sharing a texture, lightmap, and effect end up next to each other. */
+    let mut order = vec::from_fn(self.faces.len(), |i| i);
+    {
+      let faces = &self.faces;
+      sort::quick_sort(order, |a, b|
+      {
+        let (fa, fb) = (&faces[*a], &faces[*b]);
+        if fa.texture != fb.texture
+        { fa.texture < fb.texture }
+        else if fa.lightmap != fb.lightmap
+        { fa.lightmap < fb.lightmap }
+        else if fa.effect != fb.effect
+        { fa.effect < fb.effect }
+        else
+        { *a <= *b }
+      });
+    }
+
     let mut verts: ~[lump::Vertex] = ~[];
-    for self.faces.each() |face|
+    let mut billboards = ~[];
+    let mut ranges = vec::from_elem(self.faces.len(), (0u, 0u));
+    for order.iter().advance |&index|
     {
-      if face.kind != 1 { loop; }
+      let face = &self.faces[index];
+      let start = verts.len();
+      if self.is_invisible(face.texture)
+      { loop; }
 
-      match face.num_vertices
+      match face.kind
       {
-        n if n >= 3 =>
+        /* Polygon and mesh; both are already triangulated through the mesh verts. */
+        1 | 3 =>
         {
-          for i32::range(0, n - 2) |i|
+          if face.num_mesh_vertices % 3 != 0
+          { warn!(fmt!("BSP: Invalid mesh vert count: %?", face.num_mesh_vertices)); }
+
+          for i32::range(0, face.num_mesh_vertices / 3) |i|
           {
-            verts.push(self.verts[face.start_vertex]);
-            verts.push(self.verts[face.start_vertex + i + 2]);
-            verts.push(self.verts[face.start_vertex + i + 1]);
-
-            self.tris.push(Triangle::new( 
-                        Vertex_PC::new(
-                            self.verts[face.start_vertex].position,
-                            Vec3f::new( self.verts[face.start_vertex].color.x as f32,
-                                        self.verts[face.start_vertex].color.y as f32,
-                                        self.verts[face.start_vertex].color.z as f32)),
-                        Vertex_PC::new(
-                            self.verts[face.start_vertex + i + 2].position,
-                            Vec3f::new( self.verts[face.start_vertex + i + 2].color.x as f32,
-                                        self.verts[face.start_vertex + i + 2].color.y as f32,
-                                        self.verts[face.start_vertex + i + 2].color.z as f32)),
-                        Vertex_PC::new(
-                            self.verts[face.start_vertex + i + 1].position,
-                            Vec3f::new( self.verts[face.start_vertex + i + 1].color.x as f32,
-                                        self.verts[face.start_vertex + i + 1].color.y as f32,
-                                        self.verts[face.start_vertex + i + 1].color.z as f32))));
+            let first = face.start_mesh_vertex + (i * 3);
+
+            /* Mesh verts are relative to the face's first vertex. */
+            verts.push(self.verts[face.start_vertex + self.mesh_verts[first].offset]);
+            verts.push(self.verts[face.start_vertex + self.mesh_verts[first + 2].offset]);
+            verts.push(self.verts[face.start_vertex + self.mesh_verts[first + 1].offset]);
           }
         }
-        /* Something else. */
-        n => { warn!(fmt!("BSP: Invalid face: %?", n)); }
+        /* Patch. */
+        2 =>
+        {
+          let ctrl = self.verts.slice(face.start_vertex as uint,
+                                      (face.start_vertex + face.num_vertices) as uint);
+          verts.push_all(patch::tessellate(ctrl, face.patch_size, self.patch_level));
+        }
+        /* Billboard; the position and color are stashed in the lightmap fields. */
+        4 =>
+        {
+          billboards.push(Billboard::new( lump::convert_position(face.lightmap_origin),
+                                          face.lightmap_vecs[0],
+                                          lump::convert_direction(face.normal)));
+        }
+        _ => { }
       }
+      ranges[index] = (start, verts.len() - start);
     };
+    self.billboards = billboards;
+    self.face_ranges = ranges;
+    self.draw_order = order;
+    debug!("BSP: Found %? billboards.", self.billboards.len());
+
+    /* Every three render verts of the world make up a triangle for the voxelizer;
+       inline models move, so they're left out. */
+    for self.draw_order.iter().advance |&index|
+    {
+      if !self.in_world(index)
+      { loop; }
+
+      let (start, count) = self.face_ranges[index];
+      for uint::range(0, count / 3) |i|
+      {
+        let first = start + (i * 3);
+        self.tris.push(Triangle::new( to_vertex_pc(&verts[first]),
+                                      to_vertex_pc(&verts[first + 1]),
+                                      to_vertex_pc(&verts[first + 2])));
+      }
+    }
 
     self.verts = verts;
-    debug!("BSP: Trianglulated to %? faces.", self.verts.len());
+    self.build_inline_models();
+    self.total_tris = self.tris.len();
+    debug!("BSP: Trianglulated to %? faces.", self.tris.len());
+  }
+
+  /* Batches up the faces of each model past the world. */
+  priv fn build_inline_models(&mut self)
+  {
+    let mut inline_models = ~[];
+    for uint::range(1, self.models.len()) |index|
+    {
+      let lump_model = self.models[index];
+      let first = lump_model.face as uint;
+      let last = first + lump_model.num_faces as uint;
+
+      let mut batches = ~[];
+      for self.draw_order.iter().advance |&i|
+      {
+        let (start, count) = self.face_ranges[i];
+        if i >= first && i < last && count > 0
+        { push_batch(&mut batches, start, count, self.faces[i].texture, self.faces[i].lightmap, self.face_fog(i)); }
+      }
+
+      let mins = Vec3f::new(lump_model.mins[0], lump_model.mins[1], lump_model.mins[2]);
+      let maxs = Vec3f::new(lump_model.maxs[0], lump_model.maxs[1], lump_model.maxs[2]);
+      let bb = BB3::new(lump::convert_position(mins), lump::convert_position(maxs));
+      inline_models.push(Model::new(index, batches, bb));
+    }
+    self.inline_models = inline_models;
+    debug!("BSP: Found %? inline models.", self.inline_models.len());
+  }
+
+  /* Whether the face belongs to the world, rather than an inline model. */
+  #[inline(always)]
+  priv fn in_world(&self, face: uint) -> bool
+  {
+    let world = &self.models[0];
+    face >= world.face as uint && face < (world.face + world.num_faces) as uint
+  }
+
+  /* Clip brushes, caulk, hints, and the like; neither drawn nor voxelized. */
+  priv fn is_invisible(&self, texture: i32) -> bool
+  {
+    let tex = &self.textures[texture];
+    if (tex.surface_flags & (lump::Surf_No_Draw | lump::Surf_Hint | lump::Surf_Skip)) != 0
+    { return true; }
+
+    match self.material_table.find(tex.get_name())
+    {
+      Some(material) => { material.has_param(material::Param_Invisible) }
+      None => { false }
+    }
+  }
+
+  /* Resolves each texture name to an image file; missing ones get the first, empty, name. */
+  priv fn resolve_images(&mut self)
+  {
+    let vfs = Vfs::get();
+    self.surface_images = ~[~""];
+    self.materials = ~[];
+
+    let mut loaded = HashMap::new::<~str, uint>();
+    for self.textures.iter().advance |tex|
+    {
+      let name = strip_extension(tex.get_name());
+      match loaded.find(&name)
+      {
+        Some(&index) => { self.materials.push(index); loop; }
+        None => { }
+      }
+
+      /* Shaders can name an image other than their own. */
+      let mut candidates = ~[copy name];
+      match self.material_table.find(name)
+      {
+        Some(material) =>
+        {
+          match material.base_image()
+          {
+            Some(image) => { candidates.push(strip_extension(*image)); }
+            None => { }
+          }
+        }
+        None => { }
+      }
+
+      let mut index = 0;
+      for candidates.iter().advance |candidate|
+      {
+        if index != 0
+        { break; }
+
+        for Texture_Extensions.iter().advance |ext|
+        {
+          let file = *candidate + *ext;
+          if vfs.exists(file)
+          {
+            index = self.surface_images.len();
+            self.surface_images.push(file);
+            break;
+          }
+        }
+      }
+
+      if index == 0
+      { warn!("BSP: Missing texture '%s'", name); }
+      loaded.insert(name, index);
+      self.materials.push(index);
+    }
+
+    debug!("BSP: Found %? of %? textures.", self.surface_images.len() - 1, self.textures.len());
+  }
+
+  /* Determines which faces are potentially visible from the view point. */
+  pub fn cull(&mut self, view: Vec3f)
+  {
+    let cluster = self.find_cluster(view);
+    let mut visible = vec::from_elem(self.faces.len(), false);
+
+    if cluster < 0 || self.tree.leaves.len() == 0
+    {
+      /* Outside of the map; draw it all. */
+      visible = vec::from_elem(self.faces.len(), true);
+    }
+    else
+    {
+      for self.tree.leaves.iter().advance |leaf|
+      {
+        if leaf.cluster < 0 || !self.vis.is_visible(cluster, leaf.cluster)
+        { loop; }
+
+        for i32::range(leaf.face, leaf.face + leaf.num_faces) |i|
+        { visible[self.tree.leaf_faces[i].face] = true; }
+      }
+    }
+
+    /* Merge neighboring faces sharing a texture and lightmap into as few draw calls as we can. */
+    self.visible_ranges = ~[];
+    self.drawn_tris = 0;
+    for self.draw_order.iter().advance |&i|
+    {
+      let (start, count) = self.face_ranges[i];
+      if !visible[i] || count == 0 || !self.in_world(i)
+      { loop; }
+
+      self.drawn_tris += count / 3;
+      let fog = self.face_fog(i);
+      push_batch(&mut self.visible_ranges, start, count, self.faces[i].texture, self.faces[i].lightmap, fog);
+    }
   }
 
-  priv fn upload(&mut self)
+  #[inline(always)]
+  pub fn cluster_visible(&self, from: i32, to: i32) -> bool
+  { self.vis.is_visible(from, to) }
+
+  /* Index of the BSP leaf containing the point. */
+  #[inline(always)]
+  pub fn find_leaf(&self, point: Vec3f) -> i32
+  { self.tree.find_leaf(point) }
+
+  /* Visdata cluster of the point; negative when outside the map. */
+  #[inline(always)]
+  pub fn find_cluster(&self, point: Vec3f) -> i32
+  { self.tree.find_cluster(point) }
+
+  #[inline(always)]
+  pub fn find_area(&self, point: Vec3f) -> i32
+  { self.tree.find_area(point) }
+
+  #[inline(always)]
+  pub fn leaves_in_box(&self, bb: &BB3) -> ~[i32]
+  { self.tree.leaves_in_box(bb.min(), bb.max()) }
+
+  /* Leaf indices, nearest to farthest from the view point. */
+  #[inline(always)]
+  pub fn leaves_front_to_back(&self, view: Vec3f) -> ~[i32]
+  { self.tree.leaves_front_to_back(view) }
+
+  /* Sweeps a point from start to end against brushes matching the content mask. */
+  #[inline(always)]
+  pub fn trace_ray(&self, start: Vec3f, end: Vec3f, mask: i32) -> Trace
+  { trace::trace(self, start, end, Vec3f::zero(), Vec3f::zero(), mask) }
+
+  /* Sweeps the box [mins, maxs], relative to the points, from start to end. */
+  #[inline(always)]
+  pub fn trace_box(&self, start: Vec3f, end: Vec3f, mins: Vec3f, maxs: Vec3f, mask: i32) -> Trace
+  { trace::trace(self, start, end, mins, maxs, mask) }
+
+  /* Baked (ambient, directional, direction) lighting at the point;
+     colors are in [0, 1] and the direction points toward the light. */
+  #[inline(always)]
+  pub fn sample_light(&self, position: Vec3f) -> (Vec3f, Vec3f, Vec3f)
+  { self.light_grid.sample(position) }
+
+  /* The magic and version, like "IBSP 0x2E". */
+  pub fn get_version(&self) -> ~str
   {
-    self.vao = check!(gl::gen_vertex_arrays(1))[0];
-    self.vbo = check!(gl::gen_buffers(1));
-    assert!(self.vbo.len() == 1);
+    let magic = match self.format { lump::Raven_Format => "RBSP", _ => "IBSP" };
+    fmt!("%s 0x%X", magic, self.header.version as uint)
+  }
 
-    check!(gl::bind_vertex_array(self.vao));
-    check!(gl::bind_buffer(gl::ARRAY_BUFFER, self.vbo[0]));
-    check!(gl::buffer_data(gl::ARRAY_BUFFER, self.verts, gl::STATIC_DRAW));
+  /* (name, length in bytes, element count) of each lump in the file;
+     the count is None for lumps without fixed size elements. */
+  pub fn get_lump_info(&self) -> ~[(~str, uint, Option<uint>)]
+  {
+    let mut info = ~[];
+    for uint::range(0, self.header.num_lumps) |i|
+    {
+      let length = self.header.lumps[i].length as uint;
+      let size = lump::element_size(self.format, i);
+      info.push((lump::Lump_Names[i].to_owned(), length, if size > 0 { Some(length / size) } else { None }));
+    }
+    info
   }
 
-  pub fn draw(&self)
+  /* Every drawable triangle, grouped by texture, for the exporters. */
+  pub fn to_mesh(&self) -> Mesh
   {
-    check!(gl::bind_vertex_array(self.vao));
-    check!(gl::bind_buffer(gl::ARRAY_BUFFER, self.vbo[0]));
-    check!(gl::enable_vertex_attrib_array(0));
-    check!(gl::enable_vertex_attrib_array(1));
+    let mut mesh = Mesh::new();
+    let mut groups = HashMap::new::<i32, uint>();
+    for self.draw_order.iter().advance |&i|
+    {
+      let (start, count) = self.face_ranges[i];
+      if count == 0
+      { loop; }
 
-    check!(gl::vertex_attrib_pointer_f32(0, 3, false, 
-                sys::size_of::<lump::Vertex>() as i32, 
-                0));
-    check!(gl::vertex_attrib_pointer_u8(1, 4, true, 
-                sys::size_of::<lump::Vertex>() as i32, 
-                sys::size_of::<lump::Vertex>() as u32 -
-                sys::size_of::<Vec4u8>() as u32));
-    check!(gl::draw_arrays(gl::TRIANGLES, 0, self.verts.len() as i32));
+      let texture = self.faces[i].texture;
+      let group = match groups.find(&texture)
+      {
+        Some(&group) => { group }
+        None =>
+        {
+          let group = mesh.add_group(self.textures[texture].get_name(),
+                                     self.surface_images[self.materials[texture]]);
+          groups.insert(texture, group);
+          group
+        }
+      };
 
-    check!(gl::disable_vertex_attrib_array(0));
-    check!(gl::disable_vertex_attrib_array(1));
-    check!(gl::bind_vertex_array(0));
-    check!(gl::bind_buffer(gl::ARRAY_BUFFER, 0));
+      for uint::range(start, start + count) |v|
+      {
+        let vert = &self.verts[v];
+        let color = Vec3f::new( vert.color.x as f32 / 255.0,
+                                vert.color.y as f32 / 255.0,
+                                vert.color.z as f32 / 255.0);
+        let index = mesh.add_vertex(vert.position, color, Some(vert.tex_coords[0]));
+        mesh.groups[group].indices.push(index);
+      }
+    }
+    mesh
+  }
+
+  /* A fresh copy of an inline model, by name ("*3"), to be moved around. */
+  pub fn get_model(&self, name: &str) -> Option<Model>
+  {
+    match model::parse_name(name)
+    {
+      Some(index) if index < self.models.len() => { Some(copy self.inline_models[index - 1]) }
+      _ => { None }
+    }
+  }
+
+  /* The inline model an entity (door, platform, mover) uses, placed at its origin. */
+  pub fn get_entity_model(&self, ent: &Entity) -> Option<Model>
+  {
+    let name = match ent.get("model")
+    {
+      Some(name) => { copy *name }
+      None => { return None; }
+    };
+
+    match self.get_model(name)
+    {
+      Some(mut model) =>
+      {
+        match ent.origin
+        {
+          /* An origin brush leaves the verts relative to the origin. */
+          Some(origin) => { model.pivot = Vec3f::zero(); model.translate_to(origin); }
+          None => { }
+        }
+        Some(model)
+      }
+      None => { warn!("BSP: Entity '%s' references missing model '%s'", ent.class_name, name); None }
+    }
+  }
+
+  /* All entities of the given class, in lump order. */
+  pub fn find_entities<'a>(&'a self, class_name: &str) -> ~[&'a Entity]
+  {
+    let mut found = ~[];
+    for self.entities.iter().advance |ent|
+    {
+      if ent.class_name.as_slice() == class_name
+      { found.push(ent); }
+    }
+    found
+  }
+
+  /* The first entity of the given class; handy for singletons like worldspawn. */
+  pub fn find_entity<'a>(&'a self, class_name: &str) -> Option<&'a Entity>
+  {
+    for self.entities.iter().advance |ent|
+    {
+      if ent.class_name.as_slice() == class_name
+      { return Some(ent); }
+    }
+    None
   }
 
   #[inline(always)]
@@ -250,3 +967,301 @@ impl Map
   { self.bb.center_with_offset(self.position) }
 }
 
+/* Appends the run of render verts, merging it into the last batch when it continues it. */
+priv fn push_batch(batches: &mut ~[Batch], start: uint, count: uint, texture: i32, lightmap: i32, fog: i32)
+{
+  let len = batches.len();
+  if len > 0
+  {
+    let last = &mut batches[len - 1];
+    if last.start + last.count == start && last.texture == texture &&
+       last.lightmap == lightmap && last.fog == fog
+    {
+      last.count += count;
+      return;
+    }
+  }
+  batches.push(Batch { start: start, count: count, texture: texture, lightmap: lightmap, fog: fog });
+}
+
+#[inline(always)]
+priv fn to_vertex_pc(vert: &lump::Vertex) -> Vertex_PC
+{
+  Vertex_PC::new( vert.position,
+                  Vec3f::new( vert.color.x as f32,
+                              vert.color.y as f32,
+                              vert.color.z as f32))
+}
+
+/* "textures/base/wall.tga" becomes "textures/base/wall"; we try each extension ourselves. */
+priv fn strip_extension(name: &str) -> ~str
+{
+  for Texture_Extensions.iter().advance |ext|
+  {
+    if name.ends_with(*ext)
+    { return name.slice(0, name.len() - ext.len()).to_owned(); }
+  }
+  name.to_owned()
+}
+
+
+#[cfg(test)]
+mod test
+{
+  use std::{ io, f32 };
+  use math::Vec3f;
+  use vfs::Vfs;
+  use super::{ Map, Default_Patch_Level, lump };
+  use super::super::error;
+  use super::super::error::Load_Error;
+
+  fn read_map(file: &str) -> ~[u8]
+  {
+    match io::read_whole_file(&Path(fmt!("data/maps/%s", file)))
+    {
+      Ok(data) => { data }
+      Err(err) => { fail!(err) }
+    }
+  }
+
+  /* Materials are read through the VFS, even without GL. */
+  fn load(data: &[u8]) -> Result<Map, Load_Error>
+  {
+    let vfs = Vfs::new();
+    vfs.mount_dir("data");
+    Map::load_from_bytes(data, Default_Patch_Level)
+  }
+
+  fn load_err(data: &[u8]) -> Load_Error
+  {
+    match load(data)
+    {
+      Ok(_) => { fail!(~"Loaded a corrupt map") }
+      Err(err) => { err }
+    }
+  }
+
+  /* (offset, length) of the lump, from the header. */
+  fn lump_at(data: &[u8], kind: lump::Lump_Type) -> (uint, uint)
+  {
+    let entry = 8 + (kind as uint) * 8;
+    (lump::read_i32(data, entry) as uint, lump::read_i32(data, entry + 4) as uint)
+  }
+
+  fn write_i32(data: &mut ~[u8], at: uint, value: i32)
+  {
+    let value = value as u32;
+    data[at] = value as u8;
+    data[at + 1] = (value >> 8) as u8;
+    data[at + 2] = (value >> 16) as u8;
+    data[at + 3] = (value >> 24) as u8;
+  }
+
+  fn expect_bad_index(err: Load_Error, lump: &str, element: uint, field: &str, value: i32)
+  {
+    match err
+    {
+      error::Bad_Index(ref l, e, ref f, v) =>
+      {
+        assert_eq!(l.as_slice(), lump);
+        assert_eq!(e, element);
+        assert!(f.starts_with(field));
+        assert_eq!(v, value);
+      }
+      _ => { fail!(fmt!("Expected a bad index; got: %s", err.to_str())); }
+    }
+  }
+
+  #[test]
+  fn bundled()
+  {
+    for ["q3ctf1.bsp", "dk.bsp"].iter().advance |file|
+    {
+      match load(read_map(*file))
+      {
+        Ok(map) => { assert!(map.models.len() > 0 && map.tris.len() > 0); }
+        Err(err) => { fail!(fmt!("%s: %s", *file, err.to_str())); }
+      }
+    }
+  }
+
+  #[test]
+  fn truncated_header()
+  {
+    let data = read_map("q3ctf1.bsp");
+    match load_err(data.slice(0, 20))
+    {
+      error::Truncated_Header(20, _) => { }
+      err => { fail!(err.to_str()); }
+    }
+  }
+
+  #[test]
+  fn lump_out_of_range()
+  {
+    let mut data = read_map("q3ctf1.bsp");
+    let len = data.len();
+    write_i32(&mut data, 8 + (lump::Face_Type as uint) * 8 + 4, len as i32);
+    match load_err(data)
+    {
+      error::Lump_Out_Of_Range(ref name, _, _) => { assert_eq!(name.as_slice(), "faces"); }
+      err => { fail!(err.to_str()); }
+    }
+  }
+
+  #[test]
+  fn misaligned_lump()
+  {
+    let mut data = read_map("q3ctf1.bsp");
+    let (_, length) = lump_at(data, lump::Node_Type);
+    write_i32(&mut data, 8 + (lump::Node_Type as uint) * 8 + 4, (length - 1) as i32);
+    match load_err(data)
+    {
+      error::Misaligned_Lump(ref name, _, size) =>
+      {
+        assert_eq!(name.as_slice(), "nodes");
+        assert_eq!(size, lump::Node_Size);
+      }
+      err => { fail!(err.to_str()); }
+    }
+  }
+
+  #[test]
+  fn no_models()
+  {
+    let mut data = read_map("q3ctf1.bsp");
+    write_i32(&mut data, 8 + (lump::Model_Type as uint) * 8 + 4, 0);
+    match load_err(data)
+    {
+      error::Empty_Lump(ref name) => { assert_eq!(name.as_slice(), "models"); }
+      err => { fail!(err.to_str()); }
+    }
+  }
+
+  #[test]
+  fn latin1_entities()
+  {
+    /* Editors don't all write UTF-8; a stray byte mustn't lose the map.
+       q3ctf1's entities start with {\n"message" "Dueling Keeps". */
+    let mut data = read_map("q3ctf1.bsp");
+    let (entities, _) = lump_at(data, lump::Entity_Type);
+    assert_eq!(data[entities + 3], 'm' as u8);
+    data[entities + 3] = 0xE9;
+
+    match load(data)
+    {
+      Ok(map) => { assert_eq!(map.entities[0].get("éessage"), Some(&~"Dueling Keeps")); }
+      Err(err) => { fail!(err.to_str()); }
+    }
+  }
+
+  #[test]
+  fn node_cycle()
+  {
+    /* Pointing back at the root would loop forever. */
+    let mut data = read_map("q3ctf1.bsp");
+    let (nodes, _) = lump_at(data, lump::Node_Type);
+    write_i32(&mut data, nodes + (5 * lump::Node_Size) + 4, 0);
+    expect_bad_index(load_err(data), "nodes", 5, "child node", 0);
+
+    /* As would pointing at itself. */
+    let mut data = read_map("q3ctf1.bsp");
+    write_i32(&mut data, nodes + (5 * lump::Node_Size) + 8, 5);
+    expect_bad_index(load_err(data), "nodes", 5, "child node", 5);
+  }
+
+  #[test]
+  fn patch_size()
+  {
+    /* The first face of q3ctf1 is a 3x3 patch. */
+    let original = read_map("q3ctf1.bsp");
+    let (faces, _) = lump_at(original, lump::Face_Type);
+    let (width, height) = (faces + 96, faces + 100);
+    assert_eq!(lump::read_i32(original, faces + 8), 2);
+
+    let mut data = read_map("q3ctf1.bsp");
+    write_i32(&mut data, width, 4);
+    expect_bad_index(load_err(data), "faces", 0, "patch width", 4);
+
+    let mut data = read_map("q3ctf1.bsp");
+    write_i32(&mut data, height, 1);
+    expect_bad_index(load_err(data), "faces", 0, "patch height", 1);
+
+    /* Would overflow an i32 when multiplied. */
+    let mut data = read_map("q3ctf1.bsp");
+    write_i32(&mut data, width, 65535);
+    write_i32(&mut data, height, 65535);
+    expect_bad_index(load_err(data), "faces", 0, "vertex count", 9);
+  }
+
+  /* Q3 units, as in the editor, to ours. */
+  fn q3(x: f32, y: f32, z: f32) -> Vec3f
+  { lump::convert_position(Vec3f::new(x, y, z)) }
+
+  fn assert_near(a: Vec3f, b: Vec3f)
+  {
+    if f32::abs(a.x - b.x) > 0.001 || f32::abs(a.y - b.y) > 0.001 || f32::abs(a.z - b.z) > 0.001
+    { fail!(fmt!("%? is not near %?", a, b)); }
+  }
+
+  fn load_q3ctf1() -> Map
+  {
+    match load(read_map("q3ctf1.bsp"))
+    {
+      Ok(map) => { map }
+      Err(err) => { fail!(err.to_str()); }
+    }
+  }
+
+  /* q3ctf1 has a 36 unit thick block wall spanning x 1286 to 1322,
+     y -1240 to -640, and z 192 to 288, with open space west of it. */
+  #[test]
+  fn trace_ray()
+  {
+    let map = load_q3ctf1();
+    let (start, end) = (q3(1222.0, -940.0, 240.0), q3(1304.0, -940.0, 240.0));
+    let trace = map.trace_ray(start, end, lump::Mask_Solid);
+
+    /* Stops 0.125 units short of the face, 64 units into an 82 unit ray. */
+    assert!(f32::abs(trace.fraction - ((64.0 - 0.125) / 82.0)) < 0.0001);
+    assert_near(trace.normal, Vec3f::new(-1.0, 0.0, 0.0));
+    assert_near(trace.end_position, q3(1286.0 - 0.125, -940.0, 240.0));
+    assert!(!trace.start_solid && !trace.all_solid);
+
+    /* Nothing to hit on the way back out. */
+    let trace = map.trace_ray(start, q3(1270.0, -940.0, 240.0), lump::Mask_Solid);
+    assert_eq!(trace.fraction, 1.0);
+    assert_near(trace.end_position, q3(1270.0, -940.0, 240.0));
+  }
+
+  #[test]
+  fn trace_box()
+  {
+    /* A 32 unit cube stops with its leading face against the wall. */
+    let map = load_q3ctf1();
+    let half = 16.0 / 32.0;
+    let (mins, maxs) = (Vec3f::new(-half, -half, -half), Vec3f::new(half, half, half));
+    let trace = map.trace_box(q3(1222.0, -940.0, 240.0), q3(1304.0, -940.0, 240.0),
+                              mins, maxs, lump::Mask_Solid);
+
+    assert!(f32::abs(trace.fraction - ((48.0 - 0.125) / 82.0)) < 0.0001);
+    assert_near(trace.normal, Vec3f::new(-1.0, 0.0, 0.0));
+    assert_near(trace.end_position, q3(1286.0 - 16.0 - 0.125, -940.0, 240.0));
+    assert!(!trace.start_solid && !trace.all_solid);
+  }
+
+  #[test]
+  fn trace_start_solid()
+  {
+    let map = load_q3ctf1();
+
+    /* Entirely within the wall. */
+    let trace = map.trace_ray(q3(1304.0, -940.0, 240.0), q3(1310.0, -940.0, 240.0), lump::Mask_Solid);
+    assert!(trace.start_solid && trace.all_solid);
+    assert_eq!(trace.fraction, 0.0);
+
+    /* Starting within the wall, but leaving it. */
+    let trace = map.trace_ray(q3(1304.0, -940.0, 240.0), q3(1350.0, -940.0, 240.0), lump::Mask_Solid);
+    assert!(trace.start_solid && !trace.all_solid);
+  }
+}
@@ -0,0 +1,698 @@
+/*
+    Copyright 2013 Jesse 'Jeaye' Wilkerson
+    See licensing in LICENSE file, or at:
+        http://www.opensource.org/licenses/BSD-3-Clause
+
+    File: obj/bsp/material.rs
+    Author: Jesse 'Jeaye' Wilkerson
+    Description:
+      Parser for Q3's .shader scripts, which
+      describe the materials named in a BSP's
+      texture lump.
+*/
+
+use std::{ f32, uint };
+use std::hashmap::HashMap;
+use std::iterator::IteratorUtil;
+use math::Vec3f;
+use vfs::{ Vfs, to_lowercase };
+
+/* Surface parameters (surfaceparm). */
+pub static Param_No_Draw: u32 = 1 << 0;
+pub static Param_Trans: u32 = 1 << 1;
+pub static Param_Sky: u32 = 1 << 2;
+pub static Param_No_Lightmap: u32 = 1 << 3;
+pub static Param_Non_Solid: u32 = 1 << 4;
+pub static Param_Player_Clip: u32 = 1 << 5;
+pub static Param_Monster_Clip: u32 = 1 << 6;
+pub static Param_Water: u32 = 1 << 7;
+pub static Param_Slime: u32 = 1 << 8;
+pub static Param_Lava: u32 = 1 << 9;
+pub static Param_Fog: u32 = 1 << 10;
+pub static Param_No_Marks: u32 = 1 << 11;
+pub static Param_No_Impact: u32 = 1 << 12;
+pub static Param_Area_Portal: u32 = 1 << 13;
+pub static Param_Cluster_Portal: u32 = 1 << 14;
+pub static Param_Detail: u32 = 1 << 15;
+pub static Param_Structural: u32 = 1 << 16;
+pub static Param_Origin: u32 = 1 << 17;
+pub static Param_Hint: u32 = 1 << 18;
+pub static Param_Skip: u32 = 1 << 19;
+pub static Param_Ladder: u32 = 1 << 20;
+pub static Param_Slick: u32 = 1 << 21;
+pub static Param_Metal_Steps: u32 = 1 << 22;
+pub static Param_No_Steps: u32 = 1 << 23;
+pub static Param_No_Dlight: u32 = 1 << 24;
+pub static Param_Alpha_Shadow: u32 = 1 << 25;
+pub static Param_Light_Filter: u32 = 1 << 26;
+pub static Param_Do_Not_Enter: u32 = 1 << 27;
+
+/* Surfaces with any of these never get drawn or voxelized. */
+pub static Param_Invisible: u32 = Param_No_Draw | Param_Player_Clip | Param_Monster_Clip |
+                                  Param_Hint | Param_Skip | Param_Area_Portal |
+                                  Param_Cluster_Portal | Param_Origin;
+
+pub enum Cull
+{
+  Cull_Front,
+  Cull_Back,
+  Cull_None,
+}
+
+pub struct Stage
+{
+  /* One image, several for animMap, or "$lightmap"/"$whiteimage". */
+  maps: ~[~str],
+  /* Frames per second of an animMap. */
+  anim_frequency: f32,
+  /* clampMap instead of map. */
+  clamp: bool,
+  /* Source and destination factors, as GL names. None is opaque. */
+  blend_func: Option<(~str, ~str)>,
+  /* Each tcMod, with its arguments. */
+  tc_mods: ~[~[~str]],
+  tc_gen: ~str,
+  rgb_gen: ~str,
+  alpha_gen: ~str,
+  alpha_func: ~str,
+  depth_func: ~str,
+  depth_write: bool,
+}
+
+impl Stage
+{
+  #[inline(always)]
+  pub fn new() -> Stage
+  {
+    Stage
+    {
+      maps: ~[],
+      anim_frequency: 0.0,
+      clamp: false,
+      blend_func: None,
+      tc_mods: ~[],
+      tc_gen: ~"base",
+      rgb_gen: ~"identity",
+      alpha_gen: ~"identity",
+      alpha_func: ~"",
+      depth_func: ~"lequal",
+      depth_write: true,
+    }
+  }
+}
+
+pub struct Material
+{
+  /* Lowercase, as referenced from the texture lump. */
+  name: ~str,
+  surface_params: u32,
+  cull: Cull,
+  /* Each deformVertexes, with its arguments. */
+  deforms: ~[~[~str]],
+  /* Color in [0, 1] and the distance, in Q3 units, at which it becomes opaque. */
+  fog_parms: Option<(Vec3f, f32)>,
+  stages: ~[Stage],
+}
+
+impl Material
+{
+  #[inline(always)]
+  pub fn new(new_name: &str) -> Material
+  {
+    Material
+    {
+      name: to_lowercase(new_name),
+      surface_params: 0,
+      cull: Cull_Front,
+      deforms: ~[],
+      fog_parms: None,
+      stages: ~[],
+    }
+  }
+
+  #[inline(always)]
+  pub fn has_param(&self, param: u32) -> bool
+  { (self.surface_params & param) != 0 }
+
+  /* The image to show when only one can be; the first stage that isn't a lightmap. */
+  pub fn base_image<'a>(&'a self) -> Option<&'a ~str>
+  {
+    for self.stages.iter().advance |stage|
+    {
+      if stage.maps.len() > 0 && !stage.maps[0].starts_with("$")
+      { return Some(&stage.maps[0]); }
+    }
+    None
+  }
+
+  /* Parses every material in the script; a block left open
+     fails the whole script, since nothing after it can be trusted. */
+  pub fn parse(script: &str) -> Result<~[Material], ~str>
+  {
+    let mut lexer = Lexer::new(script);
+    let mut materials = ~[];
+
+    loop
+    {
+      let name = match lexer.next(true)
+      {
+        Some(name) => { name }
+        None => { break; }
+      };
+
+      match lexer.next(true)
+      {
+        Some(ref brace) if *brace == ~"{" => { }
+        _ => { return Err(fmt!("Expected '{' after '%s' on line %?", name, lexer.line)); }
+      }
+
+      let mut material = Material::new(name);
+      match material.parse_body(&mut lexer)
+      {
+        Ok(()) => { materials.push(material); }
+        Err(err) => { return Err(err); }
+      }
+    }
+
+    Ok(materials)
+  }
+
+  priv fn parse_body(&mut self, lexer: &mut Lexer) -> Result<(), ~str>
+  {
+    loop
+    {
+      let token = match lexer.next(true)
+      {
+        Some(token) => { to_lowercase(token) }
+        None => { return Err(fmt!("'%s' is missing its closing '}'", self.name)); }
+      };
+
+      match token
+      {
+        ~"}" => { return Ok(()); }
+        ~"{" =>
+        {
+          match parse_stage(lexer, self.name)
+          {
+            Ok(stage) => { self.stages.push(stage); }
+            Err(err) => { return Err(err); }
+          }
+        }
+        ~"surfaceparm" =>
+        {
+          match lexer.next(false)
+          {
+            Some(param) =>
+            {
+              let flag = surface_param(to_lowercase(param));
+              if flag == 0
+              { debug!("Material: Unknown surfaceparm '%s' in '%s'", param, self.name); }
+              self.surface_params |= flag;
+            }
+            None => { }
+          }
+        }
+        ~"cull" =>
+        {
+          self.cull = match lexer.next(false)
+          {
+            Some(mode) =>
+            {
+              match to_lowercase(mode)
+              {
+                ~"none" | ~"disable" | ~"twosided" => { Cull_None }
+                ~"back" | ~"backside" | ~"backsided" => { Cull_Back }
+                _ => { Cull_Front }
+              }
+            }
+            None => { Cull_Front }
+          };
+        }
+        ~"deformvertexes" => { self.deforms.push(lexer.rest_of_line()); }
+        ~"fogparms" =>
+        {
+          /* fogparms ( r g b ) distance; the parens are optional. */
+          let mut args = ~[];
+          for lexer.rest_of_line().iter().advance |arg|
+          {
+            if *arg == ~"(" || *arg == ~")"
+            { loop; }
+            match f32::from_str(*arg)
+            {
+              Some(f) => { args.push(f); }
+              None => { }
+            }
+          }
+
+          if args.len() >= 4
+          { self.fog_parms = Some((Vec3f::new(args[0], args[1], args[2]), args[3])); }
+          else
+          { warn!("Material: Invalid fogparms in '%s'", self.name); }
+        }
+        _ => { /* Editor and compiler directives we don't need. */ }
+      }
+      lexer.skip_line();
+    }
+  }
+}
+
+priv fn parse_stage(lexer: &mut Lexer, name: &str) -> Result<Stage, ~str>
+{
+  let mut stage = Stage::new();
+  let mut depth_write_set = false;
+
+  loop
+  {
+    let token = match lexer.next(true)
+    {
+      Some(token) => { to_lowercase(token) }
+      None => { return Err(fmt!("A stage of '%s' is missing its closing '}'", name)); }
+    };
+
+    match token
+    {
+      ~"}" => { break; }
+      ~"map" | ~"clampmap" =>
+      {
+        stage.clamp = (token == ~"clampmap");
+        match lexer.next(false)
+        {
+          Some(map) => { stage.maps = ~[map]; }
+          None => { warn!("Material: Missing map in '%s'", name); }
+        }
+      }
+      ~"animmap" =>
+      {
+        let args = lexer.rest_of_line();
+        if args.len() > 1
+        {
+          stage.anim_frequency = match f32::from_str(args[0]) { Some(f) => f, None => 0.0 };
+          stage.maps = args.slice(1, args.len()).to_owned();
+        }
+      }
+      ~"blendfunc" =>
+      {
+        let args = lexer.rest_of_line().map(|a| to_upper(*a));
+        stage.blend_func = match args.len()
+        {
+          1 =>
+          {
+            match args[0]
+            {
+              ~"ADD" | ~"GL_ADD" => { Some((~"GL_ONE", ~"GL_ONE")) }
+              ~"FILTER" => { Some((~"GL_DST_COLOR", ~"GL_ZERO")) }
+              ~"BLEND" => { Some((~"GL_SRC_ALPHA", ~"GL_ONE_MINUS_SRC_ALPHA")) }
+              _ => { warn!("Material: Unknown blendFunc '%s' in '%s'", args[0], name); None }
+            }
+          }
+          2 => { Some((copy args[0], copy args[1])) }
+          _ => { None }
+        };
+
+        /* Blended stages don't write depth, unless told to. */
+        if !depth_write_set
+        { stage.depth_write = stage.blend_func.is_none(); }
+      }
+      ~"tcmod" => { stage.tc_mods.push(lexer.rest_of_line()); }
+      ~"tcgen" | ~"texgen" => { stage.tc_gen = next_lower(lexer, ~"base"); }
+      ~"rgbgen" => { stage.rgb_gen = next_lower(lexer, ~"identity"); }
+      ~"alphagen" => { stage.alpha_gen = next_lower(lexer, ~"identity"); }
+      ~"alphafunc" => { stage.alpha_func = to_upper(next_lower(lexer, ~"")); }
+      ~"depthfunc" => { stage.depth_func = next_lower(lexer, ~"lequal"); }
+      ~"depthwrite" => { stage.depth_write = true; depth_write_set = true; }
+      _ => { }
+    }
+    lexer.skip_line();
+  }
+
+  Ok(stage)
+}
+
+#[inline(always)]
+priv fn next_lower(lexer: &mut Lexer, default: ~str) -> ~str
+{
+  match lexer.next(false)
+  {
+    Some(token) => { to_lowercase(token) }
+    None => { default }
+  }
+}
+
+priv fn surface_param(param: &str) -> u32
+{
+  match param
+  {
+    "nodraw" => { Param_No_Draw }
+    "trans" => { Param_Trans }
+    "sky" => { Param_Sky }
+    "nolightmap" => { Param_No_Lightmap }
+    "nonsolid" => { Param_Non_Solid }
+    "playerclip" => { Param_Player_Clip }
+    "monsterclip" => { Param_Monster_Clip }
+    "water" => { Param_Water }
+    "slime" => { Param_Slime }
+    "lava" => { Param_Lava }
+    "fog" => { Param_Fog }
+    "nomarks" => { Param_No_Marks }
+    "noimpact" => { Param_No_Impact }
+    "areaportal" => { Param_Area_Portal }
+    "clusterportal" => { Param_Cluster_Portal }
+    "detail" => { Param_Detail }
+    "structural" => { Param_Structural }
+    "origin" => { Param_Origin }
+    "hint" => { Param_Hint }
+    "skip" => { Param_Skip }
+    "ladder" => { Param_Ladder }
+    "slick" => { Param_Slick }
+    "metalsteps" => { Param_Metal_Steps }
+    "nosteps" => { Param_No_Steps }
+    "nodlight" => { Param_No_Dlight }
+    "alphashadow" => { Param_Alpha_Shadow }
+    "lightfilter" => { Param_Light_Filter }
+    "donotenter" => { Param_Do_Not_Enter }
+    _ => { 0 }
+  }
+}
+
+priv fn to_upper(s: &str) -> ~str
+{
+  let mut out = ~"";
+  for s.iter().advance |c|
+  {
+    if c >= 'a' && c <= 'z'
+    { out.push_char(((c as u8) - 32) as char); }
+    else
+    { out.push_char(c); }
+  }
+  out
+}
+
+/* Every material from every script, keyed by lowercase name. */
+pub struct Material_Table
+{
+  materials: HashMap<~str, Material>,
+}
+
+impl Material_Table
+{
+  #[inline(always)]
+  pub fn new() -> Material_Table
+  { Material_Table { materials: HashMap::new::<~str, Material>() } }
+
+  /* Parses each scripts/*.shader in the VFS. Like Q3, the first
+     definition of a name wins. */
+  pub fn load() -> Material_Table
+  {
+    let mut table = Material_Table::new();
+    let vfs = Vfs::get();
+    for vfs.list("scripts/*.shader").iter().advance |file|
+    {
+      let script = match vfs.read_str(*file)
+      {
+        Some(script) => { script }
+        None => { warn!("Material: Unable to read %s", *file); loop; }
+      };
+
+      match Material::parse(script)
+      {
+        Ok(materials) =>
+        {
+          for materials.consume_iter().advance |material|
+          {
+            if !table.materials.contains_key(&material.name)
+            { table.materials.insert(copy material.name, material); }
+          }
+        }
+        Err(err) => { warn!("Material: %s: %s", *file, err); }
+      }
+    }
+
+    debug!("Material: Loaded %? materials.", table.materials.len());
+    table
+  }
+
+  #[inline(always)]
+  pub fn find<'a>(&'a self, name: &str) -> Option<&'a Material>
+  { self.materials.find(&to_lowercase(name)) }
+
+  #[inline(always)]
+  pub fn len(&self) -> uint
+  { self.materials.len() }
+}
+
+/* Splits scripts into tokens, like Q3's COM_ParseExt. */
+struct Lexer
+{
+  chars: ~[char],
+  pos: uint,
+  line: uint,
+}
+
+impl Lexer
+{
+  pub fn new(script: &str) -> Lexer
+  {
+    let mut chars = ~[];
+    for script.iter().advance |c|
+    { chars.push(c); }
+    Lexer { chars: chars, pos: 0, line: 1 }
+  }
+
+  /* The next token; when not crossing lines, None at the end of the line. */
+  pub fn next(&mut self, cross_lines: bool) -> Option<~str>
+  {
+    /* Whitespace and comments. */
+    loop
+    {
+      if self.pos >= self.chars.len()
+      { return None; }
+
+      let c = self.chars[self.pos];
+      if c == '\n'
+      {
+        if !cross_lines
+        { return None; }
+        self.line += 1;
+        self.pos += 1;
+      }
+      else if c == ' ' || c == '\t' || c == '\r'
+      { self.pos += 1; }
+      else if self.peek_is("//")
+      {
+        while self.pos < self.chars.len() && self.chars[self.pos] != '\n'
+        { self.pos += 1; }
+      }
+      else if self.peek_is("/*")
+      {
+        self.pos += 2;
+        while self.pos < self.chars.len() && !self.peek_is("*/")
+        {
+          if self.chars[self.pos] == '\n'
+          { self.line += 1; }
+          self.pos += 1;
+        }
+        self.pos = uint::min(self.pos + 2, self.chars.len());
+      }
+      else
+      { break; }
+    }
+
+    let mut token = ~"";
+    if self.chars[self.pos] == '"'
+    {
+      self.pos += 1;
+      while self.pos < self.chars.len() && self.chars[self.pos] != '"' && self.chars[self.pos] != '\n'
+      { token.push_char(self.chars[self.pos]); self.pos += 1; }
+      if self.pos < self.chars.len() && self.chars[self.pos] == '"'
+      { self.pos += 1; }
+      return Some(token);
+    }
+
+    while self.pos < self.chars.len()
+    {
+      let c = self.chars[self.pos];
+      if c == ' ' || c == '\t' || c == '\r' || c == '\n'
+      { break; }
+      token.push_char(c);
+      self.pos += 1;
+    }
+    Some(token)
+  }
+
+  /* Every remaining token on the current line. */
+  pub fn rest_of_line(&mut self) -> ~[~str]
+  {
+    let mut tokens = ~[];
+    loop
+    {
+      match self.next(false)
+      {
+        Some(token) => { tokens.push(token); }
+        None => { break; }
+      }
+    }
+    tokens
+  }
+
+  pub fn skip_line(&mut self)
+  {
+    while self.next(false).is_some()
+    { }
+  }
+
+  priv fn peek_is(&self, s: &str) -> bool
+  {
+    let mut i = self.pos;
+    for s.iter().advance |c|
+    {
+      if i >= self.chars.len() || self.chars[i] != c
+      { return false; }
+      i += 1;
+    }
+    true
+  }
+}
+
+
+#[cfg(test)]
+mod test
+{
+  use super::{ Material, Lexer, Cull_None, Param_Sky, Param_No_Lightmap, Param_Trans };
+
+  fn parse(script: &str) -> ~[Material]
+  {
+    match Material::parse(script)
+    {
+      Ok(materials) => { materials }
+      Err(err) => { fail!(err); }
+    }
+  }
+
+  #[test]
+  fn stages()
+  {
+    let materials = parse(
+      "textures/base/sky\n\
+       {\n\
+         surfaceparm sky\n\
+         surfaceparm noLightmap\n\
+         cull none\n\
+         {\n\
+           map $lightmap\n\
+           rgbGen identity\n\
+         }\n\
+         {\n\
+           clampMap textures/base/clouds.tga\n\
+           blendFunc GL_DST_COLOR GL_ZERO\n\
+           tcMod scroll 0.1 0\n\
+         }\n\
+       }\n\
+       textures/base/glass\n\
+       {\n\
+         surfaceparm trans\n\
+         { map textures/base/glass.tga\n\
+           blendFunc add\n\
+         }\n\
+       }\n");
+    assert_eq!(materials.len(), 2);
+
+    let sky = &materials[0];
+    assert_eq!(sky.name.as_slice(), "textures/base/sky");
+    assert!(sky.has_param(Param_Sky) && sky.has_param(Param_No_Lightmap));
+    match sky.cull { Cull_None => { } _ => { fail!(~"Expected no culling"); } }
+    assert_eq!(sky.stages.len(), 2);
+    assert_eq!(sky.stages[0].maps, ~[~"$lightmap"]);
+    assert!(sky.stages[0].depth_write);
+    assert!(sky.stages[1].clamp);
+    assert_eq!(sky.stages[1].blend_func, Some((~"GL_DST_COLOR", ~"GL_ZERO")));
+    assert_eq!(sky.stages[1].tc_mods, ~[~[~"scroll", ~"0.1", ~"0"]]);
+    assert!(!sky.stages[1].depth_write);
+    assert_eq!(sky.base_image(), Some(&~"textures/base/clouds.tga"));
+
+    let glass = &materials[1];
+    assert!(glass.has_param(Param_Trans));
+    assert_eq!(glass.stages.len(), 1);
+    assert_eq!(glass.stages[0].blend_func, Some((~"GL_ONE", ~"GL_ONE")));
+  }
+
+  #[test]
+  fn comments()
+  {
+    let materials = parse(
+      "// A leading comment { with a brace\n\
+       textures/base/wall // trailing\n\
+       {\n\
+         /* Spanning\n\
+            { several lines }\n\
+         */\n\
+         { map textures/base/wall.tga /* inline */\n\
+         }\n\
+       }\n\
+       /* textures/base/hidden { } */\n");
+    assert_eq!(materials.len(), 1);
+    assert_eq!(materials[0].name.as_slice(), "textures/base/wall");
+    assert_eq!(materials[0].stages.len(), 1);
+    assert_eq!(materials[0].stages[0].maps, ~[~"textures/base/wall.tga"]);
+  }
+
+  #[test]
+  fn quoted()
+  {
+    let materials = parse(
+      "\"textures/base/Quoted Name\"\n\
+       {\n\
+         { map \"textures/base/with space.tga\"\n\
+         }\n\
+       }\n");
+    assert_eq!(materials[0].name.as_slice(), "textures/base/quoted name");
+    assert_eq!(materials[0].stages[0].maps, ~[~"textures/base/with space.tga"]);
+
+    /* Quotes keep comment markers and braces, but stop at the line. */
+    let mut lexer = Lexer::new("\"a // { b\" \"open\nnext");
+    assert_eq!(lexer.next(false), Some(~"a // { b"));
+    assert_eq!(lexer.next(false), Some(~"open"));
+    assert_eq!(lexer.next(false), None);
+    assert_eq!(lexer.next(true), Some(~"next"));
+    assert_eq!(lexer.line, 2);
+  }
+
+  #[test]
+  fn unknown()
+  {
+    /* Editor directives and their arguments are skipped, whole lines at a time. */
+    let materials = parse(
+      "textures/base/floor\n\
+       {\n\
+         qer_editorimage textures/base/floor.tga surfaceparm sky\n\
+         q3map_surfacelight 100\n\
+         {\n\
+           detail cull none\n\
+           map textures/base/floor.tga\n\
+         }\n\
+       }\n");
+    let floor = &materials[0];
+    assert_eq!(floor.surface_params, 0);
+    match floor.cull { Cull_None => { fail!(~"Skipped line was parsed"); } _ => { } }
+    assert_eq!(floor.stages.len(), 1);
+    assert_eq!(floor.stages[0].maps, ~[~"textures/base/floor.tga"]);
+  }
+
+  #[test]
+  fn unterminated()
+  {
+    /* An open stage. */
+    assert!(Material::parse("textures/base/a\n{\n{\nmap a.tga\n").is_err());
+
+    /* An open material. */
+    assert!(Material::parse("textures/base/a\n{\n{\nmap a.tga\n}\n").is_err());
+
+    /* A brace inside of a comment doesn't close anything. */
+    assert!(Material::parse("textures/base/a\n{\n// }\n").is_err());
+
+    /* No block at all. */
+    assert!(Material::parse("textures/base/a\ntextures/base/b\n").is_err());
+
+    /* An empty script is fine. */
+    assert_eq!(parse("// Nothing\n").len(), 0);
+  }
+}
@@ -10,6 +10,19 @@
 */
 
 pub use self::map::Map;
+pub use self::map::trace::Trace;
+#[cfg(not(headless))]
+pub use self::map::renderer::Renderer;
+pub use self::entity::Entity;
+pub use self::billboard::Billboard;
+pub use self::error::Load_Error;
+pub use self::model::Model;
+pub use self::material::{ Material, Material_Table };
 
 mod map;
+mod entity;
+mod billboard;
+mod error;
+mod model;
+pub mod material;
 
@@ -0,0 +1,148 @@
+/*
+    Copyright 2013 Jesse 'Jeaye' Wilkerson
+    See licensing in LICENSE file, or at:
+        http://www.opensource.org/licenses/BSD-3-Clause
+
+    File: obj/bsp/model.rs
+    Author: Jesse 'Jeaye' Wilkerson
+    Description:
+      An inline BSP model ("*1", "*2", ...),
+      like a door or platform, which can be
+      moved apart from the world.
+*/
+
+use std::uint;
+use math::{ Vec3f, Mat4x4, BB3 };
+use obj::traits::{ Movable, Rotatable };
+use super::map::Batch;
+
+pub struct Model
+{
+  /* Index into the model lump; 3 for "*3". */
+  index: uint,
+  /* Runs of render verts making up its faces, by texture and lightmap. */
+  batches: ~[Batch],
+  /* Where the compiler left it, before any movement. */
+  bb: BB3,
+  /* What it rotates about, among its own verts. Those are in world
+     space, so this is its center, unless an origin brush left them
+     relative to the entity's origin. */
+  pivot: Vec3f,
+  /* Offset from where the compiler left it. */
+  position: Vec3f,
+  /* Degrees around each axis. */
+  rotation: Vec3f,
+}
+
+impl Model
+{
+  #[inline(always)]
+  pub fn new(new_index: uint, new_batches: ~[Batch], new_bb: BB3) -> Model
+  {
+    Model
+    {
+      index: new_index,
+      batches: new_batches,
+      bb: new_bb,
+      pivot: (new_bb.min() + new_bb.max()) * 0.5,
+      position: Vec3f::zero(),
+      rotation: Vec3f::zero(),
+    }
+  }
+
+  /* The model's transform; rotated about its pivot, then translated.
+     Rotations follow Mat4x4: about x and z they're clockwise, and about
+     y counter-clockwise, each looking from the positive axis. */
+  pub fn get_world(&self) -> Mat4x4
+  {
+    /* Our multiplication composes left to right; the leftmost applies first. */
+    let to = self.pivot + self.position;
+    Mat4x4::new_translation(-self.pivot.x, -self.pivot.y, -self.pivot.z) *
+    Mat4x4::new_rotation_x(self.rotation.x) *
+    Mat4x4::new_rotation_y(self.rotation.y) *
+    Mat4x4::new_rotation_z(self.rotation.z) *
+    Mat4x4::new_translation(to.x, to.y, to.z)
+  }
+
+  /* Bounds after translation; rotation isn't accounted for. */
+  #[inline(always)]
+  pub fn get_bb(&self) -> BB3
+  { BB3::new(self.bb.min() + self.position, self.bb.max() + self.position) }
+
+  #[inline(always)]
+  pub fn center(&self) -> Vec3f
+  { (self.bb.min() + self.bb.max()) * 0.5 + self.position }
+}
+
+impl Movable for Model
+{
+  #[inline(always)]
+  pub fn translate(&mut self, new_position: Vec3f)
+  { self.position += new_position; }
+
+  #[inline(always)]
+  pub fn translate_to(&mut self, new_position: Vec3f)
+  { self.position = new_position; }
+}
+
+impl Rotatable for Model
+{
+  #[inline(always)]
+  pub fn rotate(&mut self, new_rotation: Vec3f)
+  { self.rotation += new_rotation; }
+
+  #[inline(always)]
+  pub fn rotate_to(&mut self, new_rotation: Vec3f)
+  { self.rotation = new_rotation; }
+}
+
+/* "*3" becomes 3; the world (0) can't be referenced this way. */
+pub fn parse_name(name: &str) -> Option<uint>
+{
+  if !name.starts_with("*")
+  { return None; }
+
+  match uint::from_str(name.slice_from(1))
+  {
+    Some(0) => { None }
+    Some(index) => { Some(index) }
+    None => { None }
+  }
+}
+
+#[cfg(test)]
+mod test
+{
+  use math::{ Vec3f, Mat4x4, BB3 };
+  use obj::traits::{ Movable, Rotatable };
+  use super::Model;
+
+  /* Points are columns; the last column of data is the translation. */
+  fn transform(mat: &Mat4x4, p: Vec3f) -> Vec3f
+  {
+    let d = &mat.data;
+    Vec3f::new(d[0][0] * p.x + d[1][0] * p.y + d[2][0] * p.z + d[3][0],
+               d[0][1] * p.x + d[1][1] * p.y + d[2][1] * p.z + d[3][1],
+               d[0][2] * p.x + d[1][2] * p.y + d[2][2] * p.z + d[3][2])
+  }
+
+  fn assert_near(a: Vec3f, b: Vec3f)
+  { assert!((a - b).length() < 0.001, fmt!("%? != %?", a, b)); }
+
+  #[test]
+  fn pivot()
+  {
+    /* A door away from the world origin turns about its own center;
+       clockwise about z, looking down from +z. */
+    let mut model = Model::new(1, ~[], BB3::new(Vec3f::new(8.0, 0.0, 0.0), Vec3f::new(12.0, 2.0, 2.0)));
+    model.rotate_to(Vec3f::new(0.0, 0.0, 90.0));
+    let world = model.get_world();
+    assert_near(transform(&world, Vec3f::new(10.0, 1.0, 1.0)), Vec3f::new(10.0, 1.0, 1.0));
+    assert_near(transform(&world, Vec3f::new(12.0, 1.0, 1.0)), Vec3f::new(10.0, -1.0, 1.0));
+
+    /* Movement applies after the rotation. */
+    model.translate_to(Vec3f::new(0.0, 0.0, 5.0));
+    let world = model.get_world();
+    assert_near(transform(&world, Vec3f::new(12.0, 1.0, 1.0)), Vec3f::new(10.0, -1.0, 6.0));
+  }
+}
@@ -0,0 +1,124 @@
+/*
+    Copyright 2013 Jesse 'Jeaye' Wilkerson
+    See licensing in LICENSE file, or at:
+        http://www.opensource.org/licenses/BSD-3-Clause
+
+    File: obj/bsp/patch.rs
+    Author: Jesse 'Jeaye' Wilkerson
+    Description:
+      Tessellation of biquadratic Bezier
+      patch faces into triangle lists.
+*/
+
+use std::{ i32, uint };
+use math::{ Vec2i, Vec2f, Vec3f, Vec4u8 };
+use super::lump;
+
+/* Tessellates the control grid of a patch face into a list
+   of triangles (three verts each). The grid is made up of
+   overlapping 3x3 patches; each is split 'level' times per side. */
+pub fn tessellate(ctrl: &[lump::Vertex], size: Vec2i, level: i32) -> ~[lump::Vertex]
+{
+  let mut out = ~[];
+  let width = size.x;
+  let height = size.y;
+
+  if width < 3 || height < 3 || width % 2 == 0 || height % 2 == 0 ||
+     (ctrl.len() as u64) < (width as u64) * (height as u64) || level < 1
+  {
+    warn!("BSP: Invalid patch of size %s with %? verts", size.to_str(), ctrl.len());
+    return out;
+  }
+
+  for i32::range(0, (height - 1) / 2) |py|
+  {
+    for i32::range(0, (width - 1) / 2) |px|
+    {
+      let mut grid = [lump::Vertex::new(), ..9];
+      for i32::range(0, 3) |row|
+      {
+        for i32::range(0, 3) |col|
+        { grid[row * 3 + col] = ctrl[((py * 2 + row) * width) + (px * 2) + col]; }
+      }
+
+      tessellate_patch(&grid, level, &mut out);
+    }
+  }
+
+  out
+}
+
+priv fn tessellate_patch(grid: &[lump::Vertex, ..9], level: i32, out: &mut ~[lump::Vertex])
+{
+  /* Evaluate the surface at (level + 1)^2 points. */
+  let side = (level + 1) as uint;
+  let mut points = ~[];
+  for uint::range(0, side) |row|
+  {
+    let v = (row as f32) / (level as f32);
+    for uint::range(0, side) |col|
+    {
+      let u = (col as f32) / (level as f32);
+      points.push(evaluate(grid, u, v));
+    }
+  }
+
+  /* Two triangles per cell; wound the same way as the polygon faces. */
+  for uint::range(0, side - 1) |row|
+  {
+    for uint::range(0, side - 1) |col|
+    {
+      let a = points[(row * side) + col];
+      let b = points[(row * side) + col + 1];
+      let c = points[((row + 1) * side) + col];
+      let d = points[((row + 1) * side) + col + 1];
+
+      out.push(a); out.push(c); out.push(b);
+      out.push(b); out.push(c); out.push(d);
+    }
+  }
+}
+
+#[inline(always)]
+priv fn bernstein(t: f32) -> [f32, ..3]
+{ [ (1.0 - t) * (1.0 - t), 2.0 * t * (1.0 - t), t * t ] }
+
+priv fn evaluate(grid: &[lump::Vertex, ..9], u: f32, v: f32) -> lump::Vertex
+{
+  let bu = bernstein(u);
+  let bv = bernstein(v);
+
+  let mut position = Vec3f::zero();
+  let mut surface = Vec2f::zero();
+  let mut lightmap = Vec2f::zero();
+  let mut normal = Vec3f::zero();
+  let mut color = [0.0f32, ..4];
+
+  for uint::range(0, 3) |row|
+  {
+    for uint::range(0, 3) |col|
+    {
+      let w = bu[col] * bv[row];
+      let vert = &grid[(row * 3) + col];
+
+      position = position + (vert.position * w);
+      surface = surface + (vert.tex_coords[0] * w);
+      lightmap = lightmap + (vert.tex_coords[1] * w);
+      normal = normal + (vert.normal * w);
+      color[0] += (vert.color.x as f32) * w;
+      color[1] += (vert.color.y as f32) * w;
+      color[2] += (vert.color.z as f32) * w;
+      color[3] += (vert.color.w as f32) * w;
+    }
+  }
+  normal.normalize();
+
+  lump::Vertex
+  {
+    position: position,
+    tex_coords: [surface, lightmap],
+    normal: normal,
+    color: Vec4u8::new(color[0] as u8, color[1] as u8, color[2] as u8, color[3] as u8)
+  }
+}
+
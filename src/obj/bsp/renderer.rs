@@ -0,0 +1,246 @@
+/*
+    Copyright 2013 Jesse 'Jeaye' Wilkerson
+    See licensing in LICENSE file, or at:
+        http://www.opensource.org/licenses/BSD-3-Clause
+
+    File: obj/bsp/renderer.rs
+    Author: Jesse 'Jeaye' Wilkerson
+    Description:
+      The GL side of a BSP map; its buffers,
+      textures, and shader. The map itself
+      never touches GL.
+*/
+
+use std::{ uint, vec, sys };
+use std::iterator::IteratorUtil;
+use math::{ Vec2i, Vec2f, Vec3f, Vec4u8, Mat4x4 };
+use primitive::Vertex_PC;
+use gl::{ Camera, Shader, Shader_Builder, Texture };
+use super::{ Map, Batch, lump };
+use super::super::Model;
+
+#[path = "../../gl/mod.rs"]
+mod gl;
+
+#[path = "../../gl/check.rs"]
+mod check;
+
+pub struct Renderer
+{
+  vao: gl::GLuint,
+  vbo: gl::GLuint,
+  billboard_vao: gl::GLuint,
+  billboard_vbo: gl::GLuint,
+
+  /* Lightmapped shader and its uniform locations. */
+  shader: @Shader,
+  proj_loc: gl::GLint,
+  world_loc: gl::GLint,
+  texture_loc: gl::GLint,
+  lightmap_loc: gl::GLint,
+  use_lightmap_loc: gl::GLint,
+  fog_color_loc: gl::GLint,
+  fog_distance_loc: gl::GLint,
+  use_fog_loc: gl::GLint,
+
+  lightmaps: ~[Texture],
+  /* Parallel to the map's surface images; the first is the checkerboard for missing ones. */
+  surface_textures: ~[Texture],
+}
+
+impl Renderer
+{
+  /* Creates the lightmaps, surface textures, shader, and buffers for the map. */
+  pub fn new(map: &Map) -> Renderer
+  {
+    /* Lightmaps. */
+    let size = lump::Light_Map_Bytes;
+    let mut lightmaps = ~[];
+    for uint::range(0, map.lightmap_data.len() / size) |i|
+    {
+      lightmaps.push(Texture::new_with_data( gl::TEXTURE_2D,
+                                             Vec2i::new(lump::Light_Map_Size, lump::Light_Map_Size), 3,
+                                             map.lightmap_data.slice(i * size, (i + 1) * size)));
+    }
+
+    let shader = Shader_Builder::new_with_files("shaders/bsp.vert", "shaders/bsp.frag");
+    let mut renderer = Renderer
+    {
+      vao: 0,
+      vbo: 0,
+      billboard_vao: 0,
+      billboard_vbo: 0,
+      shader: shader,
+      proj_loc: shader.get_uniform_location("proj"),
+      world_loc: shader.get_uniform_location("world"),
+      texture_loc: shader.get_uniform_location("texture0"),
+      lightmap_loc: shader.get_uniform_location("lightmap0"),
+      use_lightmap_loc: shader.get_uniform_location("use_lightmap"),
+      fog_color_loc: shader.get_uniform_location("fog_color"),
+      fog_distance_loc: shader.get_uniform_location("fog_distance"),
+      use_fog_loc: shader.get_uniform_location("use_fog"),
+      lightmaps: lightmaps,
+      surface_textures: load_surface_textures(map),
+    };
+    shader.bind();
+    shader.update_uniform_i32(renderer.texture_loc, 0);
+    shader.update_uniform_i32(renderer.lightmap_loc, 1);
+
+    renderer.vao = check!(gl::gen_vertex_arrays(1))[0];
+    renderer.vbo = check!(gl::gen_buffers(1))[0];
+    check!(gl::bind_vertex_array(renderer.vao));
+    check!(gl::bind_buffer(gl::ARRAY_BUFFER, renderer.vbo));
+    check!(gl::buffer_data(gl::ARRAY_BUFFER, map.verts, gl::STATIC_DRAW));
+
+    /* Billboards are rebuilt every frame to face the camera. */
+    renderer.billboard_vao = check!(gl::gen_vertex_arrays(1))[0];
+    renderer.billboard_vbo = check!(gl::gen_buffers(1))[0];
+    check!(gl::bind_vertex_array(renderer.billboard_vao));
+    check!(gl::bind_buffer(gl::ARRAY_BUFFER, renderer.billboard_vbo));
+    let data: ~[Vertex_PC] = ~[];
+    check!(gl::buffer_data(gl::ARRAY_BUFFER, data, gl::STREAM_DRAW));
+
+    renderer
+  }
+
+  /* Draws what passed the map's last cull, with its own textured and lightmapped shader. */
+  #[inline(always)]
+  pub fn draw(&self, map: &Map, camera: &Camera)
+  { self.draw_batches(map, map.visible_ranges, &camera.view, camera); }
+
+  /* Draws an inline model from the map wherever it has been moved. */
+  #[inline(always)]
+  pub fn draw_model(&self, map: &Map, model: &Model, camera: &Camera)
+  {
+    /* Our multiplication composes left to right, so the model's transform applies before the view. */
+    let world = model.get_world() * camera.view;
+    self.draw_batches(map, model.batches, &world, camera);
+  }
+
+  priv fn draw_batches(&self, map: &Map, batches: &[Batch], world: &Mat4x4, camera: &Camera)
+  {
+    let shader = self.shader;
+    shader.bind();
+    shader.update_uniform_mat(self.proj_loc, &camera.projection);
+    shader.update_uniform_mat(self.world_loc, world);
+
+    check!(gl::bind_vertex_array(self.vao));
+    check!(gl::bind_buffer(gl::ARRAY_BUFFER, self.vbo));
+    check!(gl::enable_vertex_attrib_array(0));
+    check!(gl::enable_vertex_attrib_array(1));
+
+    check!(gl::vertex_attrib_pointer_f32(0, 3, false,
+                sys::size_of::<lump::Vertex>() as i32,
+                0));
+    check!(gl::vertex_attrib_pointer_u8(1, 4, true,
+                sys::size_of::<lump::Vertex>() as i32,
+                sys::size_of::<lump::Vertex>() as u32 -
+                sys::size_of::<Vec4u8>() as u32));
+    check!(gl::enable_vertex_attrib_array(2));
+    check!(gl::vertex_attrib_pointer_f32(2, 2, false,
+                sys::size_of::<lump::Vertex>() as i32,
+                (sys::size_of::<Vec3f>() + sys::size_of::<Vec2f>()) as u32));
+    check!(gl::enable_vertex_attrib_array(3));
+    check!(gl::vertex_attrib_pointer_f32(3, 2, false,
+                sys::size_of::<lump::Vertex>() as i32,
+                sys::size_of::<Vec3f>() as u32));
+
+    /* From inside a fog volume, everything is seen through it. */
+    let camera_fog = map.find_fog(camera.position);
+    for batches.iter().advance |batch|
+    {
+      self.surface_textures[map.materials[batch.texture]].bind(0);
+
+      let fog = if batch.fog >= 0 { batch.fog } else { camera_fog };
+      if fog >= 0
+      {
+        let fog = map.fogs[fog].get_ref();
+        shader.update_uniform_vec3f(self.fog_color_loc, fog.color);
+        shader.update_uniform_f32(self.fog_distance_loc, fog.distance);
+        shader.update_uniform_i32(self.use_fog_loc, 1);
+      }
+      else
+      { shader.update_uniform_i32(self.use_fog_loc, 0); }
+
+      /* Faces without a lightmap are vertex lit. */
+      if batch.lightmap >= 0 && (batch.lightmap as uint) < self.lightmaps.len()
+      {
+        self.lightmaps[batch.lightmap].bind(1);
+        shader.update_uniform_i32(self.use_lightmap_loc, 1);
+      }
+      else
+      { shader.update_uniform_i32(self.use_lightmap_loc, 0); }
+
+      check!(gl::draw_arrays(gl::TRIANGLES, batch.start as i32, batch.count as i32));
+    }
+
+    check!(gl::bind_texture(gl::TEXTURE_2D, 0));
+    check!(gl::active_texture(gl::TEXTURE0));
+    check!(gl::bind_texture(gl::TEXTURE_2D, 0));
+    check!(gl::disable_vertex_attrib_array(0));
+    check!(gl::disable_vertex_attrib_array(1));
+    check!(gl::disable_vertex_attrib_array(2));
+    check!(gl::disable_vertex_attrib_array(3));
+    check!(gl::bind_vertex_array(0));
+    check!(gl::bind_buffer(gl::ARRAY_BUFFER, 0));
+  }
+
+  /* Expects a shader with position and color attributes (color.vert). */
+  pub fn draw_billboards(&self, map: &Map, camera: &Camera)
+  {
+    if map.billboards.len() == 0
+    { return; }
+
+    let right = camera.view.get_right();
+    let up = camera.view.get_up();
+    let mut data: ~[Vertex_PC] = vec::with_capacity(map.billboards.len() * 6);
+    for map.billboards.iter().advance |board|
+    { board.build(right, up, &mut data); }
+
+    check!(gl::bind_vertex_array(self.billboard_vao));
+    check!(gl::bind_buffer(gl::ARRAY_BUFFER, self.billboard_vbo));
+    check!(gl::buffer_data(gl::ARRAY_BUFFER, data, gl::STREAM_DRAW));
+
+    check!(gl::enable_vertex_attrib_array(0));
+    check!(gl::enable_vertex_attrib_array(1));
+    check!(gl::vertex_attrib_pointer_f32(0, 3, false, sys::size_of::<Vertex_PC>() as i32, 0));
+    check!(gl::vertex_attrib_pointer_f32(1, 3, false, sys::size_of::<Vertex_PC>() as i32,
+                                          sys::size_of::<Vec3f>() as u32));
+
+    /* Flares add light; they shouldn't occlude each other. */
+    check!(gl::enable(gl::BLEND));
+    check!(gl::blend_func(gl::ONE, gl::ONE));
+    check!(gl::depth_mask(false));
+
+    check!(gl::draw_arrays(gl::TRIANGLES, 0, data.len() as i32));
+
+    check!(gl::depth_mask(true));
+    check!(gl::blend_func(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA));
+    check!(gl::disable(gl::BLEND));
+
+    check!(gl::disable_vertex_attrib_array(0));
+    check!(gl::disable_vertex_attrib_array(1));
+    check!(gl::bind_vertex_array(0));
+    check!(gl::bind_buffer(gl::ARRAY_BUFFER, 0));
+  }
+}
+
+/* One texture per surface image the map resolved; those that
+   won't decode get the checkerboard, as do missing ones. */
+priv fn load_surface_textures(map: &Map) -> ~[Texture]
+{
+  let mut textures = ~[Texture::new_checkerboard(gl::TEXTURE_2D)];
+  for map.surface_images.iter().skip(1).advance |image|
+  {
+    match Texture::load(gl::TEXTURE_2D, *image)
+    {
+      Some(surface) => { surface.set_repeat(true); textures.push(surface); }
+      None =>
+      {
+        warn!("BSP: Unable to decode '%s'", *image);
+        textures.push(Texture::new_checkerboard(gl::TEXTURE_2D));
+      }
+    }
+  }
+  textures
+}
@@ -0,0 +1,255 @@
+/*
+    Copyright 2013 Jesse 'Jeaye' Wilkerson
+    See licensing in LICENSE file, or at:
+        http://www.opensource.org/licenses/BSD-3-Clause
+
+    File: obj/bsp/trace.rs
+    Author: Jesse 'Jeaye' Wilkerson
+    Description:
+      Ray and box sweeps against the brushes
+      of a BSP map, in the spirit of Q3's
+      collision model.
+*/
+
+use std::{ i32, f32, vec, cmp };
+use math::Vec3f;
+use super::{ Map, lump };
+
+/* Distance to stay away from brush planes; 0.125 Q3 units. */
+static Surface_Epsilon: f32 = 0.125 / 32.0;
+
+pub struct Trace
+{
+  /* 1.0 when nothing was hit. */
+  fraction: f32,
+  end_position: Vec3f,
+  /* Normal of the plane that was hit. */
+  normal: Vec3f,
+  surface_flags: i32,
+  content_flags: i32,
+  /* The start point was inside of a brush. */
+  start_solid: bool,
+  /* The whole sweep was inside of a brush. */
+  all_solid: bool,
+}
+
+impl Trace
+{
+  #[inline(always)]
+  pub fn new() -> Trace
+  {
+    Trace
+    {
+      fraction: 1.0,
+      end_position: Vec3f::zero(),
+      normal: Vec3f::zero(),
+      surface_flags: 0,
+      content_flags: 0,
+      start_solid: false,
+      all_solid: false,
+    }
+  }
+}
+
+struct State
+{
+  start: Vec3f,
+  end: Vec3f,
+  /* Half size of the (centered) box; zero for rays. */
+  extents: Vec3f,
+  mask: i32,
+  /* Brushes already tested; they can live in several leaves. */
+  checked: ~[bool],
+  result: Trace,
+}
+
+/* Sweeps the box [mins, maxs] from start to end, stopping at
+   the first brush whose contents match the mask. */
+pub fn trace(map: &Map, start: Vec3f, end: Vec3f, mins: Vec3f, maxs: Vec3f, mask: i32) -> Trace
+{
+  /* Work with a box centered on the points. */
+  let offset = (mins + maxs) * 0.5;
+  let mut state = State
+  {
+    start: start + offset,
+    end: end + offset,
+    extents: (maxs - mins) * 0.5,
+    mask: mask,
+    checked: vec::from_elem(map.brushes.len(), false),
+    result: Trace::new(),
+  };
+
+  if map.tree.nodes.len() > 0
+  {
+    let (s, e) = (state.start, state.end);
+    trace_node(map, &mut state, 0, 0.0, 1.0, s, e);
+  }
+
+  let mut result = state.result;
+  if result.fraction >= 1.0
+  { result.end_position = end; }
+  else
+  { result.end_position = start + ((end - start) * result.fraction); }
+  result
+}
+
+priv fn trace_node(map: &Map, state: &mut State, index: i32, p1f: f32, p2f: f32, p1: Vec3f, p2: Vec3f)
+{
+  /* Already hit something nearer. */
+  if state.result.fraction <= p1f
+  { return; }
+
+  if index < 0
+  {
+    trace_leaf(map, state, -(index + 1));
+    return;
+  }
+
+  let node = &map.tree.nodes[index];
+  let plane = &map.tree.planes[node.plane];
+
+  let t1 = plane.normal.dot(&p1) - plane.distance;
+  let t2 = plane.normal.dot(&p2) - plane.distance;
+  let offset = f32::abs(plane.normal.x * state.extents.x) +
+               f32::abs(plane.normal.y * state.extents.y) +
+               f32::abs(plane.normal.z * state.extents.z);
+
+  /* Entirely on one side. */
+  if t1 >= offset && t2 >= offset
+  { return trace_node(map, state, node.children[0], p1f, p2f, p1, p2); }
+  if t1 < -offset && t2 < -offset
+  { return trace_node(map, state, node.children[1], p1f, p2f, p1, p2); }
+
+  /* Split the sweep; the near side goes first. */
+  let mut side = 0;
+  let mut frac = 1.0f32;
+  let mut frac2 = 0.0f32;
+  if t1 < t2
+  {
+    let inv = 1.0 / (t1 - t2);
+    side = 1;
+    frac2 = (t1 + offset + Surface_Epsilon) * inv;
+    frac = (t1 - offset + Surface_Epsilon) * inv;
+  }
+  else if t1 > t2
+  {
+    let inv = 1.0 / (t1 - t2);
+    frac2 = (t1 - offset - Surface_Epsilon) * inv;
+    frac = (t1 + offset + Surface_Epsilon) * inv;
+  }
+  frac = frac.clamp(&0.0, &1.0);
+  frac2 = frac2.clamp(&0.0, &1.0);
+
+  let mid_f = p1f + ((p2f - p1f) * frac);
+  let mid = p1 + ((p2 - p1) * frac);
+  trace_node(map, state, node.children[side], p1f, mid_f, p1, mid);
+
+  let mid_f = p1f + ((p2f - p1f) * frac2);
+  let mid = p1 + ((p2 - p1) * frac2);
+  trace_node(map, state, node.children[side ^ 1], mid_f, p2f, mid, p2);
+}
+
+priv fn trace_leaf(map: &Map, state: &mut State, index: i32)
+{
+  let leaf = &map.tree.leaves[index];
+  for i32::range(leaf.brush, leaf.brush + leaf.num_brushes) |i|
+  {
+    let brush_index = map.tree.leaf_brushes[i].brush;
+    if state.checked[brush_index]
+    { loop; }
+    state.checked[brush_index] = true;
+
+    let brush = &map.brushes[brush_index];
+    if map.textures[brush.texture].content_flags & state.mask == 0
+    { loop; }
+
+    trace_brush(map, state, brush);
+    if state.result.all_solid
+    { return; }
+  }
+}
+
+priv fn trace_brush(map: &Map, state: &mut State, brush: &lump::Brush)
+{
+  if brush.num_sides == 0
+  { return; }
+
+  let mut enter_frac = -1.0f32;
+  let mut leave_frac = 1.0f32;
+  let mut clip_side: Option<&lump::Brush_Side> = None;
+  let mut get_out = false;
+  let mut start_out = false;
+
+  for i32::range(brush.side, brush.side + brush.num_sides) |i|
+  {
+    let side = &map.brush_sides[i];
+    let plane = &map.tree.planes[side.plane];
+
+    /* Push the plane out by the box. */
+    let dist = plane.distance + f32::abs(plane.normal.x * state.extents.x) +
+                                f32::abs(plane.normal.y * state.extents.y) +
+                                f32::abs(plane.normal.z * state.extents.z);
+    let d1 = plane.normal.dot(&state.start) - dist;
+    let d2 = plane.normal.dot(&state.end) - dist;
+
+    if d2 > 0.0
+    { get_out = true; }
+    if d1 > 0.0
+    { start_out = true; }
+
+    /* Completely in front of this side; can't hit the brush. */
+    if d1 > 0.0 && (d2 >= Surface_Epsilon || d2 >= d1)
+    { return; }
+
+    /* Completely behind this side. */
+    if d1 <= 0.0 && d2 <= 0.0
+    { loop; }
+
+    if d1 > d2
+    {
+      /* Entering the brush. */
+      let f = cmp::max(0.0, (d1 - Surface_Epsilon) / (d1 - d2));
+      if f > enter_frac
+      {
+        enter_frac = f;
+        clip_side = Some(side);
+      }
+    }
+    else
+    {
+      /* Leaving the brush. */
+      let f = cmp::min(1.0, (d1 + Surface_Epsilon) / (d1 - d2));
+      if f < leave_frac
+      { leave_frac = f; }
+    }
+  }
+
+  let contents = map.textures[brush.texture].content_flags;
+  if !start_out
+  {
+    state.result.start_solid = true;
+    if !get_out
+    {
+      state.result.all_solid = true;
+      state.result.fraction = 0.0;
+      state.result.content_flags = contents;
+    }
+    return;
+  }
+
+  if enter_frac < leave_frac && enter_frac > -1.0 && enter_frac < state.result.fraction
+  {
+    match clip_side
+    {
+      Some(side) =>
+      {
+        state.result.fraction = cmp::max(0.0, enter_frac);
+        state.result.normal = map.tree.planes[side.plane].normal;
+        state.result.surface_flags = map.textures[side.texture].surface_flags;
+        state.result.content_flags = contents;
+      }
+      None => { }
+    }
+  }
+}
+
@@ -0,0 +1,156 @@
+/*
+    Copyright 2013 Jesse 'Jeaye' Wilkerson
+    See licensing in LICENSE file, or at:
+        http://www.opensource.org/licenses/BSD-3-Clause
+
+    File: obj/bsp/tree.rs
+    Author: Jesse 'Jeaye' Wilkerson
+    Description:
+      The BSP tree of planes, nodes, and leaves
+      along with point and box queries against it.
+*/
+
+use std::uint;
+use math::Vec3f;
+use super::lump;
+
+pub struct Tree
+{
+  /* Already converted into our coordinate system. */
+  planes: ~[lump::Plane],
+  nodes: ~[lump::Node],
+  leaves: ~[lump::Leaf],
+  leaf_faces: ~[lump::Leaf_Face],
+  leaf_brushes: ~[lump::Leaf_Brush],
+}
+
+impl Tree
+{
+  #[inline(always)]
+  pub fn new() -> Tree
+  {
+    Tree
+    {
+      planes: ~[],
+      nodes: ~[],
+      leaves: ~[],
+      leaf_faces: ~[],
+      leaf_brushes: ~[],
+    }
+  }
+
+  /* Index of the leaf containing the point. */
+  pub fn find_leaf(&self, point: Vec3f) -> i32
+  {
+    if self.nodes.len() == 0
+    { return 0; }
+
+    let mut index = 0;
+    while index >= 0
+    {
+      let node = &self.nodes[index];
+      let plane = &self.planes[node.plane];
+
+      if plane.normal.dot(&point) - plane.distance >= 0.0
+      { index = node.children[0]; }
+      else
+      { index = node.children[1]; }
+    }
+
+    /* Negative children reference leaves. */
+    -(index + 1)
+  }
+
+  /* Visdata cluster of the point; negative when outside the map. */
+  pub fn find_cluster(&self, point: Vec3f) -> i32
+  {
+    if self.leaves.len() == 0
+    { return -1; }
+    self.leaves[self.find_leaf(point)].cluster
+  }
+
+  /* Areaportal area of the point. */
+  pub fn find_area(&self, point: Vec3f) -> i32
+  {
+    if self.leaves.len() == 0
+    { return -1; }
+    self.leaves[self.find_leaf(point)].area
+  }
+
+  /* Every leaf touched by the box. */
+  pub fn leaves_in_box(&self, min: Vec3f, max: Vec3f) -> ~[i32]
+  {
+    let mut leaves = ~[];
+    if self.nodes.len() > 0
+    { self.box_leaves_r(0, &min, &max, &mut leaves); }
+    leaves
+  }
+
+  priv fn box_leaves_r(&self, index: i32, min: &Vec3f, max: &Vec3f, leaves: &mut ~[i32])
+  {
+    if index < 0
+    {
+      leaves.push(-(index + 1));
+      return;
+    }
+
+    let node = &self.nodes[index];
+    let plane = &self.planes[node.plane];
+
+    /* Distances of the box corners nearest and farthest along the normal. */
+    let mut near = -plane.distance;
+    let mut far = -plane.distance;
+    for uint::range(0, 3) |i|
+    {
+      if plane.normal[i] >= 0.0
+      {
+        near += plane.normal[i] * min[i];
+        far += plane.normal[i] * max[i];
+      }
+      else
+      {
+        near += plane.normal[i] * max[i];
+        far += plane.normal[i] * min[i];
+      }
+    }
+
+    if far >= 0.0
+    { self.box_leaves_r(node.children[0], min, max, leaves); }
+    if near < 0.0
+    { self.box_leaves_r(node.children[1], min, max, leaves); }
+  }
+
+  /* Every leaf, ordered nearest to farthest from the view point. */
+  pub fn leaves_front_to_back(&self, view: Vec3f) -> ~[i32]
+  {
+    let mut leaves = ~[];
+    if self.nodes.len() > 0
+    { self.front_to_back_r(0, &view, &mut leaves); }
+    leaves
+  }
+
+  priv fn front_to_back_r(&self, index: i32, view: &Vec3f, leaves: &mut ~[i32])
+  {
+    if index < 0
+    {
+      leaves.push(-(index + 1));
+      return;
+    }
+
+    let node = &self.nodes[index];
+    let plane = &self.planes[node.plane];
+
+    /* The side the view is on comes first. */
+    if plane.normal.dot(view) - plane.distance >= 0.0
+    {
+      self.front_to_back_r(node.children[0], view, leaves);
+      self.front_to_back_r(node.children[1], view, leaves);
+    }
+    else
+    {
+      self.front_to_back_r(node.children[1], view, leaves);
+      self.front_to_back_r(node.children[0], view, leaves);
+    }
+  }
+}
+
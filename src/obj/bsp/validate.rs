@@ -0,0 +1,155 @@
+/*
+    Copyright 2013 Jesse 'Jeaye' Wilkerson
+    See licensing in LICENSE file, or at:
+        http://www.opensource.org/licenses/BSD-3-Clause
+
+    File: obj/bsp/validate.rs
+    Author: Jesse 'Jeaye' Wilkerson
+    Description:
+      Checks that every index stored in a freshly
+      decoded BSP references something that exists,
+      so nothing further on has to.
+*/
+
+use std::uint;
+use super::{ Map, lump };
+use super::super::error;
+use super::super::error::Load_Error;
+
+pub fn validate(map: &Map, num_lightmaps: uint) -> Result<(), Load_Error>
+{
+  let faces = lump::lump_name(lump::Face_Type);
+  for uint::range(0, map.faces.len()) |i|
+  {
+    let face = &map.faces[i];
+    try_load!(check_index(&faces, i, "texture", face.texture, map.textures.len()));
+    if face.effect >= 0
+    { try_load!(check_index(&faces, i, "effect", face.effect, map.effects.len())); }
+    if face.lightmap >= 0
+    { try_load!(check_index(&faces, i, "lightmap", face.lightmap, num_lightmaps)); }
+    try_load!(check_range(&faces, i, "vertex", face.start_vertex, face.num_vertices, map.verts.len()));
+    try_load!(check_range(&faces, i, "mesh vert", face.start_mesh_vertex, face.num_mesh_vertices,
+                        map.mesh_verts.len()));
+
+    /* Patches are grids of overlapping 3x3 control points. */
+    if face.kind == 2
+    {
+      let (width, height) = (face.patch_size.x, face.patch_size.y);
+      if width < 3 || width % 2 == 0
+      { return Err(error::Bad_Index(copy faces, i, ~"patch width", width)); }
+      if height < 3 || height % 2 == 0
+      { return Err(error::Bad_Index(copy faces, i, ~"patch height", height)); }
+      if (width as u64) * (height as u64) > (face.num_vertices as u64)
+      { return Err(error::Bad_Index(copy faces, i, fmt!("vertex count for a %dx%d patch", width as int, height as int), face.num_vertices)); }
+    }
+
+    /* The other RBSP light styles. */
+    if map.raven_faces.len() > 0
+    {
+      for map.raven_faces[i].lightmaps.iter().advance |&lightmap|
+      {
+        if lightmap >= 0
+        { try_load!(check_index(&faces, i, "lightmap", lightmap, num_lightmaps)); }
+      }
+    }
+
+    /* Mesh verts are relative to the face's first vertex. */
+    for uint::range(0, face.num_mesh_vertices as uint) |m|
+    {
+      let offset = map.mesh_verts[(face.start_mesh_vertex as uint) + m].offset;
+      try_load!(check_index(&faces, i, "mesh vert offset", offset, face.num_vertices as uint));
+    }
+  }
+
+  let nodes = lump::lump_name(lump::Node_Type);
+  for uint::range(0, map.tree.nodes.len()) |i|
+  {
+    let node = &map.tree.nodes[i];
+    try_load!(check_index(&nodes, i, "plane", node.plane, map.tree.planes.len()));
+    for node.children.iter().advance |&child|
+    {
+      /* Negative children are leaves. Nodes are stored parents first, so
+         a child that isn't past its parent would make the tree a cycle. */
+      if child >= 0
+      {
+        try_load!(check_index(&nodes, i, "child node", child, map.tree.nodes.len()));
+        if (child as uint) <= i
+        { return Err(error::Bad_Index(copy nodes, i, ~"child node", child)); }
+      }
+      else
+      { try_load!(check_index(&nodes, i, "child leaf", -(child + 1), map.tree.leaves.len())); }
+    }
+  }
+
+  let leaves = lump::lump_name(lump::Leaf_Type);
+  for uint::range(0, map.tree.leaves.len()) |i|
+  {
+    let leaf = &map.tree.leaves[i];
+    try_load!(check_range(&leaves, i, "leaf face", leaf.face, leaf.num_faces, map.tree.leaf_faces.len()));
+    try_load!(check_range(&leaves, i, "leaf brush", leaf.brush, leaf.num_brushes, map.tree.leaf_brushes.len()));
+  }
+
+  let leaf_faces = lump::lump_name(lump::Leaf_Face_Type);
+  for uint::range(0, map.tree.leaf_faces.len()) |i|
+  { try_load!(check_index(&leaf_faces, i, "face", map.tree.leaf_faces[i].face, map.faces.len())); }
+
+  let leaf_brushes = lump::lump_name(lump::Leaf_Brush_Type);
+  for uint::range(0, map.tree.leaf_brushes.len()) |i|
+  { try_load!(check_index(&leaf_brushes, i, "brush", map.tree.leaf_brushes[i].brush, map.brushes.len())); }
+
+  let brushes = lump::lump_name(lump::Brush_Type);
+  for uint::range(0, map.brushes.len()) |i|
+  {
+    let brush = &map.brushes[i];
+    try_load!(check_index(&brushes, i, "texture", brush.texture, map.textures.len()));
+    try_load!(check_range(&brushes, i, "side", brush.side, brush.num_sides, map.brush_sides.len()));
+  }
+
+  let brush_sides = lump::lump_name(lump::Brush_Side_Type);
+  for uint::range(0, map.brush_sides.len()) |i|
+  {
+    let side = &map.brush_sides[i];
+    try_load!(check_index(&brush_sides, i, "plane", side.plane, map.tree.planes.len()));
+    try_load!(check_index(&brush_sides, i, "texture", side.texture, map.textures.len()));
+  }
+
+  let effects = lump::lump_name(lump::Effect_Type);
+  for uint::range(0, map.effects.len()) |i|
+  {
+    /* Global fog has no brush. */
+    let effect = &map.effects[i];
+    if effect.brush >= 0
+    { try_load!(check_index(&effects, i, "brush", effect.brush, map.brushes.len())); }
+  }
+
+  let models = lump::lump_name(lump::Model_Type);
+  for uint::range(0, map.models.len()) |i|
+  {
+    let model = &map.models[i];
+    try_load!(check_range(&models, i, "face", model.face, model.num_faces, map.faces.len()));
+    try_load!(check_range(&models, i, "brush", model.brush, model.num_brushes, map.brushes.len()));
+  }
+
+  Ok(())
+}
+
+#[inline(always)]
+priv fn check_index(lump: &~str, element: uint, field: &str, index: i32, len: uint) -> Result<(), Load_Error>
+{
+  if index < 0 || (index as uint) >= len
+  { Err(error::Bad_Index(copy *lump, element, field.to_owned(), index)) }
+  else
+  { Ok(()) }
+}
+
+/* [start, start + count) has to lie within [0, len). */
+#[inline(always)]
+priv fn check_range(lump: &~str, element: uint, field: &str, start: i32, count: i32, len: uint) -> Result<(), Load_Error>
+{
+  if start < 0 || (start as uint) > len
+  { return Err(error::Bad_Index(copy *lump, element, fmt!("first %s", field), start)); }
+  if count < 0 || (count as uint) > len - (start as uint)
+  { return Err(error::Bad_Index(copy *lump, element, fmt!("%s count", field), count)); }
+  Ok(())
+}
+
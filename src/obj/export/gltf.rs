@@ -0,0 +1,256 @@
+/*
+    Copyright 2013 Jesse 'Jeaye' Wilkerson
+    See licensing in LICENSE file, or at:
+        http://www.opensource.org/licenses/BSD-3-Clause
+
+    File: obj/export/gltf.rs
+    Author: Jesse 'Jeaye' Wilkerson
+    Description:
+      Writes meshes as glTF 2.0; the JSON
+      document plus a .bin of the vertex
+      and index data.
+*/
+
+use std::{ io, cast, str };
+use std::iterator::IteratorUtil;
+use super::{ Mesh, replace_extension, base_name };
+
+/* Accessor component types and buffer view targets. */
+static Float: uint = 5126;
+static Unsigned_Int: uint = 5125;
+static Array_Buffer: uint = 34962;
+static Element_Array_Buffer: uint = 34963;
+
+/* Writes file and, next to it, the .bin it references. */
+pub fn write_gltf(mesh: &Mesh, file: &str) -> Result<(), ~str>
+{
+  let bin_file = replace_extension(file, "bin");
+  let mut bin: ~[u8] = ~[];
+  let mut views: ~[~str] = ~[];
+  let mut accessors: ~[~str] = ~[];
+  let count = mesh.positions.len();
+
+  /* Positions, which have to state their bounds. */
+  let (min, max) = mesh.bounds();
+  let start = bin.len();
+  for mesh.positions.iter().advance |p|
+  { push_f32(&mut bin, p.x); push_f32(&mut bin, p.y); push_f32(&mut bin, p.z); }
+  views.push(view(start, bin.len() - start, Array_Buffer));
+  accessors.push(fmt!("{ \"bufferView\": %u, \"componentType\": %u, \"count\": %u, \"type\": \"VEC3\", \
+                        \"min\": [%f, %f, %f], \"max\": [%f, %f, %f] }",
+                      views.len() - 1, Float, count,
+                      min.x as float, min.y as float, min.z as float,
+                      max.x as float, max.y as float, max.z as float));
+  let position_accessor = accessors.len() - 1;
+
+  let start = bin.len();
+  for mesh.colors.iter().advance |c|
+  { push_f32(&mut bin, c.x); push_f32(&mut bin, c.y); push_f32(&mut bin, c.z); }
+  views.push(view(start, bin.len() - start, Array_Buffer));
+  accessors.push(accessor(views.len() - 1, Float, count, "VEC3"));
+  let color_accessor = accessors.len() - 1;
+
+  let mut attributes = fmt!("\"POSITION\": %u, \"COLOR_0\": %u", position_accessor, color_accessor);
+  if mesh.has_coords()
+  {
+    let start = bin.len();
+    for mesh.coords.iter().advance |t|
+    { push_f32(&mut bin, t.x); push_f32(&mut bin, t.y); }
+    views.push(view(start, bin.len() - start, Array_Buffer));
+    accessors.push(accessor(views.len() - 1, Float, count, "VEC2"));
+    attributes = attributes + fmt!(", \"TEXCOORD_0\": %u", accessors.len() - 1);
+  }
+
+  /* One primitive, and one material, per group. */
+  let mut materials: ~[~str] = ~[];
+  let mut primitives: ~[~str] = ~[];
+  for mesh.groups.iter().advance |group|
+  {
+    if group.indices.len() == 0
+    { loop; }
+
+    let start = bin.len();
+    for group.indices.iter().advance |&i|
+    { push_u32(&mut bin, i); }
+    views.push(view(start, bin.len() - start, Element_Array_Buffer));
+    accessors.push(accessor(views.len() - 1, Unsigned_Int, group.indices.len(), "SCALAR"));
+
+    let material = match materials.position_elem(&group.material)
+    {
+      Some(index) => { index }
+      None => { materials.push(copy group.material); materials.len() - 1 }
+    };
+    primitives.push(fmt!("{ \"attributes\": { %s }, \"indices\": %u, \"material\": %u }",
+                         attributes, accessors.len() - 1, material));
+  }
+
+  /* glTF doesn't allow empty arrays, or meshes without primitives. */
+  if primitives.len() == 0
+  { return Err(~"Nothing to export"); }
+
+  let materials = materials.map(|name| fmt!("{ \"name\": \"%s\" }", escape(*name)));
+  let json = fmt!("{\n\
+                    \"asset\": { \"version\": \"2.0\", \"generator\": \"q3\" },\n\
+                    \"scene\": 0,\n\
+                    \"scenes\": [ { \"nodes\": [0] } ],\n\
+                    \"nodes\": [ { \"mesh\": 0 } ],\n\
+                    \"meshes\": [ { \"primitives\": [\n    %s\n  ] } ],\n\
+                    \"materials\": [\n    %s\n  ],\n\
+                    \"accessors\": [\n    %s\n  ],\n\
+                    \"bufferViews\": [\n    %s\n  ],\n\
+                    \"buffers\": [ { \"uri\": \"%s\", \"byteLength\": %u } ]\n\
+                  }\n",
+                  str::connect(primitives, ",\n    "),
+                  str::connect(materials, ",\n    "),
+                  str::connect(accessors, ",\n    "),
+                  str::connect(views, ",\n    "),
+                  escape(base_name(bin_file)), bin.len());
+
+  match io::file_writer(&Path(bin_file), [io::Create, io::Truncate])
+  {
+    Ok(out) => { out.write(bin); }
+    Err(err) => { return Err(err); }
+  }
+  match io::file_writer(&Path(file), [io::Create, io::Truncate])
+  {
+    Ok(out) => { out.write_str(json); }
+    Err(err) => { return Err(err); }
+  }
+
+  Ok(())
+}
+
+#[inline(always)]
+priv fn view(offset: uint, length: uint, target: uint) -> ~str
+{ fmt!("{ \"buffer\": 0, \"byteOffset\": %u, \"byteLength\": %u, \"target\": %u }", offset, length, target) }
+
+#[inline(always)]
+priv fn accessor(view: uint, component: uint, count: uint, kind: &str) -> ~str
+{
+  fmt!("{ \"bufferView\": %u, \"componentType\": %u, \"count\": %u, \"type\": \"%s\" }",
+       view, component, count, kind)
+}
+
+/* glTF buffers are little endian. */
+#[inline(always)]
+priv fn push_u32(bin: &mut ~[u8], x: u32)
+{
+  bin.push(x as u8);
+  bin.push((x >> 8) as u8);
+  bin.push((x >> 16) as u8);
+  bin.push((x >> 24) as u8);
+}
+
+#[inline(always)]
+priv fn push_f32(bin: &mut ~[u8], x: f32)
+{ push_u32(bin, unsafe { cast::transmute::<f32, u32>(x) }); }
+
+priv fn escape(s: &str) -> ~str
+{
+  let mut out = ~"";
+  for s.iter().advance |c|
+  {
+    if c == '"' || c == '\\'
+    { out.push_char('\\'); }
+    out.push_char(c);
+  }
+  out
+}
+
+#[cfg(test)]
+mod test
+{
+  use std::{ io, os, uint, float, str };
+  use std::iterator::IteratorUtil;
+  use extra::tempfile;
+  use super::write_gltf;
+  use super::super::mesh::test::two_groups;
+
+  /* The numbers after each occurrence of the key, in order. */
+  fn numbers_after(json: &str, key: &str) -> ~[~str]
+  {
+    let mut found = ~[];
+    let mut rest = json;
+    loop
+    {
+      match rest.find_str(key)
+      {
+        Some(at) =>
+        {
+          rest = rest.slice_from(at + key.len());
+          let mut end = 0;
+          for rest.iter().advance |c|
+          {
+            if !((c >= '0' && c <= '9') || c == '.' || c == '-')
+            { break; }
+            end += 1;
+          }
+          found.push(rest.slice(0, end).to_owned());
+        }
+        None => { return found; }
+      }
+    }
+  }
+
+  fn to_uints(numbers: &[~str]) -> ~[uint]
+  { numbers.map(|n| uint::from_str(*n).unwrap()) }
+
+  #[test]
+  fn buffers()
+  {
+    let dir = tempfile::mkdtemp(&os::tmpdir(), "q3-test-export-gltf").expect("Unable to create a temporary directory");
+    let mesh = two_groups();
+    assert!(write_gltf(&mesh, dir.push("mesh.gltf").to_str()).is_ok());
+
+    let json = match io::read_whole_file(&dir.push("mesh.gltf"))
+    {
+      Ok(data) => { str::from_bytes(data) }
+      Err(err) => { fail!(err); }
+    };
+    let bin = match io::read_whole_file(&dir.push("mesh.bin"))
+    {
+      Ok(data) => { data }
+      Err(err) => { fail!(err); }
+    };
+
+    /* Views are 4 byte aligned and packed; the last length is the buffer's. */
+    let offsets = to_uints(numbers_after(json, "\"byteOffset\": "));
+    let lengths = to_uints(numbers_after(json, "\"byteLength\": "));
+    assert_eq!(offsets.len() + 1, lengths.len());
+    let mut end = 0;
+    for uint::range(0, offsets.len()) |i|
+    {
+      assert_eq!(offsets[i] % 4, 0);
+      assert_eq!(offsets[i], end);
+      end += lengths[i];
+    }
+    assert_eq!(end, bin.len());
+    assert_eq!(lengths[lengths.len() - 1], bin.len());
+
+    /* Positions, colors, coordinates, and each group's indices. */
+    assert_eq!(bin.len(), (4 * 12) + (4 * 12) + (4 * 8) + (2 * 3 * 4));
+    assert!(json.contains("\"uri\": \"mesh.bin\""));
+
+    /* POSITION is the first accessor, and the only one with bounds. */
+    assert_eq!(numbers_after(json, "\"POSITION\": "), ~[~"0", ~"0"]);
+    let min = numbers_after(json, "\"min\": [");
+    let max = numbers_after(json, "\"max\": [");
+    assert_eq!(min.len(), 1);
+    assert_eq!(max.len(), 1);
+    let first = json.slice_from(json.find_str("\"accessors\"").unwrap());
+    let first = first.slice(0, first.find('}').unwrap());
+    let bounds = |key: &str| -> ~[float]
+    {
+      let at = first.find_str(key).unwrap() + key.len();
+      let list = first.slice(at, at + first.slice_from(at).find(']').unwrap());
+      list.split_iter(',').map(|n| float::from_str(n.trim()).unwrap()).collect()
+    };
+    assert_eq!(bounds("\"min\": ["), ~[-1.0, 0.0, -2.0]);
+    assert_eq!(bounds("\"max\": ["), ~[1.0, 3.0, 2.0]);
+
+    /* Each group refers to its own material. */
+    assert_eq!(numbers_after(json, "\"material\": "), ~[~"0", ~"1"]);
+    assert!(json.contains("{ \"name\": \"textures/base/wall\" }"));
+    assert!(json.contains("{ \"name\": \"textures/base/trim\" }"));
+  }
+}
@@ -0,0 +1,120 @@
+/*
+    Copyright 2013 Jesse 'Jeaye' Wilkerson
+    See licensing in LICENSE file, or at:
+        http://www.opensource.org/licenses/BSD-3-Clause
+
+    File: obj/export/mesh.rs
+    Author: Jesse 'Jeaye' Wilkerson
+    Description:
+      A format neutral triangle mesh, which
+      the BSP and voxel maps build for the
+      exporters.
+*/
+
+use std::cmp;
+use std::iterator::IteratorUtil;
+use math::{ Vec2f, Vec3f };
+
+/* Triangles sharing a material. */
+pub struct Group
+{
+  material: ~str,
+  /* Path of the image the material shows; empty for none. */
+  image: ~str,
+  /* Every three make up a triangle, counter-clockwise from the front. */
+  indices: ~[u32],
+}
+
+pub struct Mesh
+{
+  positions: ~[Vec3f],
+  /* RGB in [0, 1], parallel to the positions. */
+  colors: ~[Vec3f],
+  /* Either empty or parallel to the positions. */
+  coords: ~[Vec2f],
+  groups: ~[Group],
+}
+
+impl Mesh
+{
+  #[inline(always)]
+  pub fn new() -> Mesh
+  { Mesh { positions: ~[], colors: ~[], coords: ~[], groups: ~[] } }
+
+  /* Adds a vertex, returning its index. */
+  pub fn add_vertex(&mut self, position: Vec3f, color: Vec3f, coord: Option<Vec2f>) -> u32
+  {
+    self.positions.push(position);
+    self.colors.push(color);
+    match coord
+    {
+      Some(coord) => { self.coords.push(coord); }
+      None => { }
+    }
+    (self.positions.len() - 1) as u32
+  }
+
+  /* Starts a new group of triangles, returning its index. */
+  pub fn add_group(&mut self, material: &str, image: &str) -> uint
+  {
+    self.groups.push(Group { material: material.to_owned(), image: image.to_owned(), indices: ~[] });
+    self.groups.len() - 1
+  }
+
+  #[inline(always)]
+  pub fn has_coords(&self) -> bool
+  { self.coords.len() > 0 && self.coords.len() == self.positions.len() }
+
+  /* Component-wise (min, max) of the positions. */
+  pub fn bounds(&self) -> (Vec3f, Vec3f)
+  {
+    if self.positions.len() == 0
+    { return (Vec3f::zero(), Vec3f::zero()); }
+
+    let mut min = self.positions[0];
+    let mut max = self.positions[0];
+    for self.positions.iter().advance |p|
+    {
+      min = Vec3f::new(cmp::min(min.x, p.x), cmp::min(min.y, p.y), cmp::min(min.z, p.z));
+      max = Vec3f::new(cmp::max(max.x, p.x), cmp::max(max.y, p.y), cmp::max(max.z, p.z));
+    }
+    (min, max)
+  }
+}
+
+#[cfg(test)]
+pub mod test
+{
+  use math::{ Vec2f, Vec3f };
+  use super::Mesh;
+
+  /* A quad split across two materials; only the first has an image. */
+  pub fn two_groups() -> Mesh
+  {
+    let mut mesh = Mesh::new();
+    let white = Vec3f::new(1.0, 1.0, 1.0);
+    mesh.add_vertex(Vec3f::new(-1.0, 0.0, -2.0), white, Some(Vec2f::new(0.0, 0.0)));
+    mesh.add_vertex(Vec3f::new(1.0, 0.0, -2.0), white, Some(Vec2f::new(1.0, 0.0)));
+    mesh.add_vertex(Vec3f::new(1.0, 3.0, 2.0), white, Some(Vec2f::new(1.0, 0.75)));
+    mesh.add_vertex(Vec3f::new(-1.0, 3.0, 2.0), white, Some(Vec2f::new(0.0, 0.25)));
+
+    let wall = mesh.add_group("textures/base/wall", "textures/base/wall.tga");
+    mesh.groups[wall].indices.push_all([0, 1, 2]);
+    let trim = mesh.add_group("textures/base/trim", "");
+    mesh.groups[trim].indices.push_all([0, 2, 3]);
+    mesh
+  }
+
+  #[test]
+  fn bounds()
+  {
+    let mesh = two_groups();
+    assert!(mesh.has_coords());
+    let (min, max) = mesh.bounds();
+    assert_eq!((min.x, min.y, min.z), (-1.0, 0.0, -2.0));
+    assert_eq!((max.x, max.y, max.z), (1.0, 3.0, 2.0));
+
+    let (min, max) = Mesh::new().bounds();
+    assert_eq!((min.x, min.y, min.z, max.x, max.y, max.z), (0.0, 0.0, 0.0, 0.0, 0.0, 0.0));
+  }
+}
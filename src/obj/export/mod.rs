@@ -0,0 +1,51 @@
+/*
+    Copyright 2013 Jesse 'Jeaye' Wilkerson
+    See licensing in LICENSE file, or at:
+        http://www.opensource.org/licenses/BSD-3-Clause
+
+    File: obj/export/mod.rs
+    Author: Jesse 'Jeaye' Wilkerson
+    Description:
+      An aggregator of mesh exporters, for
+      inspecting geometry in other tools.
+*/
+
+pub use self::mesh::{ Mesh, Group };
+pub use self::obj::write_obj;
+pub use self::gltf::write_gltf;
+
+mod mesh;
+mod obj;
+mod gltf;
+
+/* Writes the mesh in the named format ("obj" or "gltf"). */
+pub fn write(mesh: &Mesh, format: &str, file: &str) -> Result<(), ~str>
+{
+  match format
+  {
+    "obj" => { write_obj(mesh, file) }
+    "gltf" => { write_gltf(mesh, file) }
+    _ => { Err(fmt!("Unknown export format '%s'; expected obj or gltf", format)) }
+  }
+}
+
+/* "out/map.obj" with "mtl" becomes "out/map.mtl". */
+pub fn replace_extension(file: &str, ext: &str) -> ~str
+{
+  let slash = match file.rfind('/') { Some(i) => i + 1, None => 0 };
+  match file.rfind('.')
+  {
+    Some(dot) if dot > slash => { file.slice(0, dot) + "." + ext }
+    _ => { file + "." + ext }
+  }
+}
+
+/* "out/map.obj" becomes "map.obj"; exported files reference each other relatively. */
+pub fn base_name(file: &str) -> ~str
+{
+  match file.rfind('/')
+  {
+    Some(slash) => { file.slice_from(slash + 1).to_owned() }
+    None => { file.to_owned() }
+  }
+}
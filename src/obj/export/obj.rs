@@ -0,0 +1,151 @@
+/*
+    Copyright 2013 Jesse 'Jeaye' Wilkerson
+    See licensing in LICENSE file, or at:
+        http://www.opensource.org/licenses/BSD-3-Clause
+
+    File: obj/export/obj.rs
+    Author: Jesse 'Jeaye' Wilkerson
+    Description:
+      Writes meshes as Wavefront OBJ, with
+      an MTL file alongside for the materials.
+*/
+
+use std::{ io, uint };
+use std::iterator::IteratorUtil;
+use super::{ Mesh, replace_extension, base_name };
+
+/* Writes file and, next to it, the .mtl it references. */
+pub fn write_obj(mesh: &Mesh, file: &str) -> Result<(), ~str>
+{
+  let mtl_file = replace_extension(file, "mtl");
+  let out = match io::file_writer(&Path(file), [io::Create, io::Truncate])
+  {
+    Ok(out) => { out }
+    Err(err) => { return Err(err); }
+  };
+
+  out.write_line(fmt!("mtllib %s", base_name(mtl_file)));
+
+  /* Vertex colors follow the position; most tools understand them. */
+  for uint::range(0, mesh.positions.len()) |i|
+  {
+    let (p, c) = (&mesh.positions[i], &mesh.colors[i]);
+    out.write_line(fmt!("v %f %f %f %f %f %f", p.x as float, p.y as float, p.z as float,
+                                               c.x as float, c.y as float, c.z as float));
+  }
+
+  /* OBJ puts the origin of its texture coordinates at the bottom. */
+  let has_coords = mesh.has_coords();
+  if has_coords
+  {
+    for mesh.coords.iter().advance |t|
+    { out.write_line(fmt!("vt %f %f", t.x as float, 1.0 - (t.y as float))); }
+  }
+
+  for mesh.groups.iter().advance |group|
+  {
+    out.write_line(fmt!("usemtl %s", group.material));
+    for uint::range(0, group.indices.len() / 3) |i|
+    {
+      /* OBJ indices start at 1. */
+      let a = group.indices[i * 3] + 1;
+      let b = group.indices[(i * 3) + 1] + 1;
+      let c = group.indices[(i * 3) + 2] + 1;
+      if has_coords
+      { out.write_line(fmt!("f %u/%u %u/%u %u/%u", a as uint, a as uint, b as uint, b as uint, c as uint, c as uint)); }
+      else
+      { out.write_line(fmt!("f %u %u %u", a as uint, b as uint, c as uint)); }
+    }
+  }
+
+  write_mtl(mesh, mtl_file)
+}
+
+priv fn write_mtl(mesh: &Mesh, file: &str) -> Result<(), ~str>
+{
+  let out = match io::file_writer(&Path(file), [io::Create, io::Truncate])
+  {
+    Ok(out) => { out }
+    Err(err) => { return Err(err); }
+  };
+
+  let mut written: ~[~str] = ~[];
+  for mesh.groups.iter().advance |group|
+  {
+    if written.contains(&group.material)
+    { loop; }
+    written.push(copy group.material);
+
+    out.write_line(fmt!("newmtl %s", group.material));
+    out.write_line("Kd 1.0 1.0 1.0");
+    if group.image.len() > 0
+    { out.write_line(fmt!("map_Kd %s", group.image)); }
+    out.write_line("");
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod test
+{
+  use std::{ io, os, float, str };
+  use std::iterator::IteratorUtil;
+  use extra::tempfile;
+  use super::write_obj;
+  use super::super::mesh::test::two_groups;
+
+  fn read_lines(file: &Path) -> ~[~str]
+  {
+    match io::read_whole_file(file)
+    {
+      Ok(data) => { str::from_bytes(data).line_iter().map(|l| l.to_owned()).collect() }
+      Err(err) => { fail!(err); }
+    }
+  }
+
+  /* Lines starting with the keyword, without it. */
+  fn with(lines: &[~str], keyword: &str) -> ~[~str]
+  {
+    let prefix = keyword + " ";
+    let mut found = ~[];
+    for lines.iter().advance |line|
+    {
+      if line.starts_with(prefix)
+      { found.push(line.slice_from(prefix.len()).to_owned()); }
+    }
+    found
+  }
+
+  #[test]
+  fn groups()
+  {
+    let dir = tempfile::mkdtemp(&os::tmpdir(), "q3-test-export-obj").expect("Unable to create a temporary directory");
+    let mesh = two_groups();
+    assert!(write_obj(&mesh, dir.push("mesh.obj").to_str()).is_ok());
+
+    let obj = read_lines(&dir.push("mesh.obj"));
+    let mtl = read_lines(&dir.push("mesh.mtl"));
+    assert_eq!(with(obj, "mtllib"), ~[~"mesh.mtl"]);
+    assert_eq!(with(obj, "v").len(), 4);
+
+    /* Flipped in V, since OBJ starts at the bottom. */
+    let coords = with(obj, "vt");
+    assert_eq!(coords.len(), 4);
+    for coords.iter().enumerate().advance |(i, line)|
+    {
+      let uv: ~[float] = line.split_iter(' ').map(|s| float::from_str(s).unwrap()).collect();
+      assert_eq!(uv[0], mesh.coords[i].x as float);
+      assert_eq!(uv[1], 1.0 - (mesh.coords[i].y as float));
+    }
+
+    /* One based, with each vertex's texture coordinate alongside. */
+    assert_eq!(with(obj, "f"), ~[~"1/1 2/2 3/3", ~"1/1 3/3 4/4"]);
+
+    /* Each group's material is defined once, in order. */
+    let used = with(obj, "usemtl");
+    assert_eq!(used, ~[~"textures/base/wall", ~"textures/base/trim"]);
+    assert_eq!(with(mtl, "newmtl"), used);
+    assert_eq!(with(mtl, "map_Kd"), ~[~"textures/base/wall.tga"]);
+  }
+}
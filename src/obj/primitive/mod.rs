@@ -12,10 +12,12 @@
 pub use self::vertex::{ Vertex_P, Vertex_PC, Vertex_PN, Vertex_PCN };
 pub use self::triangle::{ Triangle, Triangle_Index };
 pub use self::cube::{ Cube, Cube_Index };
+#[cfg(not(headless))]
 pub use self::sphere::Sphere;
 
 mod vertex;
 mod triangle;
 mod cube;
+#[cfg(not(headless))]
 mod sphere;
 
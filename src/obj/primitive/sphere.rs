@@ -32,7 +32,16 @@ pub struct Sphere
 
 impl Sphere
 {
+  #[inline(always)]
   pub fn new(new_radius: f32, new_subdivides: i32) -> Sphere
+  {
+    let mut sphere = Sphere::load(new_radius, new_subdivides);
+    sphere.upload();
+    sphere
+  }
+
+  /* Builds the triangles without touching GL; upload() before drawing. */
+  pub fn load(new_radius: f32, new_subdivides: i32) -> Sphere
   {
     /* Magic numbers place all verts 1.0 from center. */
     let magic_x = 0.525731112119133606f32;
@@ -91,15 +100,18 @@ impl Sphere
     for uint::range(0, tris.len()) |x|
     { sphere.subdivide(tris[x], new_subdivides); }
 
-    sphere.vao = check!(gl::gen_vertex_arrays(1))[0]; /* TODO: Check these. */
-    sphere.vbo = check!(gl::gen_buffers(1))[0];
-    check!(gl::bind_vertex_array(sphere.vao));
-    check!(gl::bind_buffer(gl::ARRAY_BUFFER, sphere.vbo));
-    check!(gl::buffer_data(gl::ARRAY_BUFFER, sphere.tris, gl::STATIC_DRAW));
-
     sphere
   }
 
+  pub fn upload(&mut self)
+  {
+    self.vao = check!(gl::gen_vertex_arrays(1))[0]; /* TODO: Check these. */
+    self.vbo = check!(gl::gen_buffers(1))[0];
+    check!(gl::bind_vertex_array(self.vao));
+    check!(gl::bind_buffer(gl::ARRAY_BUFFER, self.vbo));
+    check!(gl::buffer_data(gl::ARRAY_BUFFER, self.tris, gl::STATIC_DRAW));
+  }
+
   /* Recursive subdivide for a given triangle. */
   priv fn subdivide(&mut self, tri: Triangle, depth: i32)
   {
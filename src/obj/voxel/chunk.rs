@@ -9,7 +9,13 @@
       A pageable 3D chunk of voxel data.
 */
 
-use math::{ Vec3i8 };
+use std::{ io, sys, uint, cmp };
+use std::iterator::IteratorUtil;
+use std::hashmap::HashMap;
+use math::{ Vec3f, Vec3i, Vec3u8, BB3 };
+use primitive::Vertex_PC;
+use super::Vertex;
+use super::mesher::Face_Mesh;
 
 #[path = "../../gl/mod.rs"]
 mod gl;
@@ -19,35 +25,468 @@ mod util;
 #[path = "../../gl/check.rs"]
 mod check;
 
+/* Cells along each side of a chunk. */
+pub static Chunk_Size: i32 = 32;
+
+static Page_Magic: u32 = 0x43563351; /* "Q3VC" */
+static Page_Version: u32 = 1;
+
 pub struct Chunk
 {
+  /* In chunks, not cells. */
+  position: Vec3i,
+
+  /* Grid positions, as the instance attributes expect them. */
+  voxels: ~[Vertex],
+
+  /* Cell position to its index in voxels. */
+  cells: HashMap<(i32, i32, i32), uint>,
+
+  /* BSP clusters the voxels lie in; negative is solid space. */
+  clusters: ~[i32],
+
+  /* World space; empty chunks have none. */
+  bb: Option<BB3>,
+
+  /* Instances, or the face mesh, depending on which was uploaded
+     last; rebuilt when dirty. */
   vbo: gl::GLuint,
-  dimensions: Vec3i8,
-  voxels: ~[Vec3i8],
+  dirty: bool,
+
+  /* Instances the buffer has room for, and those that were edited
+     since the last upload; only they are written, if they fit. */
+  capacity: uint,
+  changed: ~[uint],
+
+  /* Of the last face mesh uploaded. */
+  mesh_faces: uint,
+  mesh_verts: uint,
 }
 
 impl Chunk
 {
-  pub fn new(dim: &Vec3i8) -> Chunk
+  pub fn new(position: Vec3i) -> Chunk
   {
-    let chunk = Chunk
+    Chunk
     {
-      vbo: check!(gl::gen_buffers(1))[0],
-      dimensions: *dim,
+      position: position,
       voxels: ~[],
+      cells: HashMap::new::<(i32, i32, i32), uint>(),
+      clusters: ~[],
+      bb: None,
+      vbo: 0,
+      dirty: true,
+      capacity: 0,
+      changed: ~[],
+      mesh_faces: 0,
+      mesh_verts: 0,
+    }
+  }
+
+  /* Index of the voxel at the grid position, if solid. */
+  pub fn find(&self, x: i32, y: i32, z: i32) -> Option<uint>
+  {
+    match self.cells.find(&(x, y, z))
+    {
+      Some(&i) => { Some(i) }
+      None => { None }
+    }
+  }
+
+  /* Fills, or recolors, the voxel's cell. */
+  pub fn set(&mut self, vert: Vertex, cluster: i32)
+  {
+    match self.find(vert.position.x, vert.position.y, vert.position.z)
+    {
+      Some(i) =>
+      {
+        self.voxels[i].color = vert.color;
+        self.changed.push(i);
+      }
+      None =>
+      {
+        self.cells.insert((vert.position.x, vert.position.y, vert.position.z), self.voxels.len());
+        self.changed.push(self.voxels.len());
+        self.voxels.push(vert);
+        if self.voxels.len() > self.capacity
+        { self.dirty = true; }
+      }
+    }
+
+    if !self.clusters.contains(&cluster)
+    { self.clusters.push(cluster); }
+  }
+
+  /* Order isn't kept; the last voxel takes the cleared one's place. */
+  pub fn clear(&mut self, x: i32, y: i32, z: i32) -> bool
+  {
+    match self.find(x, y, z)
+    {
+      Some(i) =>
+      {
+        self.voxels.swap_remove(i);
+        self.cells.remove(&(x, y, z));
+        if i < self.voxels.len()
+        {
+          let moved = self.voxels[i].position;
+          self.cells.insert((moved.x, moved.y, moved.z), i);
+        }
+
+        /* Even when nothing moved into its place, the mesh is out of date. */
+        self.changed.push(i);
+        true
+      }
+      None => { false }
+    }
+  }
+
+  /* Each voxel is drawn from its position, times voxel_size, out half a voxel. */
+  pub fn update_bounds(&mut self, voxel_size: f32)
+  {
+    self.bb = None;
+    if self.voxels.len() == 0
+    { return; }
+
+    let first = self.voxels[0].position;
+    let (mut min, mut max) = (first, first);
+    for self.voxels.iter().advance |vert|
+    {
+      min.x = cmp::min(min.x, vert.position.x);
+      min.y = cmp::min(min.y, vert.position.y);
+      min.z = cmp::min(min.z, vert.position.z);
+
+      max.x = cmp::max(max.x, vert.position.x);
+      max.y = cmp::max(max.y, vert.position.y);
+      max.z = cmp::max(max.z, vert.position.z);
+    }
+
+    let h = voxel_size / 2.0;
+    self.bb = Some(BB3::new(Vec3f::new(min.x as f32 * voxel_size - h, min.y as f32 * voxel_size - h, min.z as f32 * voxel_size - h),
+                            Vec3f::new(max.x as f32 * voxel_size + h, max.y as f32 * voxel_size + h, max.z as f32 * voxel_size + h)));
+  }
+
+  /* Distance from the point to the chunk's bounds; zero inside. */
+  pub fn distance_to(&self, point: Vec3f) -> f32
+  {
+    match self.bb
+    {
+      Some(ref bb) => { bounds_distance(bb, point) }
+      None => { 0.0 }
+    }
+  }
+
+  /* Whether any part of the chunk is in front of the viewer. */
+  pub fn in_front(&self, position: Vec3f, forward: &Vec3f) -> bool
+  {
+    match self.bb
+    {
+      Some(bb) =>
+      {
+        /* The corner furthest along the view direction. */
+        let (min, max) = (bb.min(), bb.max());
+        let corner = Vec3f::new(if forward.x > 0.0 { max.x } else { min.x },
+                                if forward.y > 0.0 { max.y } else { min.y },
+                                if forward.z > 0.0 { max.z } else { min.z });
+        (corner - position).dot(forward) >= 0.0
+      }
+      None => { false }
+    }
+  }
+
+  /* Whether what's uploaded is out of date at all. */
+  #[inline(always)]
+  pub fn is_stale(&self) -> bool
+  { self.dirty || self.changed.len() > 0 }
+
+  /* Uploads the instances; if only some changed, and they
+     fit in the buffer, just those are written. */
+  pub fn upload(&mut self)
+  {
+    if !self.is_stale()
+    { return; }
+
+    if self.vbo == 0
+    { self.vbo = check!(gl::gen_buffers(1))[0]; }
+    check!(gl::bind_buffer(gl::ARRAY_BUFFER, self.vbo));
+    if self.dirty
+    {
+      check!(gl::buffer_data(gl::ARRAY_BUFFER, self.voxels, gl::DYNAMIC_DRAW));
+      self.capacity = self.voxels.len();
+    }
+    else
+    {
+      /* Cleared voxels at the end just fall out of the draw count. */
+      for self.changed.iter().advance |&i|
+      {
+        if i < self.voxels.len()
+        {
+          check!(gl::buffer_sub_data(gl::ARRAY_BUFFER, (i * sys::size_of::<Vertex>()) as i32,
+                                     self.voxels.slice(i, i + 1)));
+        }
+      }
+    }
+    check!(gl::bind_buffer(gl::ARRAY_BUFFER, 0));
+    self.dirty = false;
+    self.changed = ~[];
+  }
+
+  /* In place of the instances; see voxel::mesher. */
+  pub fn upload_mesh(&mut self, mesh: &Face_Mesh)
+  {
+    if self.vbo == 0
+    { self.vbo = check!(gl::gen_buffers(1))[0]; }
+    check!(gl::bind_buffer(gl::ARRAY_BUFFER, self.vbo));
+    check!(gl::buffer_data(gl::ARRAY_BUFFER, mesh.verts, gl::STATIC_DRAW));
+    check!(gl::bind_buffer(gl::ARRAY_BUFFER, 0));
+    self.mesh_faces = mesh.faces;
+    self.mesh_verts = mesh.verts.len();
+
+    /* The instances are gone from the buffer. */
+    self.capacity = 0;
+    self.dirty = false;
+    self.changed = ~[];
+  }
+
+  /* Frees the GPU side; the next upload() rebuilds it. */
+  pub fn unload(&mut self)
+  {
+    if self.vbo != 0
+    {
+      check!(gl::delete_buffers([self.vbo]));
+      self.vbo = 0;
+    }
+    self.dirty = true;
+  }
+
+  /* Expects the cube's VAO, with its vertex attribute, to be bound. */
+  pub fn draw_instances(&self)
+  {
+    if self.vbo == 0 || self.voxels.len() == 0
+    { return; }
+
+    check!(gl::bind_buffer(gl::ARRAY_BUFFER, self.vbo));
+    check!(gl::vertex_attrib_pointer_i32(1, 3, false, (sys::size_of::<Vertex>()) as i32, 0));
+    check!(gl::vertex_attrib_pointer_u8(2, 3, true, (sys::size_of::<Vertex>()) as i32, sys::size_of::<Vec3i>() as u32));
+    check!(gl::draw_arrays_instanced(gl::TRIANGLE_STRIP, 0, 24, self.voxels.len() as i32));
+  }
+
+  /* Expects attributes 0 and 1 to be enabled. */
+  pub fn draw_mesh(&self)
+  {
+    if self.vbo == 0 || self.mesh_verts == 0
+    { return; }
+
+    check!(gl::bind_buffer(gl::ARRAY_BUFFER, self.vbo));
+    check!(gl::vertex_attrib_pointer_f32(0, 3, false, sys::size_of::<Vertex_PC>() as i32, 0));
+    check!(gl::vertex_attrib_pointer_f32(1, 3, false, sys::size_of::<Vertex_PC>() as i32,
+                                          sys::size_of::<Vec3f>() as u32));
+    check!(gl::draw_arrays(gl::TRIANGLES, 0, self.mesh_verts as i32));
+  }
+
+  /* Little endian: magic, version, position, clusters, then voxels. */
+  pub fn write(&self, file: &str) -> Result<(), ~str>
+  {
+    let mut bytes = ~[];
+    push_u32(&mut bytes, Page_Magic);
+    push_u32(&mut bytes, Page_Version);
+    push_u32(&mut bytes, self.position.x as u32);
+    push_u32(&mut bytes, self.position.y as u32);
+    push_u32(&mut bytes, self.position.z as u32);
+
+    push_u32(&mut bytes, self.clusters.len() as u32);
+    for self.clusters.iter().advance |&cluster|
+    { push_u32(&mut bytes, cluster as u32); }
+
+    push_u32(&mut bytes, self.voxels.len() as u32);
+    for self.voxels.iter().advance |vert|
+    {
+      push_u32(&mut bytes, vert.position.x as u32);
+      push_u32(&mut bytes, vert.position.y as u32);
+      push_u32(&mut bytes, vert.position.z as u32);
+      bytes.push_all([vert.color.x, vert.color.y, vert.color.z, 0]);
+    }
+
+    match io::file_writer(&Path(file), [io::Create, io::Truncate])
+    {
+      Ok(out) => { out.write(bytes); Ok(()) }
+      Err(err) => { Err(err) }
+    }
+  }
+
+  /* Bounds aren't stored; update_bounds() after reading. */
+  pub fn read(file: &str) -> Result<Chunk, ~str>
+  {
+    let bytes = match io::read_whole_file(&Path(file))
+    {
+      Ok(bytes) => { bytes }
+      Err(err) => { return Err(err); }
     };
 
-    chunk
+    if bytes.len() < 24 || read_u32(bytes, 0) != Page_Magic
+    { return Err(fmt!("%s is not a voxel chunk", file)); }
+    if read_u32(bytes, 4) != Page_Version
+    { return Err(fmt!("%s is version %u; expected %u", file, read_u32(bytes, 4) as uint, Page_Version as uint)); }
+
+    let mut chunk = Chunk::new(Vec3i::new(read_u32(bytes, 8) as i32,
+                                          read_u32(bytes, 12) as i32,
+                                          read_u32(bytes, 16) as i32));
+
+    let clusters = read_u32(bytes, 20) as uint;
+    let mut at = 24;
+    if at + (clusters * 4) + 4 > bytes.len()
+    { return Err(fmt!("%s is truncated", file)); }
+    for uint::range(0, clusters) |_|
+    {
+      chunk.clusters.push(read_u32(bytes, at) as i32);
+      at += 4;
+    }
+
+    let count = read_u32(bytes, at) as uint;
+    at += 4;
+    if at + (count * 16) != bytes.len()
+    { return Err(fmt!("%s is truncated", file)); }
+    for uint::range(0, count) |_|
+    {
+      let position = Vec3i::new(read_u32(bytes, at) as i32,
+                                read_u32(bytes, at + 4) as i32,
+                                read_u32(bytes, at + 8) as i32);
+      chunk.cells.insert((position.x, position.y, position.z), chunk.voxels.len());
+      chunk.voxels.push(Vertex::new(position, Vec3u8::new(bytes[at + 12], bytes[at + 13], bytes[at + 14])));
+      at += 16;
+    }
+
+    Ok(chunk)
   }
+}
+
+/* The chunk holding the cell at the grid position. */
+#[inline(always)]
+pub fn chunk_of(x: i32, y: i32, z: i32) -> (i32, i32, i32)
+{ (div_floor(x, Chunk_Size), div_floor(y, Chunk_Size), div_floor(z, Chunk_Size)) }
 
-  //pub fn get(&self, x: i8, y: i8, z: i8) -> Behavior
-  //{
-  //  voxels[(z * self.dimensions.z * self.dimensions.z) + (y * self.dimensions.y) + x].get_behavior()
-  //}
+/* Distance from the point to the bounds; also used for chunks that
+   are paged out, of which only the bounds are kept. */
+pub fn bounds_distance(bb: &BB3, point: Vec3f) -> f32
+{
+  let (min, max) = (bb.min(), bb.max());
+  let d = Vec3f::new( axis_distance(point.x, min.x, max.x),
+                      axis_distance(point.y, min.y, max.y),
+                      axis_distance(point.z, min.z, max.z));
+  d.length()
+}
+
+#[inline(always)]
+priv fn div_floor(a: i32, b: i32) -> i32
+{
+  if a >= 0 { a / b }
+  else { (a - b + 1) / b }
+}
 
-  pub fn draw(&self)
+#[inline(always)]
+priv fn axis_distance(p: f32, min: f32, max: f32) -> f32
+{
+  if p < min { min - p }
+  else if p > max { p - max }
+  else { 0.0 }
+}
+
+#[inline(always)]
+priv fn push_u32(bytes: &mut ~[u8], x: u32)
+{
+  bytes.push(x as u8);
+  bytes.push((x >> 8) as u8);
+  bytes.push((x >> 16) as u8);
+  bytes.push((x >> 24) as u8);
+}
+
+#[inline(always)]
+priv fn read_u32(bytes: &[u8], at: uint) -> u32
+{
+  (bytes[at] as u32) | (bytes[at + 1] as u32 << 8) |
+  (bytes[at + 2] as u32 << 16) | (bytes[at + 3] as u32 << 24)
+}
+
+#[cfg(test)]
+mod test
+{
+  use std::os;
+  use std::iterator::IteratorUtil;
+  use extra::tempfile;
+  use math::{ Vec3f, Vec3i, Vec3u8 };
+  use super::super::Vertex;
+  use super::{ Chunk, chunk_of };
+
+  #[test]
+  fn chunk_of_negative()
+  {
+    assert_eq!(chunk_of(0, 31, 32), (0, 0, 1));
+    assert_eq!(chunk_of(-1, -32, -33), (-1, -1, -2));
+  }
+
+  #[test]
+  fn edit()
   {
+    let mut chunk = Chunk::new(Vec3i::new(0, 0, 0));
+    chunk.set(Vertex::new(Vec3i::new(1, 2, 3), Vec3u8::new(10, 20, 30)), 4);
+    chunk.set(Vertex::new(Vec3i::new(1, 2, 3), Vec3u8::new(40, 50, 60)), 4);
+    assert_eq!(chunk.voxels.len(), 1);
+    assert_eq!(chunk.voxels[0].color.x, 40);
+    assert_eq!(chunk.clusters, ~[4]);
 
+    assert!(chunk.clear(1, 2, 3));
+    assert!(!chunk.clear(1, 2, 3));
+    assert!(chunk.find(1, 2, 3).is_none());
+  }
+
+  #[test]
+  fn bounds()
+  {
+    let mut chunk = Chunk::new(Vec3i::new(0, 0, 0));
+    assert_eq!(chunk.distance_to(Vec3f::new(100.0, 0.0, 0.0)), 0.0);
+
+    chunk.set(Vertex::new(Vec3i::new(0, 0, 0), Vec3u8::new(0, 0, 0)), -1);
+    chunk.set(Vertex::new(Vec3i::new(3, 0, 0), Vec3u8::new(0, 0, 0)), -1);
+    chunk.update_bounds(2.0);
+
+    /* Voxels reach a unit past their positions, at size 2. */
+    assert_eq!(chunk.distance_to(Vec3f::new(3.0, 0.0, 0.0)), 0.0);
+    assert_eq!(chunk.distance_to(Vec3f::new(10.0, 0.0, 0.0)), 3.0);
+    assert!(chunk.in_front(Vec3f::new(-5.0, 0.0, 0.0), &Vec3f::new(1.0, 0.0, 0.0)));
+    assert!(!chunk.in_front(Vec3f::new(10.0, 0.0, 0.0), &Vec3f::new(1.0, 0.0, 0.0)));
+  }
+
+  #[test]
+  fn page()
+  {
+    let mut chunk = Chunk::new(Vec3i::new(-1, 0, 2));
+    chunk.set(Vertex::new(Vec3i::new(-5, 1, 70), Vec3u8::new(1, 2, 3)), -1);
+    chunk.set(Vertex::new(Vec3i::new(-32, 31, 64), Vec3u8::new(255, 128, 0)), 7);
+
+    /* Unique per run, so parallel and repeated runs don't collide. */
+    let dir = tempfile::mkdtemp(&os::tmpdir(), "q3-test-chunk").expect("Unable to create a temporary directory");
+    let file = dir.push("page.chunk").to_str();
+    let written = chunk.write(file);
+    let read = Chunk::read(file);
+    os::remove_file(&Path(file));
+    os::remove_dir(&dir);
+
+    assert!(written.is_ok());
+    let read = match read
+    {
+      Ok(read) => { read }
+      Err(err) => { fail!(err) }
+    };
+
+    assert_eq!(read.position.x, -1);
+    assert_eq!(read.position.z, 2);
+    assert_eq!(read.clusters, ~[-1, 7]);
+    assert_eq!(read.voxels.len(), 2);
+    for chunk.voxels.iter().zip(read.voxels.iter()).advance |(a, b)|
+    {
+      assert_eq!((a.position.x, a.position.y, a.position.z), (b.position.x, b.position.y, b.position.z));
+      assert_eq!((a.color.x, a.color.y, a.color.z), (b.color.x, b.color.y, b.color.z));
+    }
+    assert!(read.dirty);
   }
 }
 
@@ -0,0 +1,1007 @@
+/*
+    Copyright 2013 Jesse 'Jeaye' Wilkerson
+    See licensing in LICENSE file, or at:
+        http://www.opensource.org/licenses/BSD-3-Clause
+
+    File: obj/voxel/grid.rs
+    Author: Jesse 'Jeaye' Wilkerson
+    Description:
+      A voxelization of arbitrary triangles;
+      CPU-side only, see voxel::Map for drawing it.
+*/
+
+use std::{ f32, i32, uint, vec, cmp, comm, task };
+use std::iterator::IteratorUtil;
+use std::hashmap::HashMap;
+use extra::sort;
+use extra::arc::ARC;
+use math::{ Vec3f, Vec3i, Vec3u8 };
+use primitive::Triangle;
+use export::Mesh;
+use super::Vertex;
+use super::octree::{ Octree, Node_Slack };
+use super::mesher;
+use super::mesher::Face_Mesh;
+
+/* Corners of each cube face, counter-clockwise from outside, along with
+   the direction of the neighbor that would hide it. */
+static Cube_Faces: [([i32, ..3], [[f32, ..3], ..4]), ..6] =
+[
+  ([1, 0, 0], [[1.0, -1.0, -1.0], [1.0, 1.0, -1.0], [1.0, 1.0, 1.0], [1.0, -1.0, 1.0]]),
+  ([-1, 0, 0], [[-1.0, -1.0, 1.0], [-1.0, 1.0, 1.0], [-1.0, 1.0, -1.0], [-1.0, -1.0, -1.0]]),
+  ([0, 1, 0], [[-1.0, 1.0, -1.0], [-1.0, 1.0, 1.0], [1.0, 1.0, 1.0], [1.0, 1.0, -1.0]]),
+  ([0, -1, 0], [[-1.0, -1.0, 1.0], [-1.0, -1.0, -1.0], [1.0, -1.0, -1.0], [1.0, -1.0, 1.0]]),
+  ([0, 0, 1], [[-1.0, -1.0, 1.0], [1.0, -1.0, 1.0], [1.0, 1.0, 1.0], [-1.0, 1.0, 1.0]]),
+  ([0, 0, -1], [[1.0, -1.0, -1.0], [-1.0, -1.0, -1.0], [-1.0, 1.0, -1.0], [1.0, 1.0, -1.0]]),
+];
+
+/* Tasks splitting up the voxelization; see new_with_workers. */
+pub static Default_Workers: uint = 4;
+
+/* A contiguous run of voxels within the same BSP cluster. */
+pub struct Region
+{
+  cluster: i32,
+  start: uint,
+  count: uint,
+}
+
+pub struct Voxel_Grid
+{
+  resolution: u32,
+  voxel_size: f32,
+
+  /* One per solid cell; positions are in voxels,
+     relative to the center of the grid. */
+  voxels: ~[Vertex],
+
+  /* Cell position to its index in voxels. */
+  cells: HashMap<(i32, i32, i32), uint>,
+
+  /* Empty until partitioned. */
+  regions: ~[Region],
+
+  /* Triangle and box tests the voxelization ran, the octree's
+     own included; only for comparing it against new_exhaustive. */
+  tests: uint,
+}
+
+impl Voxel_Grid
+{
+  #[inline(always)]
+  pub fn new(tris: &[Triangle], res: u32) -> Voxel_Grid
+  { Voxel_Grid::create(tris, res, Default_Workers, false) }
+
+  /* The result doesn't depend on the number of workers. */
+  #[inline(always)]
+  pub fn new_with_workers(tris: &[Triangle], res: u32, workers: uint) -> Voxel_Grid
+  { Voxel_Grid::create(tris, res, workers, false) }
+
+  /* Tests every cell in each triangle's bounding box, without the
+     octree or workers. Same result, only slower; kept for comparison. */
+  #[inline(always)]
+  pub fn new_exhaustive(tris: &[Triangle], res: u32) -> Voxel_Grid
+  { Voxel_Grid::create(tris, res, 1, true) }
+
+  /* No voxels; fill it with set(). */
+  pub fn empty(res: u32, voxel_size: f32) -> Voxel_Grid
+  {
+    Voxel_Grid
+    {
+      resolution: res,
+      voxel_size: voxel_size,
+      voxels: ~[],
+      cells: HashMap::new::<(i32, i32, i32), uint>(),
+      regions: ~[],
+      tests: 0,
+    }
+  }
+
+  priv fn create(tris: &[Triangle], res: u32, workers: uint, exhaustive: bool) -> Voxel_Grid
+  {
+    let mut grid = Voxel_Grid::empty(res, 0.0);
+    grid.voxelize(tris, workers, exhaustive);
+
+    grid
+  }
+
+  #[inline(always)]
+  pub fn len(&self) -> uint
+  { self.voxels.len() }
+
+  #[inline(always)]
+  pub fn is_solid(&self, x: i32, y: i32, z: i32) -> bool
+  { self.cells.contains_key(&(x, y, z)) }
+
+  /* The color of the cell, if it's solid. */
+  pub fn get(&self, x: i32, y: i32, z: i32) -> Option<Vec3u8>
+  {
+    match self.cells.find(&(x, y, z))
+    {
+      Some(&i) => { Some(self.voxels[i].color) }
+      None => { None }
+    }
+  }
+
+  /* Fills the cell, or recolors it if it's already solid. New cells
+     on a partitioned grid are put in a trailing unclassified (-1)
+     region. Edits aren't seen by a voxel::Map that's already built. */
+  pub fn set(&mut self, x: i32, y: i32, z: i32, color: Vec3u8)
+  {
+    let existing = match self.cells.find(&(x, y, z))
+    {
+      Some(&i) => { Some(i) }
+      None => { None }
+    };
+
+    match existing
+    {
+      Some(i) => { self.voxels[i].color = color; }
+      None =>
+      {
+        self.cells.insert((x, y, z), self.voxels.len());
+        self.voxels.push(Vertex::new(Vec3i::new(x, y, z), color));
+
+        if self.regions.len() > 0
+        {
+          if self.regions[self.regions.len() - 1].cluster != -1
+          { self.regions.push(Region { cluster: -1, start: self.voxels.len() - 1, count: 0 }); }
+          self.regions[self.regions.len() - 1].count += 1;
+        }
+      }
+    }
+  }
+
+  /* Empties the cell; returns whether it was solid. Each region
+     after the cell's gives its last voxel to the gap left before it,
+     so the runs stay contiguous and only one cell moves per region. */
+  pub fn clear(&mut self, x: i32, y: i32, z: i32) -> bool
+  {
+    let index = match self.cells.pop(&(x, y, z))
+    {
+      Some(i) => { i }
+      None => { return false; }
+    };
+
+    let mut hole = index;
+    let mut first = self.regions.len();
+    for uint::range(0, self.regions.len()) |r|
+    {
+      let region = &self.regions[r];
+      if index >= region.start && index < region.start + region.count
+      { first = r; break; }
+    }
+    for uint::range(first, self.regions.len()) |r|
+    {
+      if r == first
+      { self.regions[r].count -= 1; }
+      else
+      { self.regions[r].start -= 1; }
+
+      let last = self.regions[r].start + self.regions[r].count;
+      self.move_voxel(last, hole);
+      hole = last;
+    }
+
+    /* Unpartitioned, or the gap is now at the end. */
+    let last = self.voxels.len() - 1;
+    self.move_voxel(last, hole);
+    self.voxels.pop();
+    true
+  }
+
+  priv fn move_voxel(&mut self, from: uint, to: uint)
+  {
+    if from == to
+    { return; }
+
+    self.voxels[to] = self.voxels[from];
+    let pos = self.voxels[to].position;
+    self.cells.insert((pos.x, pos.y, pos.z), to);
+  }
+
+  /* Each solid cell, once. */
+  #[inline(always)]
+  pub fn iter<'r>(&'r self) -> vec::VecIterator<'r, Vertex>
+  { self.voxels.iter() }
+
+  /* Groups the voxels by the region (BSP cluster) each voxel's center lies in. */
+  pub fn partition(&mut self, classify: &fn(Vec3f) -> i32)
+  {
+    struct Keyed
+    {
+      cluster: i32,
+      vert: Vertex,
+    }
+
+    let mut keyed = vec::with_capacity(self.voxels.len());
+    for self.voxels.iter().advance |vert|
+    {
+      let center = voxel_center(vert.position, self.voxel_size);
+      keyed.push(Keyed { cluster: classify(center), vert: *vert });
+    }
+    sort::quick_sort(keyed, |a, b| a.cluster <= b.cluster);
+
+    self.voxels = vec::with_capacity(keyed.len());
+    self.regions = ~[];
+    for keyed.iter().advance |k|
+    {
+      let len = self.regions.len();
+      if len == 0 || self.regions[len - 1].cluster != k.cluster
+      { self.regions.push(Region { cluster: k.cluster, start: self.voxels.len(), count: 0 }); }
+
+      self.regions[self.regions.len() - 1].count += 1;
+      self.cells.insert((k.vert.position.x, k.vert.position.y, k.vert.position.z), self.voxels.len());
+      self.voxels.push(k.vert);
+    }
+    debug!("VOXEL: Partitioned into %? regions", self.regions.len());
+  }
+
+  /* The voxels as one mesh of cubes, for the exporters. Faces
+     between neighboring voxels are left out. */
+  pub fn to_mesh(&self) -> Mesh
+  {
+    let mut mesh = Mesh::new();
+    let group = mesh.add_group("voxel", "");
+    let h = self.voxel_size / 2.0;
+    for self.voxels.iter().advance |vert|
+    {
+      let (x, y, z) = (vert.position.x, vert.position.y, vert.position.z);
+      let color = vert.color;
+      let center = Vec3f::new(x as f32, y as f32, z as f32) * self.voxel_size;
+      let color = Vec3f::new(color.x as f32 / 255.0, color.y as f32 / 255.0, color.z as f32 / 255.0);
+      for Cube_Faces.iter().advance |&(dir, corners)|
+      {
+        if self.is_solid(x + dir[0], y + dir[1], z + dir[2])
+        { loop; }
+
+        let mut quad = [0u32, ..4];
+        for uint::range(0, 4) |i|
+        {
+          let corner = Vec3f::new(corners[i][0] * h, corners[i][1] * h, corners[i][2] * h);
+          quad[i] = mesh.add_vertex(center + corner, color, None);
+        }
+        mesh.groups[group].indices.push_all([quad[0], quad[1], quad[2], quad[0], quad[2], quad[3]]);
+      }
+    }
+    mesh
+  }
+
+  /* The faces bordering empty space, as voxel::Map draws them
+     outside of instanced mode. */
+  #[inline(always)]
+  pub fn to_faces(&self, greedy: bool) -> Face_Mesh
+  { mesher::build(self.voxels, self.voxel_size, greedy, |x, y, z| self.is_solid(x, y, z)) }
+
+  priv fn voxelize(&mut self, tris: &[Triangle], workers: uint, exhaustive: bool)
+  {
+    /* Require at least one triangle. */
+    assert!(tris.len() >= 1);
+    debug!("VOXEL: Incoming triangles: %?", tris.len());
+
+    /* Bounding box of vert dimensions. */
+    let mut min = Vec3f::new( tris[0].verts[0].position.x,
+                              tris[0].verts[0].position.y, 
+                              tris[0].verts[0].position.z);
+    let mut max = Vec3f::new( tris[0].verts[0].position.x,
+                              tris[0].verts[0].position.y,
+                              tris[0].verts[0].position.z);
+    for tris.each |curr|
+    {
+      for curr.verts.each |vert|
+      {
+        min.x = cmp::min(min.x, vert.position.x);
+        min.y = cmp::min(min.y, vert.position.y);
+        min.z = cmp::min(min.z, vert.position.z);
+
+        max.x = cmp::max(max.x, vert.position.x);
+        max.y = cmp::max(max.y, vert.position.y);
+        max.z = cmp::max(max.z, vert.position.z);
+      }
+    }
+    debug!("VOXEL: Min: %s Max: %s", min.to_str(), max.to_str());
+    let center = Vec3f::new(max.x - ((max.x - min.x) / 2.0),
+                            max.y - ((max.y - min.y) / 2.0),
+                            max.z - ((max.z - min.z) / 2.0));
+    debug!("VOXEL: Center of mesh is %s", center.to_str());
+
+    /* Calculate, given resolution (how many voxels across), the dimensions of a voxel. */
+    self.voxel_size = cmp::max( max.x - min.x,
+                                cmp::max(max.y - min.y, max.z - min.z)) / (self.resolution as f32);
+    debug!("VOXEL: Voxel size is %?", self.voxel_size);
+
+    /* Create 3D array of voxels. */
+    let mid_offset = (((self.resolution as f32) / 2.0) * self.voxel_size);
+    debug!("VOXEL: Midpoint offset is %?", mid_offset);
+
+    /* The cells each triangle could touch. */
+    let ranges = tris.map(|tri| self.cell_range(tri, mid_offset));
+
+    /* Color totals and contributing triangles per cell, for averaging. */
+    let mut sums: ~[(u32, u32, u32, u32)] = ~[];
+
+    if exhaustive
+    {
+      for uint::range(0, tris.len()) |t|
+      {
+        let (start, end) = ranges[t];
+        let mut hits = ~[];
+        self.tests += test_cells(&tris[t], start, end, self.resolution, self.voxel_size, false, &mut hits);
+        self.add_hits(hits, &mut sums);
+      }
+    }
+    else
+    {
+      let tree = Octree::new(tris, ranges, self.resolution, self.voxel_size);
+      let leaves = tree.leaf_count();
+      let workers = cmp::max(1, cmp::min(workers, leaves));
+      self.tests = tree.tests;
+      debug!("VOXEL: Octree has %? leaves for %? workers", leaves, workers);
+
+      if workers == 1
+      {
+        let (hits, tests) = test_leaves(tris, ranges, &tree, 0, leaves, self.resolution, self.voxel_size);
+        self.add_hits(hits, &mut sums);
+        self.tests += tests;
+      }
+      else
+      {
+        /* Each worker takes a contiguous run of leaves. */
+        let shared = ARC((tris.to_owned(), ranges, tree));
+        let mut ports = ~[];
+        for uint::range(0, workers) |w|
+        {
+          let (port, chan) = comm::stream();
+          let local = shared.clone();
+          let (first, last) = (w * leaves / workers, (w + 1) * leaves / workers);
+          let (resolution, voxel_size) = (self.resolution, self.voxel_size);
+          do task::spawn
+          {
+            let &(ref tris, ref ranges, ref tree) = local.get();
+            chan.send(test_leaves(*tris, *ranges, tree, first, last, resolution, voxel_size));
+          }
+          ports.push(port);
+        }
+
+        /* Merged in leaf order, so cells are created, and colors
+           summed, just as they would be by a single worker. */
+        for ports.iter().advance |port|
+        {
+          let (hits, tests) = port.recv();
+          self.add_hits(hits, &mut sums);
+          self.tests += tests;
+        }
+      }
+    }
+
+    for uint::range(0, self.voxels.len()) |i|
+    {
+      let (r, g, b, n) = sums[i];
+      self.voxels[i].color = Vec3u8::new((r / n) as u8, (g / n) as u8, (b / n) as u8);
+    }
+    debug!("VOXEL: Enabled %? of %? voxels", self.voxels.len(), (f32::pow((self.resolution) as f32, 3.0)) as uint);
+  }
+
+  /* The first and one past the last cell in the triangle's bounding box. */
+  priv fn cell_range(&self, tri: &Triangle, mid_offset: f32) -> (Vec3i, Vec3i)
+  {
+    /* Calculate bounding box of the triangle. */
+    let mut min = Vec3f::new(tri.verts[0].position.x, tri.verts[0].position.y, tri.verts[0].position.z);
+    let mut max = Vec3f::new(tri.verts[0].position.x, tri.verts[0].position.y, tri.verts[0].position.z);
+    for tri.verts.each |vert|
+    {
+      min.x = cmp::min(min.x, vert.position.x);
+      min.y = cmp::min(min.y, vert.position.y);
+      min.z = cmp::min(min.z, vert.position.z);
+
+      max.x = cmp::max(max.x, vert.position.x);
+      max.y = cmp::max(max.y, vert.position.y);
+      max.z = cmp::max(max.z, vert.position.z);
+    }
+
+    /* Determine what voxels lie in the bounding box. */
+    let mut vox_amount = Vec3i::new(f32::ceil(((max.x - min.x) / self.voxel_size)) as i32,
+                                    f32::ceil(((max.y - min.y) / self.voxel_size)) as i32,
+                                    f32::ceil(((max.z - min.z) / self.voxel_size)) as i32);
+    if vox_amount.x < 1
+    { vox_amount.x = 1; }
+    if vox_amount.y < 1
+    { vox_amount.y = 1; }
+    if vox_amount.z < 1
+    { vox_amount.z = 1; }
+
+    let start_indices = Vec3i::new( ((min.x - -mid_offset) / self.voxel_size) as i32, 
+                                    ((min.y - -mid_offset) / self.voxel_size) as i32,
+                                    ((min.z - -mid_offset) / self.voxel_size) as i32);
+
+    (start_indices, start_indices + vox_amount)
+  }
+
+  /* Each hit marks its cell solid and adds its color to the cell's average. */
+  priv fn add_hits(&mut self, hits: &[Vertex], sums: &mut ~[(u32, u32, u32, u32)])
+  {
+    for hits.iter().advance |hit|
+    {
+      let pos = (hit.position.x, hit.position.y, hit.position.z);
+      let existing = match self.cells.find(&pos)
+      {
+        Some(&i) => { Some(i) }
+        None => { None }
+      };
+      let i = match existing
+      {
+        Some(i) => { i }
+        None =>
+        {
+          self.cells.insert(pos, self.voxels.len());
+          self.voxels.push(*hit);
+          sums.push((0, 0, 0, 0));
+          self.voxels.len() - 1
+        }
+      };
+
+      let (r, g, b, n) = sums[i];
+      sums[i] = (r + hit.color.x as u32, g + hit.color.y as u32, b + hit.color.z as u32, n + 1);
+    }
+  }
+}
+
+/* The hits of every triangle reaching the leaves from first up to last,
+   in the order the octree visits them, and how many tests that took.
+   Runs on the worker tasks. */
+priv fn test_leaves(tris: &[Triangle], ranges: &[(Vec3i, Vec3i)], tree: &Octree,
+                    first: uint, last: uint, resolution: u32, voxel_size: f32) -> (~[Vertex], uint)
+{
+  let mut hits = ~[];
+  let mut tests = 0;
+  let mut index = 0;
+  for tree.each_leaf |leaf|
+  {
+    if index >= last
+    { break; }
+
+    if index >= first
+    {
+      /* Only test the triangles that reach the leaf, and only within it. */
+      for leaf.tris.iter().advance |&t|
+      {
+        let (start, end) = ranges[t];
+        let start = Vec3i::new( cmp::max(start.x, leaf.min.x),
+                                cmp::max(start.y, leaf.min.y),
+                                cmp::max(start.z, leaf.min.z));
+        let end = Vec3i::new( cmp::min(end.x, leaf.min.x + leaf.size),
+                              cmp::min(end.y, leaf.min.y + leaf.size),
+                              cmp::min(end.z, leaf.min.z + leaf.size));
+        tests += test_cells(&tris[t], start, end, resolution, voxel_size, true, &mut hits);
+      }
+    }
+    index += 1;
+  }
+  (hits, tests)
+}
+
+/* Tests the triangle against each cell from start up to end, returning
+   how many were tested. With cull, cells that the triangle's plane
+   misses are skipped first; that's the cheapest of the test's axes,
+   yet tri_cube_intersect checks it last. */
+priv fn test_cells(tri: &Triangle, start: Vec3i, end: Vec3i, resolution: u32, voxel_size: f32,
+                   cull: bool, hits: &mut ~[Vertex]) -> uint
+{
+  let v0 = tri.verts[0].position;
+  let normal = (tri.verts[1].position - v0).cross(&(tri.verts[2].position - tri.verts[1].position));
+  let distance = normal.dot(&v0);
+  /* Furthest a cell's box reaches along the normal, with the same slack as the octree's nodes. */
+  let reach = voxel_size * (f32::abs(normal.x) + f32::abs(normal.y) + f32::abs(normal.z)) * Node_Slack;
+
+  let mut tests = 0;
+  for i32::range(start.z, end.z) |z|
+  {
+    for i32::range(start.y, end.y) |y|
+    {
+      for i32::range(start.x, end.x) |x|
+      {
+        let c = cell_center(x, y, z, resolution, voxel_size);
+        if cull && f32::abs(normal.dot(&c) - distance) > reach
+        { loop; }
+
+        /* Check for intersection. */
+        tests += 1;
+        if !tri_cube_intersect(c, voxel_size, tri)
+        { loop; }
+
+        /* We have intersection; the cell is solid, possibly already. */
+        hits.push(Vertex::new(Vec3i::new( x - (resolution / 2) as i32,
+                                          y - (resolution / 2) as i32,
+                                          z - (resolution / 2) as i32),
+                              Vec3u8::new(tri.verts[0].color.x as u8, tri.verts[0].color.y as u8, tri.verts[0].color.z as u8))); /* TODO: Conversion between Vec types. */
+      }
+    }
+  }
+  tests
+}
+
+/* World center of the voxel at the grid position. This is where voxels
+   are drawn, so it's also where they're classified and edited. */
+#[inline(always)]
+pub fn voxel_center(position: Vec3i, voxel_size: f32) -> Vec3f
+{ Vec3f::new(position.x as f32, position.y as f32, position.z as f32) * voxel_size }
+
+/* Center of the cell at the given (unshifted) voxelizer indices. */
+#[inline(always)]
+pub fn cell_center(x: i32, y: i32, z: i32, resolution: u32, voxel_size: f32) -> Vec3f
+{
+  Vec3f::new( ((x as f32 - (resolution as f32 / 2.0)) * voxel_size) + (voxel_size / 2.0), 
+              ((y as f32 - (resolution as f32 / 2.0)) * voxel_size) + (voxel_size / 2.0),
+              ((z as f32 - (resolution as f32 / 2.0)) * voxel_size) + (voxel_size / 2.0))
+}
+
+macro_rules! find_min_max
+(
+  ($x0:expr, $x1:expr, $x2:expr) =>
+  (
+    {
+      _min = $x0;
+      _max = $x0;
+
+      if($x1 < _min){ _min = $x1; }
+      if($x1 > _max){ _max = $x1; }
+      if($x2 < _min){ _min = $x2; }
+      if($x2 > _max){ _max = $x2; }
+    }
+  )
+)
+
+/*======================== X-tests ========================*/
+macro_rules! axis_test_x01
+(
+  ($a:expr, $b:expr, $fa:expr, $fb:expr) =>
+  (
+    {
+      _p0 = $a * _v0.y - $b * _v0.z;
+      _p2 = $a * _v2.y - $b * _v2.z;
+      if _p0 < _p2  { _min = _p0; _max = _p2; } else { _min = _p2; _max = _p0; }
+      _rad = $fa * box_size + $fb * box_size;
+      if _min > _rad || _max < -_rad  { return false; }
+    }
+  )
+)
+
+macro_rules! axis_test_x2
+(
+  ($a:expr, $b:expr, $fa:expr, $fb:expr) =>
+  (
+    {
+      _p0 = $a * _v0.y - $b * _v0.z;
+      _p1 = $a * _v1.y - $b * _v1.z;
+      if _p0 < _p1 { _min = _p0; _max = _p1; } else { _min = _p1; _max = _p0; }
+      _rad = $fa * box_size + $fb * box_size;
+      if _min > _rad || _max < -_rad { return false; }
+    }
+  )
+)
+
+/*======================== Y-tests ========================*/
+
+macro_rules! axis_test_y02
+(
+  ($a:expr, $b:expr, $fa:expr, $fb:expr) =>
+  (
+    {
+      _p0 = -$a * _v0.x + $b * _v0.z;
+      _p2 = -$a * _v2.x + $b * _v2.z;
+      if _p0 < _p2 { _min = _p0; _max = _p2; } else { _min = _p2; _max = _p0; }
+      _rad = $fa * box_size + $fb * box_size;
+      if _min > _rad || _max < -_rad { return false; }
+    }
+  )
+)
+
+macro_rules! axis_test_y1
+(
+  ($a:expr, $b:expr, $fa:expr, $fb:expr) =>
+  (
+    {
+      _p0 = -$a * _v0.x + $b * _v0.z;
+      _p1 = -$a * _v1.x + $b * _v1.z;
+      if _p0 < _p1 { _min = _p0; _max = _p1; } else { _min = _p1; _max = _p0; }
+      _rad = $fa * box_size + $fb * box_size;
+      if _min > _rad || _max < -_rad { return false; }
+    }
+  )
+)
+
+/*======================== Z-tests ========================*/
+
+macro_rules! axis_test_z12
+(
+  ($a:expr, $b:expr, $fa:expr, $fb:expr) =>
+  (
+    {
+      _p1 = $a * _v1.x - $b * _v1.y;
+      _p2 = $a * _v2.x - $b * _v2.y;
+      if _p2 < _p1 { _min = _p2; _max = _p1;} else { _min = _p1; _max = _p2; }
+      _rad = $fa * box_size + $fb * box_size;
+      if _min > _rad || _max < -_rad { return false; }
+    }
+  )
+)
+
+
+macro_rules! axis_test_z0
+(
+  ($a:expr, $b:expr, $fa:expr, $fb:expr) =>
+  (
+    {
+      _p0 = $a * _v0.x - $b * _v0.y;
+      _p1 = $a * _v1.x - $b * _v1.y;
+      if _p0 < _p1 { _min = _p0; _max = _p1; } else { _min = _p1; _max = _p0; }
+      _rad = $fa * box_size + $fb * box_size;
+      if _min > _rad || _max < -_rad { return false; }
+    }
+  )
+)
+
+/* Note that box_size is taken as the box's half extent. */
+#[inline(always)]
+pub fn tri_cube_intersect(box_center: Vec3f, box_size: f32, tri: &Triangle) -> bool
+{
+  let _v0;
+  let _v1;
+  let _v2;
+  let mut _min;
+  let mut _max;
+  let mut _p0 = 0.0;
+  let mut _p1 = 0.0;
+  let mut _p2 = 0.0;
+  let mut _rad;
+  let mut _fex;
+  let mut _fey;
+  let mut _fez;
+  let _normal;
+  let _e0;
+  let _e1;
+  let _e2;
+
+  /* Move everything so that the box's center is in (0, 0, 0). */
+  _v0 = tri.verts[0].position - box_center;
+  _v1 = tri.verts[1].position - box_center;
+  _v2 = tri.verts[2].position - box_center;
+
+  /* Computer triangle edges. */
+  _e0 = _v1 - _v0; /* Edge 0. */
+  _e1 = _v2 - _v1; /* Edge 1. */
+  _e2 = _v0 - _v2; /* Edge 2. */
+
+  //debug!("VOXEL: [Per voxel SAT] Testing bullet 3 edge 0");
+  /* Bullet 3. */
+  _fex = f32::abs(_e0.x);
+  _fey = f32::abs(_e0.y);
+  _fez = f32::abs(_e0.z);
+  axis_test_x01!(_e0.z, _e0.y, _fez, _fey);
+  axis_test_y02!(_e0.z, _e0.x, _fez, _fex);
+  axis_test_z12!(_e0.y, _e0.x, _fey, _fex);
+
+  //debug!("VOXEL: [Per voxel SAT] Testing bullet 3 edge 1");
+  _fex = f32::abs(_e1.x);
+  _fey = f32::abs(_e1.y);
+  _fez = f32::abs(_e1.z);
+  axis_test_x01!(_e1.z, _e1.y, _fez, _fey);
+  axis_test_y02!(_e1.z, _e1.x, _fez, _fex);
+  axis_test_z0!(_e1.y, _e1.x, _fey, _fex);
+
+  //debug!("VOXEL: [Per voxel SAT] Testing bullet 3 edge 2");
+  _fex = f32::abs(_e2.x);
+  _fey = f32::abs(_e2.y);
+  _fez = f32::abs(_e2.z);
+  axis_test_x2!(_e2.z, _e2.y, _fez, _fey);
+  axis_test_y1!(_e2.z, _e2.x, _fez, _fex);
+  axis_test_z12!(_e2.y, _e2.x, _fey, _fex);
+
+  //debug!("VOXEL: [Per voxel SAT] Testing bullet 1");
+  /* Bullet 1. */
+  /* Test in X-direction */
+  find_min_max!(_v0.x, _v1.x, _v2.x);
+  if _min > box_size || _max < -box_size { return false; }
+
+  /* Test in Y-direction */
+  find_min_max!(_v0.y, _v1.y, _v2.y);
+  if _min > box_size || _max < -box_size { return false; }
+
+  /* Test in Z-direction */
+  find_min_max!(_v0.z, _v1.z, _v2.z);
+  if _min > box_size || _max < -box_size { return false; }
+
+  //debug!("VOXEL: [Per voxel SAT] Testing bullet 2");
+  /* Bullet 2. */
+  _normal = _e0.cross(&_e1);
+  plane_cube_intersect(&_normal, &_v0, box_size)
+}
+
+#[inline(always)]
+priv fn plane_cube_intersect(normal: &Vec3f, vert: &Vec3f, box_size: f32) -> bool
+{
+  let mut vmin: [f32, ..3] = [0.0, 0.0, 0.0];
+  let mut vmax: [f32, ..3] = [0.0, 0.0, 0.0];
+  let mut v = 0.0;
+
+  for uint::range(0, 3) |q|
+  {
+    v = vert[q];
+    if normal[q] > 0.0
+    {
+      vmin[q] = -box_size - v;
+      vmax[q] = box_size - v;
+    }
+    else
+    {
+      vmin[q] = box_size - v;
+      vmax[q] = -box_size - v;
+    }
+  }
+  if (normal[0]*vmin[0]+normal[1]*vmin[1]+normal[2]*vmin[2]) > 0.0 { return false; }
+  if (normal[0]*vmax[0]+normal[1]*vmax[1]+normal[2]*vmax[2]) >= 0.0 { return true; }
+
+  false
+}
+
+#[cfg(test)]
+mod test
+{
+  use std::uint;
+  use std::hashmap::HashSet;
+  use std::iterator::IteratorUtil;
+  use math::{ Vec3f, Vec3u8 };
+  use primitive::{ Triangle, Vertex_PC, Cube, Sphere };
+  use super::Voxel_Grid;
+
+  fn positions(grid: &Voxel_Grid) -> HashSet<(i32, i32, i32)>
+  {
+    let mut set = HashSet::new();
+    for grid.voxels.iter().advance |vert|
+    { set.insert((vert.position.x, vert.position.y, vert.position.z)); }
+    set
+  }
+
+  /* Vec3u8 has no Eq. */
+  fn color_at(grid: &Voxel_Grid, x: i32, y: i32, z: i32) -> Option<(u8, u8, u8)>
+  {
+    match grid.get(x, y, z)
+    {
+      Some(c) => { Some((c.x, c.y, c.z)) }
+      None => { None }
+    }
+  }
+
+  fn expect(positions: &[(i32, i32, i32)]) -> HashSet<(i32, i32, i32)>
+  {
+    let mut set = HashSet::new();
+    for positions.iter().advance |&pos|
+    { set.insert(pos); }
+    set
+  }
+
+  #[test]
+  fn triangle()
+  {
+    /* 4 across at resolution 4, so each voxel is 1 unit. */
+    let tri = Triangle::new_with_position(Vec3f::new(-2.0, -2.0, 0.0),
+                                          Vec3f::new(2.0, -2.0, 0.0),
+                                          Vec3f::new(2.0, 1.5, 0.0));
+    let grid = Voxel_Grid::new([tri], 4);
+
+    assert_eq!(grid.voxel_size, 1.0);
+    assert_eq!(grid.voxels.len(), 13);
+    assert!(positions(&grid) == expect(
+    [
+      (-2, -2, 0), (-2, -1, 0),
+      (-1, -2, 0), (-1, -1, 0), (-1, 0, 0),
+      (0, -2, 0), (0, -1, 0), (0, 0, 0), (0, 1, 0),
+      (1, -2, 0), (1, -1, 0), (1, 0, 0), (1, 1, 0),
+    ]));
+  }
+
+  #[test]
+  fn cube()
+  {
+    let cube = Cube::new(4.0, Vec3f::zero());
+    let grid = Voxel_Grid::new(cube.tris, 4);
+
+    /* Each face yields a 4x4 slab on its side; the inside stays empty. */
+    let mut expected = ~[];
+    for [-2, 2].iter().advance |&side|
+    {
+      for [-2, -1, 0, 1].iter().advance |&a|
+      {
+        for [-2, -1, 0, 1].iter().advance |&b|
+        {
+          expected.push((side, a, b));
+          expected.push((a, side, b));
+          expected.push((a, b, side));
+        }
+      }
+    }
+
+    /* Edges and corners are shared by several triangles, but stored once. */
+    assert_eq!(grid.voxel_size, 1.0);
+    assert_eq!(grid.len(), 85);
+    assert!(positions(&grid) == expect(expected));
+    assert!(!positions(&grid).contains(&(0, 0, 0)));
+  }
+
+  #[test]
+  fn sphere()
+  {
+    /* A bare icosahedron at resolution 2 touches every voxel around the center. */
+    let sphere = Sphere::load(1.0, 0);
+    let grid = Voxel_Grid::new(sphere.tris, 2);
+
+    assert_eq!(sphere.tris.len(), 20);
+    assert!(positions(&grid) == expect(
+    [
+      (-1, -1, -1), (-1, -1, 0), (-1, 0, -1), (-1, 0, 0),
+      (0, -1, -1), (0, -1, 0), (0, 0, -1), (0, 0, 0),
+    ]));
+  }
+
+  /* The octree only skips tests; it never changes the result.
+     Gives the tests each ran, octree first. */
+  fn same_as_exhaustive(tris: &[Triangle], res: u32) -> (uint, uint)
+  {
+    let fast = Voxel_Grid::new(tris, res);
+    let slow = Voxel_Grid::new_exhaustive(tris, res);
+
+    assert!(fast.len() > 0);
+    assert_eq!(fast.len(), slow.len());
+    for slow.iter().advance |vert|
+    {
+      let (x, y, z) = (vert.position.x, vert.position.y, vert.position.z);
+      assert!(color_at(&fast, x, y, z) == color_at(&slow, x, y, z));
+    }
+    (fast.tests, slow.tests)
+  }
+
+  #[test]
+  fn octree()
+  {
+    let sphere = Sphere::load(1.0, 2);
+    same_as_exhaustive(sphere.tris, 32);
+
+    /* One large slanted triangle; most of its bounding box is empty,
+       so the octree should skip most of the exhaustive tests. */
+    let slope = Triangle::new_with_position(Vec3f::new(-3.0, -1.0, -2.0),
+                                            Vec3f::new(2.0, 3.0, 1.0),
+                                            Vec3f::new(1.0, -2.0, 3.0));
+    let (fast, slow) = same_as_exhaustive([slope], 48);
+    assert!(fast * 4 < slow, fmt!("%u tests with the octree, %u without", fast, slow));
+  }
+
+  #[test]
+  fn workers()
+  {
+    let sphere = Sphere::load(1.0, 2);
+    let single = Voxel_Grid::new_with_workers(sphere.tris, 32, 1);
+
+    /* Same voxels, in the same order, with the same colors. */
+    for [2u, 3, 8, 1000].iter().advance |&workers|
+    {
+      let multi = Voxel_Grid::new_with_workers(sphere.tris, 32, workers);
+      assert_eq!(multi.len(), single.len());
+      for uint::range(0, single.len()) |i|
+      {
+        let (a, b) = (&single.voxels[i], &multi.voxels[i]);
+        assert!((a.position.x, a.position.y, a.position.z) == (b.position.x, b.position.y, b.position.z));
+        assert!((a.color.x, a.color.y, a.color.z) == (b.color.x, b.color.y, b.color.z));
+      }
+    }
+  }
+
+  #[test]
+  fn merged_color()
+  {
+    /* Two triangles covering the same square, in different colors. */
+    let a = Vec3f::new(-1.0, -1.0, 0.0);
+    let b = Vec3f::new(1.0, -1.0, 0.0);
+    let c = Vec3f::new(1.0, 1.0, 0.0);
+    let red = Vec3f::new(10.0, 20.0, 30.0);
+    let blue = Vec3f::new(30.0, 40.0, 50.0);
+    let tris =
+    [
+      Triangle::new(Vertex_PC::new(a, red), Vertex_PC::new(b, red), Vertex_PC::new(c, red)),
+      Triangle::new(Vertex_PC::new(c, blue), Vertex_PC::new(b, blue), Vertex_PC::new(a, blue)),
+    ];
+    let grid = Voxel_Grid::new(tris, 2);
+
+    assert_eq!(grid.len(), 4);
+    for grid.iter().advance |vert|
+    { assert!((vert.color.x, vert.color.y, vert.color.z) == (20, 30, 40)); }
+  }
+
+  #[test]
+  fn edit()
+  {
+    let tri = Triangle::new_with_position(Vec3f::new(-2.0, -2.0, 0.0),
+                                          Vec3f::new(2.0, -2.0, 0.0),
+                                          Vec3f::new(2.0, 1.5, 0.0));
+    let mut grid = Voxel_Grid::new([tri], 4);
+
+    assert!(grid.is_solid(0, 0, 0));
+    assert!(!grid.is_solid(-2, 1, 0));
+    assert!(grid.get(-2, 1, 0).is_none());
+
+    grid.set(-2, 1, 0, Vec3u8::new(1, 2, 3));
+    assert!(color_at(&grid, -2, 1, 0) == Some((1, 2, 3)));
+    assert_eq!(grid.len(), 14);
+
+    /* Setting a solid cell only recolors it. */
+    grid.set(0, 0, 0, Vec3u8::new(1, 2, 3));
+    assert!(color_at(&grid, 0, 0, 0) == Some((1, 2, 3)));
+    assert_eq!(grid.len(), 14);
+
+    assert!(grid.clear(-2, -2, 0));
+    assert!(!grid.clear(-2, -2, 0));
+    assert!(!grid.is_solid(-2, -2, 0));
+    assert_eq!(grid.len(), 13);
+
+    /* Everything after the removed cell is still found. */
+    for grid.iter().advance |vert|
+    {
+      let color = (vert.color.x, vert.color.y, vert.color.z);
+      assert!(color_at(&grid, vert.position.x, vert.position.y, vert.position.z) == Some(color));
+    }
+  }
+
+  #[test]
+  fn partition()
+  {
+    let cube = Cube::new(4.0, Vec3f::zero());
+    let mut grid = Voxel_Grid::new(cube.tris, 4);
+    let before = positions(&grid);
+    let count = grid.voxels.len();
+
+    grid.partition(|pos| if pos.x < 0.0 { 1 } else { 0 });
+
+    /* Same voxels, now in one run per cluster. */
+    assert_eq!(grid.voxels.len(), count);
+    assert!(positions(&grid) == before);
+    assert_eq!(grid.regions.len(), 2);
+    assert_eq!(grid.regions[0].cluster, 0);
+    assert_eq!(grid.regions[0].start, 0);
+    assert_eq!(grid.regions[1].start, grid.regions[0].count);
+    assert_eq!(grid.regions[0].count + grid.regions[1].count, count);
+    for grid.voxels.slice(0, grid.regions[0].count).iter().advance |vert|
+    { assert!(vert.position.x >= 0); }
+
+    /* Cells still point at their moved voxels. */
+    for grid.iter().advance |vert|
+    { assert!(grid.is_solid(vert.position.x, vert.position.y, vert.position.z)); }
+    for grid.voxels.iter().enumerate().advance |(i, vert)|
+    { assert_eq!(*grid.cells.get(&(vert.position.x, vert.position.y, vert.position.z)), i); }
+
+    /* New cells land in a trailing unclassified region. */
+    grid.set(5, 5, 5, Vec3u8::new(1, 1, 1));
+    assert_eq!(grid.regions.len(), 3);
+    assert_eq!(grid.regions[2].cluster, -1);
+    assert_eq!(grid.regions[2].start, count);
+    assert_eq!(grid.regions[2].count, 1);
+
+    assert!(grid.clear(5, 5, 5));
+    assert_eq!(grid.regions[2].count, 0);
+
+    /* Clearing from the first region keeps each run to its own cluster. */
+    let (first, second) = (grid.regions[0].count, grid.regions[1].count);
+    let removed = grid.voxels[1].position;
+    assert!(grid.clear(removed.x, removed.y, removed.z));
+    assert_eq!(grid.voxels.len(), count - 1);
+    assert_eq!(grid.regions[0].count, first - 1);
+    assert_eq!(grid.regions[1].start, first - 1);
+    assert_eq!(grid.regions[1].count, second);
+    assert_eq!(grid.regions[2].start, count - 1);
+    for grid.voxels.slice(0, first - 1).iter().advance |vert|
+    { assert!(vert.position.x >= 0); }
+    for grid.voxels.slice_from(first - 1).iter().advance |vert|
+    { assert!(vert.position.x < 0); }
+    for grid.voxels.iter().enumerate().advance |(i, vert)|
+    { assert_eq!(*grid.cells.get(&(vert.position.x, vert.position.y, vert.position.z)), i); }
+    assert!(!grid.is_solid(removed.x, removed.y, removed.z));
+  }
+}
@@ -6,15 +6,20 @@
     File: obj/voxel/map.rs
     Author: Jesse 'Jeaye' Wilkerson
     Description:
-      A voxelization of arbitrary triangles
-      into OpenGL-ready cubes.
+      Renders a voxel grid as chunks of
+      instanced OpenGL cubes, or of their
+      exposed faces; chunks far from the
+      camera are paged to disk.
 */
 
-use std::{ f32, uint, vec, cmp, sys };
-use math::{ Vec3f, Vec3i, Vec3u8 };
-use primitive::Triangle;
-use super::{ Vertex, Behavior, Default };
-use ui::Console_Activator;
+use std::{ os, uint, i32, f32 };
+use std::iterator::IteratorUtil;
+use std::hashmap::{ HashMap, HashSet };
+use math::{ Vec3f, Vec3i, Vec3u8, BB3 };
+use super::{ Vertex, Voxel_Grid };
+use super::chunk::{ Chunk, Chunk_Size, chunk_of, bounds_distance };
+use super::mesher;
+use super::grid::voxel_center;
 
 #[path = "../../gl/mod.rs"]
 mod gl;
@@ -24,463 +29,857 @@ mod util;
 #[path = "../../gl/check.rs"]
 mod check;
 
+pub enum Draw_Mode
+{
+  /* A whole cube per voxel. */
+  Draw_Instanced,
+  /* Only faces bordering empty space. */
+  Draw_Faces,
+  /* Exposed faces, merged where neighbors share a color. */
+  Draw_Greedy,
+}
+
 struct Map
 {
   resolution: u32,
   voxel_size: f32,
 
+  /* The cube each voxel is instanced from. */
   vao: gl::GLuint,
   vbo: gl::GLuint,
-  ibo: gl::GLuint,
 
-  voxels: ~[Behavior],
-  indices: ~[Vertex],
+  /* For the face meshes, which have no instance attributes. */
+  mesh_vao: gl::GLuint,
+  mode: Draw_Mode,
+
+  /* Resident chunks, by chunk position. */
+  chunks: HashMap<(i32, i32, i32), Chunk>,
+
+  /* Chunks written to page_dir, with their bounds. */
+  paged: HashMap<(i32, i32, i32), BB3>,
+  page_dir: Option<~str>,
+  page_distance: f32,
+
+  /* Chunks to draw, from the last cull(). */
+  visible: ~[(i32, i32, i32)],
+  drawn_voxels: uint,
+  total_voxels: uint,
+
+  /* Of the visible chunks, as drawn in the current mode. */
+  drawn_faces: uint,
+  drawn_tris: uint,
 
   wireframe: bool,
 }
 
 impl Map
 {
-  pub fn new(tris: &[Triangle], res: u32) -> @mut Map
+  /* Partition the grid first, so the chunks know their clusters. */
+  pub fn new(grid: Voxel_Grid) -> @mut Map
   {
     let map = @mut Map
     {
-      resolution: res,
-      voxel_size: 0.0,
+      resolution: grid.resolution,
+      voxel_size: grid.voxel_size,
       vao: 0,
       vbo: 0,
-      ibo: 0,
-      voxels: ~[],
-      indices: ~[],
+      mesh_vao: 0,
+      mode: Draw_Instanced,
+      chunks: HashMap::new::<(i32, i32, i32), Chunk>(),
+      paged: HashMap::new::<(i32, i32, i32), BB3>(),
+      page_dir: None,
+      page_distance: 0.0,
+      visible: ~[],
+      drawn_voxels: 0,
+      total_voxels: grid.len(),
+      drawn_faces: 0,
+      drawn_tris: 0,
       wireframe: false,
     };
 
-    map.voxelize(tris);
+    /* Nothing is paged out yet, so every chunk is there to be had. */
+    if grid.regions.len() == 0
+    {
+      for grid.iter().advance |vert|
+      { map.chunk_mut(chunk_of(vert.position.x, vert.position.y, vert.position.z)).unwrap().set(*vert, -1); }
+    }
+    else
+    {
+      for grid.regions.iter().advance |region|
+      {
+        for grid.voxels.slice(region.start, region.start + region.count).iter().advance |vert|
+        { map.chunk_mut(chunk_of(vert.position.x, vert.position.y, vert.position.z)).unwrap().set(*vert, region.cluster); }
+      }
+    }
+
+    let voxel_size = map.voxel_size;
+    for map.chunks.mut_iter().advance |(_, chunk)|
+    { chunk.update_bounds(voxel_size); }
+    debug!("VOXEL: Split into %? chunks", map.chunks.len());
+
+    map.upload();
+
+    map
+  }
 
+  /* Creates the instanced cube; chunks upload their own voxels as they're drawn. */
+  priv fn upload(&mut self)
+  {
     /* Single voxel that will be instance-rendered. */
-    let h: f32 = map.voxel_size / 2.0;
+    let h: f32 = self.voxel_size / 2.0;
     let voxel: ~[f32] = /* TRIANGLE_STRIP style. */
     ~[
-      -h,-h,h,  h,-h,h,   
-      -h,h,h,   h,h,h,    
+      -h,-h,h,  h,-h,h,
+      -h,h,h,   h,h,h,
 
-      h,-h,h,   h,-h,-h,  
-      h,h,h,    h,h,-h,   
+      h,-h,h,   h,-h,-h,
+      h,h,h,    h,h,-h,
 
-      h,-h,-h,  -h,-h,-h, 
-      h,h,-h,   -h,h,-h,  
+      h,-h,-h,  -h,-h,-h,
+      h,h,-h,   -h,h,-h,
 
-      -h,-h,-h, -h,-h,h,  
-      -h,h,-h,  -h,h,h,   
+      -h,-h,-h, -h,-h,h,
+      -h,h,-h,  -h,h,h,
 
-      -h,-h,-h, h,-h,-h,  
-      -h,-h,h,  h,-h,h,   
+      -h,-h,-h, h,-h,-h,
+      -h,-h,h,  h,-h,h,
 
-      -h,h,h,   h,h,h,    
-      -h,h,-h,  h,h,-h,   
+      -h,h,h,   h,h,h,
+      -h,h,-h,  h,h,-h,
     ];
 
-    let names = check!(gl::gen_vertex_arrays(1));
-    assert!(names.len() == 1);
-    map.vao = names[0];
-
-    let names = check!(gl::gen_buffers(2));
+    let names = check!(gl::gen_vertex_arrays(2));
     assert!(names.len() == 2);
-    map.vbo = names[0];
-    map.ibo = names[1];
-    check!(gl::bind_vertex_array(map.vao));
-    check!(gl::bind_buffer(gl::ARRAY_BUFFER, map.vbo));
+    self.vao = names[0];
+    self.mesh_vao = names[1];
+
+    let names = check!(gl::gen_buffers(1));
+    assert!(names.len() == 1);
+    self.vbo = names[0];
+    check!(gl::bind_vertex_array(self.vao));
+    check!(gl::bind_buffer(gl::ARRAY_BUFFER, self.vbo));
     check!(gl::buffer_data(gl::ARRAY_BUFFER, voxel, gl::STATIC_DRAW));
+    check!(gl::bind_vertex_array(0));
+  }
 
-    check!(gl::bind_buffer(gl::ARRAY_BUFFER, map.ibo));
-    check!(gl::buffer_data(gl::ARRAY_BUFFER, map.indices, gl::STATIC_DRAW));
+  /* Every resident chunk is rebuilt for the new mode as it's drawn. */
+  pub fn set_mode(&mut self, mode: Draw_Mode)
+  {
+    self.mode = mode;
+    for self.chunks.mut_iter().advance |(_, chunk)|
+    { chunk.dirty = true; }
+  }
 
-    /* Console functions. */
-    Console_Activator::get().add_accessor("map.wireframe", |_|
-    { map.wireframe.to_str() });
-    Console_Activator::get().add_mutator("map.wireframe", |_, x|
+  /* Cells in chunks that are paged out count as empty. */
+  pub fn is_solid(&self, x: i32, y: i32, z: i32) -> bool
+  {
+    match self.chunks.find(&chunk_of(x, y, z))
     {
-      map.wireframe = if x == "true" { true }
-                          else { false };
-      None
-    });
+      Some(chunk) => { chunk.find(x, y, z).is_some() }
+      None => { false }
+    }
+  }
 
-    map
+  /* From now on, page() writes chunks further than the
+     distance (in world units) out to the directory. */
+  pub fn enable_paging(&mut self, dir: &str, distance: f32) -> Result<(), ~str>
+  {
+    if !os::path_is_dir(&Path(dir)) && !os::mkdir_recursive(&Path(dir), 0x1ed) /* 755 */
+    { return Err(fmt!("Unable to create %s", dir)); }
+
+    self.page_dir = Some(dir.to_owned());
+    self.page_distance = distance;
+    Ok(())
   }
 
-  pub fn draw(&self)
+  /* Pages chunks out and in around the camera. Chunks get a
+     chunk's worth of slack before going out, so those right
+     on the edge don't go back and forth every frame. */
+  pub fn page(&mut self, position: Vec3f)
   {
-    check!(gl::bind_vertex_array(self.vao));
+    let dir = match self.page_dir
+    {
+      Some(ref dir) => { dir.clone() }
+      None => { return; }
+    };
+    let slack = (Chunk_Size as f32) * self.voxel_size;
 
-    check!(gl::bind_buffer(gl::ARRAY_BUFFER, self.vbo));
-    check!(gl::vertex_attrib_pointer_f32(0, 3, false, 0, 0));
-    check!(gl::enable_vertex_attrib_array(0));
+    let mut outgoing = ~[];
+    for self.chunks.iter().advance |(&key, chunk)|
+    {
+      if chunk.distance_to(position) > self.page_distance + slack
+      { outgoing.push(key); }
+    }
+    for outgoing.iter().advance |&key|
+    {
+      let mut chunk = self.chunks.pop(&key).unwrap();
+      match chunk.write(page_file(dir, key))
+      {
+        Ok(()) =>
+        {
+          chunk.unload();
+          self.paged.insert(key, chunk.bb.unwrap());
+        }
+        Err(err) =>
+        {
+          /* Better to keep it around than to lose it. */
+          error!("VOXEL: Failed to page out chunk: %s", err);
+          self.chunks.insert(key, chunk);
+        }
+      }
+    }
 
-    check!(gl::bind_buffer(gl::ARRAY_BUFFER, self.ibo));
+    let mut incoming = ~[];
+    for self.paged.iter().advance |(&key, bb)|
+    {
+      if bounds_distance(bb, position) <= self.page_distance
+      { incoming.push(key); }
+    }
+    for incoming.iter().advance |&key|
+    { self.page_in(dir, key); }
+  }
 
-    check!(gl::vertex_attrib_pointer_i32(1, 3, false, (sys::size_of::<Vertex>()) as i32, 0));
-    check!(gl::enable_vertex_attrib_array(1));
-    check!(gl::vertex_attrib_divisor(1, 1));
+  /* On failure, the chunk stays paged out and its file is left alone. */
+  priv fn page_in(&mut self, dir: &str, key: (i32, i32, i32)) -> bool
+  {
+    match Chunk::read(page_file(dir, key))
+    {
+      Ok(chunk) =>
+      {
+        let mut chunk = chunk;
+        chunk.update_bounds(self.voxel_size);
+        self.paged.remove(&key);
+        self.chunks.insert(key, chunk);
+        true
+      }
+      Err(err) => { error!("VOXEL: Failed to page in chunk: %s", err); false }
+    }
+  }
 
-    check!(gl::vertex_attrib_pointer_u8(2, 3, true, (sys::size_of::<Vertex>()) as i32, 
-                                        (sys::size_of::<Vec3i>()) as u32));
-    check!(gl::enable_vertex_attrib_array(2));
-    check!(gl::vertex_attrib_divisor(2, 1));
+  /* The chunk at the chunk position, paged in or created if need be.
+     None if it can't be paged in; an empty chunk in its place would
+     be written over the saved one when it's paged out again. */
+  priv fn chunk_mut<'r>(&'r mut self, key: (i32, i32, i32)) -> Option<&'r mut Chunk>
+  {
+    if self.paged.contains_key(&key)
+    {
+      let dir = self.page_dir.get_ref().clone();
+      if !self.page_in(dir, key)
+      { return None; }
+    }
+    if !self.chunks.contains_key(&key)
+    {
+      let (x, y, z) = key;
+      self.chunks.insert(key, Chunk::new(Vec3i::new(x, y, z)));
+    }
+    Some(self.chunks.get_mut(&key))
+  }
 
-    if self.wireframe
-    { check!(gl::polygon_mode(gl::FRONT_AND_BACK, gl::LINE)); }
+  /* The color of the cell, if it's solid; paged out chunks count as empty. */
+  pub fn get(&self, x: i32, y: i32, z: i32) -> Option<Vec3u8>
+  {
+    match self.chunks.find(&chunk_of(x, y, z))
+    {
+      Some(chunk) =>
+      {
+        match chunk.find(x, y, z)
+        {
+          Some(i) => { Some(chunk.voxels[i].color) }
+          None => { None }
+        }
+      }
+      None => { None }
+    }
+  }
 
-    check!(gl::draw_arrays_instanced(gl::TRIANGLE_STRIP, 0, 24, self.indices.len() as i32));
+  /* Edits at grid positions; only the edited instances are uploaded
+     again or, when drawing faces, the meshes of the owning chunk and
+     of neighbors whose exposed faces change. New voxels are in no
+     cluster, so they're drawn from anywhere. */
+  pub fn set(&mut self, x: i32, y: i32, z: i32, color: Vec3u8)
+  {
+    let mut touched = HashSet::new::<(i32, i32, i32)>();
+    self.put(x, y, z, color, &mut touched);
+    self.update_bounds(&touched);
+  }
 
-    if self.wireframe
-    { check!(gl::polygon_mode(gl::FRONT_AND_BACK, gl::FILL)); }
+  pub fn clear(&mut self, x: i32, y: i32, z: i32) -> bool
+  {
+    let mut touched = HashSet::new::<(i32, i32, i32)>();
+    let removed = self.remove(x, y, z, &mut touched).is_some();
+    self.update_bounds(&touched);
+    removed
+  }
 
-    check!(gl::disable_vertex_attrib_array(0));
-    check!(gl::disable_vertex_attrib_array(1));
-    check!(gl::disable_vertex_attrib_array(2));
-    check!(gl::bind_vertex_array(0));
-    check!(gl::bind_buffer(gl::ARRAY_BUFFER, 0));
+  /* The single cell edits leave the chunk bounds to update_bounds,
+     so that a whole shape only recomputes each chunk once. Cells in
+     chunks that can't be paged in aren't edited. */
+  priv fn put(&mut self, x: i32, y: i32, z: i32, color: Vec3u8, touched: &mut HashSet<(i32, i32, i32)>) -> bool
+  {
+    let key = chunk_of(x, y, z);
+    let added = match self.chunk_mut(key)
+    {
+      Some(chunk) =>
+      {
+        let added = chunk.find(x, y, z).is_none();
+        chunk.set(Vertex::new(Vec3i::new(x, y, z), color), -1);
+        added
+      }
+      None => { return false; }
+    };
+
+    touched.insert(key);
+    if added
+    { self.total_voxels += 1; }
+    self.touch_neighbors(x, y, z);
+    true
   }
 
-  priv fn voxelize(&mut self, tris: &[Triangle])
+  /* Clears the cell, giving back what was there. */
+  priv fn remove(&mut self, x: i32, y: i32, z: i32, touched: &mut HashSet<(i32, i32, i32)>) -> Option<Vertex>
   {
-    /* Require at least one triangle. */
-    assert!(tris.len() >= 1);
-    debug!("VOXEL: Incoming triangles: %?", tris.len());
+    /* Don't make chunks just to find them empty. */
+    let key = chunk_of(x, y, z);
+    if !self.chunks.contains_key(&key) && !self.paged.contains_key(&key)
+    { return None; }
 
-    /* Bounding box of vert dimensions. */
-    let mut min = Vec3f::new( tris[0].verts[0].position.x,
-                              tris[0].verts[0].position.y, 
-                              tris[0].verts[0].position.z);
-    let mut max = Vec3f::new( tris[0].verts[0].position.x,
-                              tris[0].verts[0].position.y,
-                              tris[0].verts[0].position.z);
-    for tris.each |curr|
+    let removed = match self.chunk_mut(key)
     {
-      for curr.verts.each |vert|
+      Some(chunk) =>
       {
-        min.x = cmp::min(min.x, vert.position.x);
-        min.y = cmp::min(min.y, vert.position.y);
-        min.z = cmp::min(min.z, vert.position.z);
+        match chunk.find(x, y, z)
+        {
+          Some(i) =>
+          {
+            let vert = chunk.voxels[i];
+            chunk.clear(x, y, z);
+            Some(vert)
+          }
+          None => { None }
+        }
+      }
+      None => { None }
+    };
 
-        max.x = cmp::max(max.x, vert.position.x);
-        max.y = cmp::max(max.y, vert.position.y);
-        max.z = cmp::max(max.z, vert.position.z);
+    if removed.is_some()
+    {
+      touched.insert(key);
+      self.total_voxels -= 1;
+      self.touch_neighbors(x, y, z);
+    }
+    removed
+  }
+
+  priv fn update_bounds(&mut self, touched: &HashSet<(i32, i32, i32)>)
+  {
+    let voxel_size = self.voxel_size;
+    for touched.iter().advance |key|
+    {
+      match self.chunks.find_mut(key)
+      {
+        Some(chunk) => { chunk.update_bounds(voxel_size); }
+        None => { }
+      }
+    }
+  }
+
+  /* Cells on a chunk's edge show, or hide, faces in the next chunk
+     over; instances don't care about their neighbors. */
+  priv fn touch_neighbors(&mut self, x: i32, y: i32, z: i32)
+  {
+    match self.mode
+    {
+      Draw_Instanced => { return; }
+      Draw_Faces | Draw_Greedy => { }
+    }
+
+    let key = chunk_of(x, y, z);
+    for [(1, 0, 0), (-1, 0, 0), (0, 1, 0), (0, -1, 0), (0, 0, 1), (0, 0, -1)].iter().advance |&(dx, dy, dz)|
+    {
+      let neighbor = chunk_of(x + dx, y + dy, z + dz);
+      if neighbor == key
+      { loop; }
+
+      match self.chunks.find_mut(&neighbor)
+      {
+        Some(chunk) => { chunk.dirty = true; }
+        None => { }
       }
     }
-    debug!("VOXEL: Min: %s Max: %s", min.to_str(), max.to_str());
-    let center = Vec3f::new(max.x - ((max.x - min.x) / 2.0),
-                            max.y - ((max.y - min.y) / 2.0),
-                            max.z - ((max.z - min.z) / 2.0));
-    debug!("VOXEL: Center of mesh is %s", center.to_str());
-
-    /* Calculate, given resolution (how many voxels across), the dimensions of a voxel. */
-    self.voxel_size = cmp::max( max.x - min.x,
-                                cmp::max(max.y - min.y, max.z - min.z)) / (self.resolution as f32);
-    debug!("VOXEL: Voxel size is %?", self.voxel_size);
-
-    /* Create 3D array of voxels. */
-    let mid_offset = (((self.resolution as f32) / 2.0) * self.voxel_size);
-    debug!("VOXEL: Midpoint offset is %?", mid_offset);
-
-    self.voxels = vec::with_capacity((f32::pow((self.resolution + 1) as f32, 3.0)) as uint);
-    self.indices = vec::with_capacity((f32::pow((self.resolution + 1) as f32, 3.0)) as uint);
-    for uint::range(0, self.resolution as uint) |_z| 
-    { for uint::range(0, self.resolution as uint) |_y|
-      { for uint::range(0, self.resolution as uint) |_x|
+  }
+
+  /* Destruction works in world space, on each cell whose center is
+     inside the shape; the removed voxels are handed back, for debris
+     and for sending over the network. */
+  pub fn carve_sphere(&mut self, center: Vec3f, radius: f32) -> ~[Vertex]
+  {
+    let (min, max) = cells_within(&sphere_bounds(center, radius), self.voxel_size);
+    let mut touched = HashSet::new::<(i32, i32, i32)>();
+    let mut removed = ~[];
+    for each_cell(min, max) |x, y, z|
+    {
+      if self.in_sphere(x, y, z, center, radius)
+      {
+        match self.remove(x, y, z, &mut touched)
         {
-          self.voxels.push(Default);
+          Some(vert) => { removed.push(vert); }
+          None => { }
         }
       }
     }
-    assert!(self.voxels.len() == (f32::pow((self.resolution) as f32, 3.0)) as uint);
+    self.update_bounds(&touched);
+    removed
+  }
 
-    for tris.each |tri|
+  pub fn carve_box(&mut self, bb: &BB3) -> ~[Vertex]
+  {
+    let (min, max) = cells_within(bb, self.voxel_size);
+    let mut touched = HashSet::new::<(i32, i32, i32)>();
+    let mut removed = ~[];
+    for each_cell(min, max) |x, y, z|
     {
-      /* Calculate bounding box of the triangle. */
-      min = Vec3f::new(tri.verts[0].position.x, tri.verts[0].position.y, tri.verts[0].position.z);
-      max = Vec3f::new(tri.verts[0].position.x, tri.verts[0].position.y, tri.verts[0].position.z);
-      for tri.verts.each |vert|
+      match self.remove(x, y, z, &mut touched)
       {
-        min.x = cmp::min(min.x, vert.position.x);
-        min.y = cmp::min(min.y, vert.position.y);
-        min.z = cmp::min(min.z, vert.position.z);
-
-        max.x = cmp::max(max.x, vert.position.x);
-        max.y = cmp::max(max.y, vert.position.y);
-        max.z = cmp::max(max.z, vert.position.z);
+        Some(vert) => { removed.push(vert); }
+        None => { }
       }
+    }
+    self.update_bounds(&touched);
+    removed
+  }
 
-      /* Determine what voxels lie in the bounding box. */
-      let mut vox_amount = Vec3i::new(f32::ceil(((max.x - min.x) / self.voxel_size)) as i32,
-                                      f32::ceil(((max.y - min.y) / self.voxel_size)) as i32,
-                                      f32::ceil(((max.z - min.z) / self.voxel_size)) as i32);
-      if vox_amount.x < 1
-      { vox_amount.x = 1; }
-      if vox_amount.y < 1
-      { vox_amount.y = 1; }
-      if vox_amount.z < 1
-      { vox_amount.z = 1; }
-      //debug!("VOXEL: [Per voxel] Checking %s surrounding voxels with SAT", vox_amount.to_str());
-
-      let start_indices = Vec3i::new( ((min.x - -mid_offset) / self.voxel_size) as i32, 
-                                      ((min.y - -mid_offset) / self.voxel_size) as i32,
-                                      ((min.z - -mid_offset) / self.voxel_size) as i32);
-      //debug!("VOXEL: [Per voxel] Starting indices are %s", start_indices.to_str());
-
-      /* Test intersection with each accepted voxel. */
-      /* TODO: Better loop syntax. */
-      let mut z = start_indices.z;
-      'collision: loop
+  /* Tunnels through every voxel the ray passes through, up to length. */
+  pub fn carve_ray(&mut self, origin: Vec3f, direction: Vec3f, length: f32) -> ~[Vertex]
+  {
+    let mut touched = HashSet::new::<(i32, i32, i32)>();
+    let mut removed = ~[];
+    for each_ray_cell(origin, direction, length, self.voxel_size) |x, y, z|
+    {
+      match self.remove(x, y, z, &mut touched)
       {
-        if z == start_indices.z + vox_amount.z
-        { break; }
+        Some(vert) => { removed.push(vert); }
+        None => { }
+      }
+    }
+    self.update_bounds(&touched);
+    removed
+  }
 
-        let mut y = start_indices.y;
-        loop
+  /* The counterparts of carving; only empty cells are filled, and
+     those are handed back. */
+  pub fn fill_sphere(&mut self, center: Vec3f, radius: f32, color: Vec3u8) -> ~[Vertex]
+  {
+    let (min, max) = cells_within(&sphere_bounds(center, radius), self.voxel_size);
+    let mut touched = HashSet::new::<(i32, i32, i32)>();
+    let mut added = ~[];
+    for each_cell(min, max) |x, y, z|
+    {
+      if self.in_sphere(x, y, z, center, radius) && self.fill(x, y, z, color, &mut touched)
+      { added.push(Vertex::new(Vec3i::new(x, y, z), color)); }
+    }
+    self.update_bounds(&touched);
+    added
+  }
+
+  pub fn fill_box(&mut self, bb: &BB3, color: Vec3u8) -> ~[Vertex]
+  {
+    let (min, max) = cells_within(bb, self.voxel_size);
+    let mut touched = HashSet::new::<(i32, i32, i32)>();
+    let mut added = ~[];
+    for each_cell(min, max) |x, y, z|
+    {
+      if self.fill(x, y, z, color, &mut touched)
+      { added.push(Vertex::new(Vec3i::new(x, y, z), color)); }
+    }
+    self.update_bounds(&touched);
+    added
+  }
+
+  pub fn fill_ray(&mut self, origin: Vec3f, direction: Vec3f, length: f32, color: Vec3u8) -> ~[Vertex]
+  {
+    let mut touched = HashSet::new::<(i32, i32, i32)>();
+    let mut added = ~[];
+    for each_ray_cell(origin, direction, length, self.voxel_size) |x, y, z|
+    {
+      if self.fill(x, y, z, color, &mut touched)
+      { added.push(Vertex::new(Vec3i::new(x, y, z), color)); }
+    }
+    self.update_bounds(&touched);
+    added
+  }
+
+  priv fn fill(&mut self, x: i32, y: i32, z: i32, color: Vec3u8, touched: &mut HashSet<(i32, i32, i32)>) -> bool
+  {
+    /* Paged in first, so get() sees what's there. */
+    let key = chunk_of(x, y, z);
+    if self.paged.contains_key(&key) && self.chunk_mut(key).is_none()
+    { return false; }
+    if self.get(x, y, z).is_some()
+    { return false; }
+
+    self.put(x, y, z, color, touched)
+  }
+
+  #[inline(always)]
+  priv fn in_sphere(&self, x: i32, y: i32, z: i32, center: Vec3f, radius: f32) -> bool
+  { (voxel_center(Vec3i::new(x, y, z), self.voxel_size) - center).length() <= radius }
+
+  #[inline(always)]
+  pub fn resident_chunks(&self) -> uint
+  { self.chunks.len() }
+
+  #[inline(always)]
+  pub fn paged_chunks(&self) -> uint
+  { self.paged.len() }
+
+  /* Picks the chunks to draw: those in front of the camera with
+     voxels in a visible cluster. Voxels in solid space (negative
+     clusters) are always drawn, since they sit on walls. Chunks
+     that made it are uploaded, if they've changed. */
+  pub fn cull(&mut self, position: Vec3f, forward: Vec3f, visible: &fn(i32) -> bool)
+  {
+    self.visible = ~[];
+    for self.chunks.iter().advance |(&key, chunk)|
+    {
+      if !chunk.in_front(position, &forward)
+      { loop; }
+      if !chunk.clusters.iter().any(|&c| c < 0 || visible(c))
+      { loop; }
+
+      self.visible.push(key);
+    }
+
+    self.drawn_voxels = 0;
+    self.drawn_faces = 0;
+    self.drawn_tris = 0;
+    for uint::range(0, self.visible.len()) |i|
+    {
+      let key = self.visible[i];
+      match self.mode
+      {
+        Draw_Instanced =>
         {
-          if y == start_indices.y + vox_amount.y
-          { break; }
+          let chunk = self.chunks.get_mut(&key);
+          chunk.upload();
 
-          let mut x = start_indices.x;
-          loop
+          /* The strip is six quads, with degenerates in between. */
+          self.drawn_faces += chunk.voxels.len() * 6;
+          self.drawn_tris += chunk.voxels.len() * 12;
+        }
+        Draw_Faces | Draw_Greedy =>
+        {
+          /* Meshing looks into the neighboring chunks, so it's
+             built before the chunk is borrowed for the upload. */
+          if self.chunks.get(&key).is_stale()
           {
-            if x == start_indices.x + vox_amount.x
-            { break; }
-
-            /* Check for intersection. */
-            let c = Vec3f::new( ((x as f32 - (self.resolution as f32 / 2.0)) * self.voxel_size) + (self.voxel_size / 2.0), 
-                                ((y as f32 - (self.resolution as f32 / 2.0)) * self.voxel_size) + (self.voxel_size / 2.0),
-                                ((z as f32 - (self.resolution as f32 / 2.0)) * self.voxel_size) + (self.voxel_size / 2.0));
-            if tri_cube_intersect(c, self.voxel_size, tri)
+            let greedy = match self.mode { Draw_Greedy => true, _ => false };
+            let mesh =
             {
-              /* We have intersection; add a reference to this voxel to the index map. */
-              self.indices.push(Vertex
-              {
-                position: Vec3i::new( x - (self.resolution / 2) as i32, /* TODO: Remove duplicates. */
-                                      y - (self.resolution / 2) as i32,
-                                      z - (self.resolution / 2) as i32), 
-                color: Vec3u8::new(tri.verts[0].color.x as u8, tri.verts[0].color.y as u8, tri.verts[0].color.z as u8), /* TODO: Conversion between Vec types. */
-                unused: 0,
-              });
-            }
-            
-            x += 1;
+              let chunk = self.chunks.get(&key);
+              mesher::build(chunk.voxels, self.voxel_size, greedy, |x, y, z| self.is_solid(x, y, z))
+            };
+            self.chunks.get_mut(&key).upload_mesh(&mesh);
           }
-          y += 1;
+
+          let chunk = self.chunks.get(&key);
+          self.drawn_faces += chunk.mesh_faces;
+          self.drawn_tris += chunk.mesh_verts / 3;
         }
-        z += 1;
       }
+      self.drawn_voxels += self.chunks.get(&key).voxels.len();
     }
-    debug!("VOXEL: Enabled %? of %? voxels", self.indices.len(), self.voxels.len());
   }
-}
-
-macro_rules! find_min_max
-(
-  ($x0:expr, $x1:expr, $x2:expr) =>
-  (
-    {
-      _min = $x0;
-      _max = $x0;
-
-      if($x1 < _min){ _min = $x1; }
-      if($x1 > _max){ _max = $x1; }
-      if($x2 < _min){ _min = $x2; }
-      if($x2 > _max){ _max = $x2; }
-    }
-  )
-)
 
-/*======================== X-tests ========================*/
-macro_rules! axis_test_x01
-(
-  ($a:expr, $b:expr, $fa:expr, $fb:expr) =>
-  (
+  /* Instanced cubes go through the voxel shader; face meshes are
+     plain colored triangles, for the color shader. */
+  pub fn draw(&self)
+  {
+    match self.mode
     {
-      _p0 = $a * _v0.y - $b * _v0.z;
-      _p2 = $a * _v2.y - $b * _v2.z;
-      if _p0 < _p2  { _min = _p0; _max = _p2; } else { _min = _p2; _max = _p0; }
-      _rad = $fa * box_size + $fb * box_size;
-      if _min > _rad || _max < -_rad  { return false; }
+      Draw_Instanced => { self.draw_instances(); }
+      Draw_Faces | Draw_Greedy => { self.draw_meshes(); }
     }
-  )
-)
+  }
 
-macro_rules! axis_test_x2
-(
-  ($a:expr, $b:expr, $fa:expr, $fb:expr) =>
-  (
-    {
-      _p0 = $a * _v0.y - $b * _v0.z;
-      _p1 = $a * _v1.y - $b * _v1.z;
-      if _p0 < _p1 { _min = _p0; _max = _p1; } else { _min = _p1; _max = _p0; }
-      _rad = $fa * box_size + $fb * box_size;
-      if _min > _rad || _max < -_rad { return false; }
-    }
-  )
-)
+  priv fn draw_instances(&self)
+  {
+    check!(gl::bind_vertex_array(self.vao));
+
+    check!(gl::bind_buffer(gl::ARRAY_BUFFER, self.vbo));
+    check!(gl::vertex_attrib_pointer_f32(0, 3, false, 0, 0));
+    check!(gl::enable_vertex_attrib_array(0));
+
+    check!(gl::enable_vertex_attrib_array(1));
+    check!(gl::vertex_attrib_divisor(1, 1));
+    check!(gl::enable_vertex_attrib_array(2));
+    check!(gl::vertex_attrib_divisor(2, 1));
 
-/*======================== Y-tests ========================*/
+    if self.wireframe
+    { check!(gl::polygon_mode(gl::FRONT_AND_BACK, gl::LINE)); }
 
-macro_rules! axis_test_y02
-(
-  ($a:expr, $b:expr, $fa:expr, $fb:expr) =>
-  (
+    for self.visible.iter().advance |key|
     {
-      _p0 = -$a * _v0.x + $b * _v0.z;
-      _p2 = -$a * _v2.x + $b * _v2.z;
-      if _p0 < _p2 { _min = _p0; _max = _p2; } else { _min = _p2; _max = _p0; }
-      _rad = $fa * box_size + $fb * box_size;
-      if _min > _rad || _max < -_rad { return false; }
+      match self.chunks.find(key)
+      {
+        Some(chunk) => { chunk.draw_instances(); }
+        None => { }
+      }
     }
-  )
-)
 
-macro_rules! axis_test_y1
-(
-  ($a:expr, $b:expr, $fa:expr, $fb:expr) =>
-  (
+    if self.wireframe
+    { check!(gl::polygon_mode(gl::FRONT_AND_BACK, gl::FILL)); }
+
+    check!(gl::disable_vertex_attrib_array(0));
+    check!(gl::disable_vertex_attrib_array(1));
+    check!(gl::disable_vertex_attrib_array(2));
+    check!(gl::bind_vertex_array(0));
+    check!(gl::bind_buffer(gl::ARRAY_BUFFER, 0));
+  }
+
+  priv fn draw_meshes(&self)
+  {
+    check!(gl::bind_vertex_array(self.mesh_vao));
+    check!(gl::enable_vertex_attrib_array(0));
+    check!(gl::enable_vertex_attrib_array(1));
+
+    if self.wireframe
+    { check!(gl::polygon_mode(gl::FRONT_AND_BACK, gl::LINE)); }
+
+    for self.visible.iter().advance |key|
     {
-      _p0 = -$a * _v0.x + $b * _v0.z;
-      _p1 = -$a * _v1.x + $b * _v1.z;
-      if _p0 < _p1 { _min = _p0; _max = _p1; } else { _min = _p1; _max = _p0; }
-      _rad = $fa * box_size + $fb * box_size;
-      if _min > _rad || _max < -_rad { return false; }
+      match self.chunks.find(key)
+      {
+        Some(chunk) => { chunk.draw_mesh(); }
+        None => { }
+      }
     }
-  )
-)
 
-/*======================== Z-tests ========================*/
+    if self.wireframe
+    { check!(gl::polygon_mode(gl::FRONT_AND_BACK, gl::FILL)); }
 
-macro_rules! axis_test_z12
-(
-  ($a:expr, $b:expr, $fa:expr, $fb:expr) =>
-  (
+    check!(gl::disable_vertex_attrib_array(0));
+    check!(gl::disable_vertex_attrib_array(1));
+    check!(gl::bind_vertex_array(0));
+    check!(gl::bind_buffer(gl::ARRAY_BUFFER, 0));
+  }
+
+  /* Every voxel, paged out or not, in one grid; for the exporters. */
+  pub fn to_grid(&self) -> Voxel_Grid
+  {
+    let mut grid = Voxel_Grid::empty(self.resolution, self.voxel_size);
+    for self.chunks.iter().advance |(_, chunk)|
     {
-      _p1 = $a * _v1.x - $b * _v1.y;
-      _p2 = $a * _v2.x - $b * _v2.y;
-      if _p2 < _p1 { _min = _p2; _max = _p1;} else { _min = _p1; _max = _p2; }
-      _rad = $fa * box_size + $fb * box_size;
-      if _min > _rad || _max < -_rad { return false; }
+      for chunk.voxels.iter().advance |vert|
+      { grid.set(vert.position.x, vert.position.y, vert.position.z, vert.color); }
     }
-  )
-)
 
-
-macro_rules! axis_test_z0
-(
-  ($a:expr, $b:expr, $fa:expr, $fb:expr) =>
-  (
+    match self.page_dir
     {
-      _p0 = $a * _v0.x - $b * _v0.y;
-      _p1 = $a * _v1.x - $b * _v1.y;
-      if _p0 < _p1 { _min = _p0; _max = _p1; } else { _min = _p1; _max = _p0; }
-      _rad = $fa * box_size + $fb * box_size;
-      if _min > _rad || _max < -_rad { return false; }
-    }
-  )
-)
+      Some(ref dir) =>
+      {
+        for self.paged.iter().advance |(&key, _)|
+        {
+          match Chunk::read(page_file(dir.as_slice(), key))
+          {
+            Ok(chunk) =>
+            {
+              for chunk.voxels.iter().advance |vert|
+              { grid.set(vert.position.x, vert.position.y, vert.position.z, vert.color); }
+            }
+            Err(err) => { error!("VOXEL: Failed to read paged chunk: %s", err); }
+          }
+        }
+      }
+      None => { }
+    }
+
+    grid
+  }
+}
 
 #[inline(always)]
-priv fn tri_cube_intersect(box_center: Vec3f, box_size: f32, tri: &Triangle) -> bool
+priv fn page_file(dir: &str, key: (i32, i32, i32)) -> ~str
 {
-  let _v0;
-  let _v1;
-  let _v2;
-  let mut _min;
-  let mut _max;
-  let mut _p0 = 0.0;
-  let mut _p1 = 0.0;
-  let mut _p2 = 0.0;
-  let mut _rad;
-  let mut _fex;
-  let mut _fey;
-  let mut _fez;
-  let _normal;
-  let _e0;
-  let _e1;
-  let _e2;
-
-  /* Move everything so that the box's center is in (0, 0, 0). */
-  _v0 = tri.verts[0].position - box_center;
-  _v1 = tri.verts[1].position - box_center;
-  _v2 = tri.verts[2].position - box_center;
-
-  /* Computer triangle edges. */
-  _e0 = _v1 - _v0; /* Edge 0. */
-  _e1 = _v2 - _v1; /* Edge 1. */
-  _e2 = _v0 - _v2; /* Edge 2. */
-
-  //debug!("VOXEL: [Per voxel SAT] Testing bullet 3 edge 0");
-  /* Bullet 3. */
-  _fex = f32::abs(_e0.x);
-  _fey = f32::abs(_e0.y);
-  _fez = f32::abs(_e0.z);
-  axis_test_x01!(_e0.z, _e0.y, _fez, _fey);
-  axis_test_y02!(_e0.z, _e0.x, _fez, _fex);
-  axis_test_z12!(_e0.y, _e0.x, _fey, _fex);
-
-  //debug!("VOXEL: [Per voxel SAT] Testing bullet 3 edge 1");
-  _fex = f32::abs(_e1.x);
-  _fey = f32::abs(_e1.y);
-  _fez = f32::abs(_e1.z);
-  axis_test_x01!(_e1.z, _e1.y, _fez, _fey);
-  axis_test_y02!(_e1.z, _e1.x, _fez, _fex);
-  axis_test_z0!(_e1.y, _e1.x, _fey, _fex);
-
-  //debug!("VOXEL: [Per voxel SAT] Testing bullet 3 edge 2");
-  _fex = f32::abs(_e2.x);
-  _fey = f32::abs(_e2.y);
-  _fez = f32::abs(_e2.z);
-  axis_test_x2!(_e2.z, _e2.y, _fez, _fey);
-  axis_test_y1!(_e2.z, _e2.x, _fez, _fex);
-  axis_test_z12!(_e2.y, _e2.x, _fey, _fex);
-
-  //debug!("VOXEL: [Per voxel SAT] Testing bullet 1");
-  /* Bullet 1. */
-  /* Test in X-direction */
-  find_min_max!(_v0.x, _v1.x, _v2.x);
-  if _min > box_size || _max < -box_size { return false; }
-
-  /* Test in Y-direction */
-  find_min_max!(_v0.y, _v1.y, _v2.y);
-  if _min > box_size || _max < -box_size { return false; }
-
-  /* Test in Z-direction */
-  find_min_max!(_v0.z, _v1.z, _v2.z);
-  if _min > box_size || _max < -box_size { return false; }
-
-  //debug!("VOXEL: [Per voxel SAT] Testing bullet 2");
-  /* Bullet 2. */
-  _normal = _e0.cross(&_e1);
-  plane_cube_intersect(&_normal, &_v0, box_size)
+  let (x, y, z) = key;
+  fmt!("%s/%d_%d_%d.chunk", dir, x as int, y as int, z as int)
+}
+
+/* The first and last cells with their centers in the bounds. */
+pub fn cells_within(bb: &BB3, voxel_size: f32) -> (Vec3i, Vec3i)
+{
+  let (min, max) = (bb.min(), bb.max());
+  (Vec3i::new(f32::ceil(min.x / voxel_size) as i32,
+              f32::ceil(min.y / voxel_size) as i32,
+              f32::ceil(min.z / voxel_size) as i32),
+   Vec3i::new(f32::floor(max.x / voxel_size) as i32,
+              f32::floor(max.y / voxel_size) as i32,
+              f32::floor(max.z / voxel_size) as i32))
 }
 
 #[inline(always)]
-priv fn plane_cube_intersect(normal: &Vec3f, vert: &Vec3f, box_size: f32) -> bool
+priv fn sphere_bounds(center: Vec3f, radius: f32) -> BB3
+{ BB3::new(center - Vec3f::new(radius, radius, radius), center + Vec3f::new(radius, radius, radius)) }
+
+priv fn each_cell(min: Vec3i, max: Vec3i, f: &fn(i32, i32, i32) -> bool) -> bool
 {
-  let mut vmin: [f32, ..3] = [0.0, 0.0, 0.0];
-  let mut vmax: [f32, ..3] = [0.0, 0.0, 0.0];
-  let mut v = 0.0;
+  for i32::range(min.x, max.x + 1) |x|
+  {
+    for i32::range(min.y, max.y + 1) |y|
+    {
+      for i32::range(min.z, max.z + 1) |z|
+      {
+        if !f(x, y, z)
+        { return false; }
+      }
+    }
+  }
+  true
+}
 
-  for uint::range(0, 3) |q|
+/* Walks the cells along the ray in order, one face crossing at a
+   time (Amanatides and Woo); the origin's cell comes first. */
+pub fn each_ray_cell(origin: Vec3f, direction: Vec3f, length: f32, voxel_size: f32,
+                     f: &fn(i32, i32, i32) -> bool) -> bool
+{
+  let mut dir = direction;
+  if dir.length() == 0.0
+  { return true; }
+  dir.normalize();
+
+  /* In cells, where cell n spans n - 0.5 to n + 0.5. */
+  let start = [origin.x / voxel_size + 0.5, origin.y / voxel_size + 0.5, origin.z / voxel_size + 0.5];
+  let dir = [dir.x, dir.y, dir.z];
+  let length = length / voxel_size;
+
+  let mut cell = [0i32, ..3];
+  let mut step = [0i32, ..3];
+  let mut next = [f32::infinity, ..3]; /* Distance to the next crossing, per axis. */
+  let mut delta = [f32::infinity, ..3]; /* Distance between crossings, per axis. */
+  for uint::range(0, 3) |i|
   {
-    v = vert[q];
-    if normal[q] > 0.0
+    cell[i] = f32::floor(start[i]) as i32;
+    if dir[i] > 0.0
     {
-      vmin[q] = -box_size - v;
-      vmax[q] = box_size - v;
+      step[i] = 1;
+      next[i] = ((cell[i] + 1) as f32 - start[i]) / dir[i];
+      delta[i] = 1.0 / dir[i];
     }
-    else
+    else if dir[i] < 0.0
     {
-      vmin[q] = box_size - v;
-      vmax[q] = -box_size - v;
+      step[i] = -1;
+      next[i] = (cell[i] as f32 - start[i]) / dir[i];
+      delta[i] = -1.0 / dir[i];
     }
   }
-  if (normal[0]*vmin[0]+normal[1]*vmin[1]+normal[2]*vmin[2]) > 0.0 { return false; }
-  if (normal[0]*vmax[0]+normal[1]*vmax[1]+normal[2]*vmax[2]) >= 0.0 { return true; }
 
-  false
+  let mut travelled = 0.0;
+  while travelled <= length
+  {
+    if !f(cell[0], cell[1], cell[2])
+    { return false; }
+
+    let mut axis = 0;
+    if next[1] < next[axis] { axis = 1; }
+    if next[2] < next[axis] { axis = 2; }
+
+    travelled = next[axis];
+    cell[axis] += step[axis];
+    next[axis] += delta[axis];
+  }
+  true
 }
 
+#[cfg(test)]
+mod test
+{
+  use std::{ uint, i32, f32 };
+  use math::{ Vec3f, Vec3i, BB3 };
+  use super::{ cells_within, each_ray_cell };
+  use super::super::grid::voxel_center;
+
+  fn ray(origin: Vec3f, direction: Vec3f, length: f32) -> ~[(i32, i32, i32)]
+  {
+    let mut cells = ~[];
+    for each_ray_cell(origin, direction, length, 1.0) |x, y, z|
+    { cells.push((x, y, z)); }
+    cells
+  }
+
+  #[test]
+  fn ray_axis()
+  {
+    /* Cell n spans n - 0.5 to n + 0.5, so x = 3 is still in cell 3. */
+    assert_eq!(ray(Vec3f::zero(), Vec3f::new(2.0, 0.0, 0.0), 3.0), ~[(0, 0, 0), (1, 0, 0), (2, 0, 0), (3, 0, 0)]);
+    assert_eq!(ray(Vec3f::zero(), Vec3f::new(-1.0, 0.0, 0.0), 2.0), ~[(0, 0, 0), (-1, 0, 0), (-2, 0, 0)]);
+    assert_eq!(ray(Vec3f::zero(), Vec3f::zero(), 2.0), ~[]);
+  }
+
+  #[test]
+  fn ray_diagonal()
+  {
+    /* No cell is skipped; each step crosses one face. */
+    let cells = ray(Vec3f::new(0.2, -0.3, 0.1), Vec3f::new(1.0, 2.0, -0.5), 10.0);
+    assert_eq!(cells[0], (0, 0, 0));
+    for uint::range(1, cells.len()) |i|
+    {
+      let ((ax, ay, az), (bx, by, bz)) = (cells[i - 1], cells[i]);
+      assert_eq!(i32::abs(bx - ax) + i32::abs(by - ay) + i32::abs(bz - az), 1);
+      assert!(bx >= ax && by >= ay && bz <= az);
+    }
+    let (x, y, z) = cells[cells.len() - 1];
+    assert!(x > 0 && y > 3 && z < 0);
+  }
+
+  #[test]
+  fn within()
+  {
+    let (min, max) = cells_within(&BB3::new(Vec3f::new(-1.2, -0.5, 0.0), Vec3f::new(2.0, 0.9, 0.0)), 1.0);
+    assert_eq!((min.x, min.y, min.z), (-1, 0, 0));
+    assert_eq!((max.x, max.y, max.z), (2, 0, 0));
+
+    /* Twice the size, half the cells. */
+    let (min, max) = cells_within(&BB3::new(Vec3f::new(4.0, 4.0, 4.0), Vec3f::new(-4.0, -4.0, -3.0)), 2.0);
+    assert_eq!((min.x, min.y, min.z), (-2, -2, -1));
+    assert_eq!((max.x, max.y, max.z), (2, 2, 2));
+  }
+
+  #[test]
+  fn centers()
+  {
+    /* Edits pick the same centers that the grid partitions by. */
+    let size = 0.75;
+    let bb = BB3::new(Vec3f::new(-2.1, 0.3, -0.4), Vec3f::new(1.6, 2.2, 0.4));
+    let (min, max) = cells_within(&bb, size);
+    let inside = |x: i32, y: i32, z: i32| -> bool
+    {
+      let c = voxel_center(Vec3i::new(x, y, z), size);
+      c.x >= bb.min().x && c.x <= bb.max().x &&
+      c.y >= bb.min().y && c.y <= bb.max().y &&
+      c.z >= bb.min().z && c.z <= bb.max().z
+    };
+
+    for i32::range(min.x - 1, max.x + 2) |x|
+    {
+      for i32::range(min.y - 1, max.y + 2) |y|
+      {
+        for i32::range(min.z - 1, max.z + 2) |z|
+        {
+          let within = x >= min.x && x <= max.x && y >= min.y && y <= max.y && z >= min.z && z <= max.z;
+          assert_eq!(inside(x, y, z), within);
+        }
+      }
+    }
+
+    /* A ray starts in the cell whose center is nearest. */
+    let origin = Vec3f::new(0.6, -0.2, 1.4);
+    let mut first = None;
+    for each_ray_cell(origin, Vec3f::new(1.0, 0.0, 0.0), 1.0, size) |x, y, z|
+    {
+      first = Some((x, y, z));
+      break;
+    }
+    let (x, y, z) = first.unwrap();
+    assert_eq!((x, y, z), (1, 0, 2));
+    assert!((voxel_center(Vec3i::new(x, y, z), size) - origin).length() <= size * 0.5 * f32::sqrt(3.0));
+  }
+}
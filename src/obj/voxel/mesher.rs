@@ -0,0 +1,260 @@
+/*
+    Copyright 2013 Jesse 'Jeaye' Wilkerson
+    See licensing in LICENSE file, or at:
+        http://www.opensource.org/licenses/BSD-3-Clause
+
+    File: obj/voxel/mesher.rs
+    Author: Jesse 'Jeaye' Wilkerson
+    Description:
+      Builds triangles for only the voxel faces
+      that border empty space, optionally merging
+      neighboring faces of the same color.
+*/
+
+use std::{ uint, i32, vec };
+use std::iterator::IteratorUtil;
+use std::hashmap::{ HashMap, HashSet };
+use extra::sort;
+use math::Vec3f;
+use primitive::Vertex_PC;
+use super::Vertex;
+
+pub struct Face_Mesh
+{
+  /* Triangles; six vertices per face. */
+  verts: ~[Vertex_PC],
+
+  /* Merged faces count once. */
+  faces: uint,
+}
+
+impl Face_Mesh
+{
+  #[inline(always)]
+  pub fn tris(&self) -> uint
+  { self.verts.len() / 3 }
+}
+
+/* Exposed faces of one side of the voxels, within one slice, by (u, v) cell. */
+struct Slice
+{
+  axis: uint,
+  sign: i32,
+  depth: i32,
+  faces: HashMap<(i32, i32), (u8, u8, u8)>,
+}
+
+/* Meshes the voxels, which are at grid positions, as in voxel::Map.
+   is_solid answers for cells outside of the voxels as well, so
+   faces against neighboring chunks can be left out. */
+pub fn build(voxels: &[Vertex], voxel_size: f32, greedy: bool,
+             is_solid: &fn(i32, i32, i32) -> bool) -> Face_Mesh
+{
+  /* Exposed faces, grouped by side and slice. */
+  let mut slices = HashMap::new::<(uint, i32, i32), Slice>();
+  for voxels.iter().advance |vert|
+  {
+    let pos = [vert.position.x, vert.position.y, vert.position.z];
+    for uint::range(0, 3) |axis|
+    {
+      for [-1i32, 1].iter().advance |&sign|
+      {
+        let mut neighbor = pos;
+        neighbor[axis] += sign;
+        if is_solid(neighbor[0], neighbor[1], neighbor[2])
+        { loop; }
+
+        let key = (axis, sign, pos[axis]);
+        if !slices.contains_key(&key)
+        {
+          slices.insert(key, Slice { axis: axis, sign: sign, depth: pos[axis],
+                                     faces: HashMap::new::<(i32, i32), (u8, u8, u8)>() });
+        }
+
+        let (u, v) = (pos[(axis + 1) % 3], pos[(axis + 2) % 3]);
+        slices.get_mut(&key).faces.insert((u, v), (vert.color.x, vert.color.y, vert.color.z));
+      }
+    }
+  }
+
+  let mut mesh = Face_Mesh { verts: ~[], faces: 0 };
+  for slices.iter().advance |(_, slice)|
+  { mesh_slice(slice, voxel_size, greedy, &mut mesh); }
+  mesh
+}
+
+/* Greedy merging grows each face along u as far as the color holds,
+   then along v for as long as whole rows match. */
+priv fn mesh_slice(slice: &Slice, voxel_size: f32, greedy: bool, mesh: &mut Face_Mesh)
+{
+  /* Row by row, so faces grow from their lowest corner. */
+  let mut cells = vec::with_capacity(slice.faces.len());
+  for slice.faces.iter().advance |(&cell, _)|
+  { cells.push(cell); }
+  sort::quick_sort(cells, |&(au, av), &(bu, bv)| av < bv || (av == bv && au <= bu));
+
+  let mut done = HashSet::new::<(i32, i32)>();
+  for cells.iter().advance |&(u, v)|
+  {
+    if done.contains(&(u, v))
+    { loop; }
+    let color = *slice.faces.get(&(u, v));
+    let (width, height) = if greedy { grow(slice, &done, u, v, color) }
+                          else { (1, 1) };
+
+    for i32::range(0, height) |j|
+    {
+      for i32::range(0, width) |i|
+      { done.insert((u + i, v + j)); }
+    }
+
+    add_face(slice, u, v, width, height, color, voxel_size, mesh);
+  }
+}
+
+priv fn grow(slice: &Slice, done: &HashSet<(i32, i32)>, u: i32, v: i32, color: (u8, u8, u8)) -> (i32, i32)
+{
+  let matches = |cu: i32, cv: i32| -> bool
+  {
+    !done.contains(&(cu, cv)) &&
+    match slice.faces.find(&(cu, cv))
+    {
+      Some(&c) => { c == color }
+      None => { false }
+    }
+  };
+
+  let mut width = 1;
+  while matches(u + width, v)
+  { width += 1; }
+
+  let mut height = 1;
+  loop
+  {
+    for i32::range(0, width) |i|
+    {
+      if !matches(u + i, v + height)
+      { return (width, height); }
+    }
+    height += 1;
+  }
+}
+
+/* Voxels are centered on their positions, so faces are half a voxel out. */
+priv fn add_face(slice: &Slice, u: i32, v: i32, width: i32, height: i32,
+                 color: (u8, u8, u8), voxel_size: f32, mesh: &mut Face_Mesh)
+{
+  let depth = ((slice.depth as f32) + (slice.sign as f32) * 0.5) * voxel_size;
+  let (u0, u1) = (((u as f32) - 0.5) * voxel_size, ((u + width) as f32 - 0.5) * voxel_size);
+  let (v0, v1) = (((v as f32) - 0.5) * voxel_size, ((v + height) as f32 - 0.5) * voxel_size);
+
+  /* u cross v points along the axis, so this is counter-clockwise
+     from the positive side; the negative side is wound the other way. */
+  let corners = if slice.sign > 0 { [(u0, v0), (u1, v0), (u1, v1), (u0, v1)] }
+                else { [(u0, v0), (u0, v1), (u1, v1), (u1, v0)] };
+
+  let (r, g, b) = color;
+  let color = Vec3f::new(r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0);
+  for [0u, 1, 2, 0, 2, 3].iter().advance |&i|
+  {
+    let (cu, cv) = corners[i];
+    let mut pos = [0.0f32, ..3];
+    pos[slice.axis] = depth;
+    pos[(slice.axis + 1) % 3] = cu;
+    pos[(slice.axis + 2) % 3] = cv;
+    mesh.verts.push(Vertex_PC::new(Vec3f::new(pos[0], pos[1], pos[2]), color));
+  }
+  mesh.faces += 1;
+}
+
+#[cfg(test)]
+mod test
+{
+  use std::i32;
+  use std::hashmap::HashSet;
+  use std::iterator::IteratorUtil;
+  use math::{ Vec3i, Vec3u8 };
+  use super::super::Vertex;
+  use super::{ build, Face_Mesh };
+
+  fn mesh(voxels: &[Vertex], greedy: bool) -> Face_Mesh
+  {
+    let mut cells = HashSet::new::<(i32, i32, i32)>();
+    for voxels.iter().advance |vert|
+    { cells.insert((vert.position.x, vert.position.y, vert.position.z)); }
+    build(voxels, 1.0, greedy, |x, y, z| cells.contains(&(x, y, z)))
+  }
+
+  fn block(size: i32, color: Vec3u8) -> ~[Vertex]
+  {
+    let mut voxels = ~[];
+    for i32::range(0, size) |x|
+    {
+      for i32::range(0, size) |y|
+      {
+        for i32::range(0, size) |z|
+        { voxels.push(Vertex::new(Vec3i::new(x, y, z), color)); }
+      }
+    }
+    voxels
+  }
+
+  #[test]
+  fn single()
+  {
+    let voxels = [Vertex::new(Vec3i::new(0, 0, 0), Vec3u8::new(255, 0, 0))];
+    let faces = mesh(voxels, false);
+    assert_eq!(faces.faces, 6);
+    assert_eq!(faces.tris(), 12);
+    assert_eq!(mesh(voxels, true).faces, 6);
+
+    /* Half a voxel out on each side. */
+    for faces.verts.iter().advance |vert|
+    {
+      assert!(vert.position.x == 0.5 || vert.position.x == -0.5);
+      assert!(vert.position.y == 0.5 || vert.position.y == -0.5);
+      assert!(vert.position.z == 0.5 || vert.position.z == -0.5);
+      assert_eq!(vert.color.x, 1.0);
+    }
+  }
+
+  #[test]
+  fn hidden()
+  {
+    /* Only the outside of the block; nothing inside it. */
+    let voxels = block(3, Vec3u8::new(10, 20, 30));
+    let faces = mesh(voxels, false);
+    assert_eq!(faces.faces, 6 * 9);
+    assert_eq!(faces.verts.len(), 6 * 9 * 6);
+
+    let greedy = mesh(voxels, true);
+    assert_eq!(greedy.faces, 6);
+    assert_eq!(greedy.tris(), 12);
+  }
+
+  #[test]
+  fn colors()
+  {
+    /* A row of two colors only merges within each color. */
+    let voxels =
+    [
+      Vertex::new(Vec3i::new(0, 0, 0), Vec3u8::new(255, 0, 0)),
+      Vertex::new(Vec3i::new(1, 0, 0), Vec3u8::new(255, 0, 0)),
+      Vertex::new(Vec3i::new(2, 0, 0), Vec3u8::new(0, 0, 255)),
+    ];
+    assert_eq!(mesh(voxels, false).faces, 14);
+
+    /* Red's four sides merge, as do blue's; the ends stay. */
+    assert_eq!(mesh(voxels, true).faces, 10);
+  }
+
+  #[test]
+  fn neighbors()
+  {
+    /* Faces against solid cells outside of the voxels are left out. */
+    let voxels = [Vertex::new(Vec3i::new(0, 0, 0), Vec3u8::new(1, 2, 3))];
+    let faces = build(voxels, 1.0, true, |x, _, _| x != 0);
+    assert_eq!(faces.faces, 4);
+  }
+}
+
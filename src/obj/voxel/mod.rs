@@ -9,17 +9,28 @@
       An aggregator of voxel-oriented items.
 */
 
-pub use self::map::Map;
+#[cfg(not(headless))]
+pub use self::map::{ Map, Draw_Mode, Draw_Instanced, Draw_Faces, Draw_Greedy };
+pub use self::grid::{ Voxel_Grid, Default_Workers };
+pub use self::octree::Octree;
 pub use self::vertex::Vertex;
 pub use self::triangle::{ Triangle, Triangle_Index };
 pub use self::voxel::Voxel;
-pub use self::chunk::Chunk;
+#[cfg(not(headless))]
+pub use self::chunk::{ Chunk, Chunk_Size };
+pub use self::mesher::Face_Mesh;
 pub use self::behavior::{ Behavior, Default };
 
+/* Both upload to GL; the inspector builds without them. */
+#[cfg(not(headless))]
 mod map;
+mod grid;
+mod octree;
 mod vertex;
 mod triangle;
 mod voxel;
+#[cfg(not(headless))]
 mod chunk;
+mod mesher;
 mod behavior;
 
@@ -0,0 +1,165 @@
+/*
+    Copyright 2013 Jesse 'Jeaye' Wilkerson
+    See licensing in LICENSE file, or at:
+        http://www.opensource.org/licenses/BSD-3-Clause
+
+    File: obj/voxel/octree.rs
+    Author: Jesse 'Jeaye' Wilkerson
+    Description:
+      An octree of triangles over the voxelizer's
+      cells, so only the triangles that reach a
+      cell are tested against it.
+*/
+
+use std::{ uint, vec, cmp };
+use std::iterator::IteratorUtil;
+use math::{ Vec3f, Vec3i };
+use primitive::Triangle;
+use super::grid::{ cell_center, tri_cube_intersect };
+
+/* Cells across at which nodes stop splitting. */
+static Leaf_Size: i32 = 8;
+
+/* Node boxes are grown a little, so float error in the node
+   tests never drops a triangle that a cell in it would accept. */
+pub static Node_Slack: f32 = 1.01;
+
+pub struct Node
+{
+  /* First cell and cells across; the node is always a cube. */
+  min: Vec3i,
+  size: i32,
+
+  children: ~[Node],
+
+  /* Indices of the triangles reaching the node; only leaves have them. */
+  tris: ~[uint],
+}
+
+pub struct Octree
+{
+  root: Node,
+
+  /* Triangle and node tests it took to build. */
+  tests: uint,
+}
+
+impl Octree
+{
+  /* Ranges are the first and one past the last cell each triangle
+     could touch, in the same (unshifted) indices as the voxelizer. */
+  pub fn new(tris: &[Triangle], ranges: &[(Vec3i, Vec3i)], resolution: u32, voxel_size: f32) -> Octree
+  {
+    assert!(tris.len() == ranges.len());
+
+    let mut min = Vec3i::new(0, 0, 0);
+    let mut max = Vec3i::new(0, 0, 0);
+    for ranges.iter().enumerate().advance |(i, &(start, end))|
+    {
+      if i == 0
+      { min = start; max = end; loop; }
+
+      min.x = cmp::min(min.x, start.x);
+      min.y = cmp::min(min.y, start.y);
+      min.z = cmp::min(min.z, start.z);
+
+      max.x = cmp::max(max.x, end.x);
+      max.y = cmp::max(max.y, end.y);
+      max.z = cmp::max(max.z, end.z);
+    }
+
+    /* Power of two, so every split is even. */
+    let extent = cmp::max(max.x - min.x, cmp::max(max.y - min.y, max.z - min.z));
+    let mut size = 1;
+    while size < extent
+    { size *= 2; }
+
+    let all = vec::from_fn(tris.len(), |t| t);
+    let mut tests = 0;
+    let root = build(tris, ranges, all, min, size, resolution, voxel_size, &mut tests);
+    Octree
+    {
+      root: root,
+      tests: tests,
+    }
+  }
+
+  pub fn each_leaf(&self, f: &fn(&Node) -> bool) -> bool
+  { self.root.each_leaf(f) }
+
+  pub fn leaf_count(&self) -> uint
+  {
+    let mut count = 0;
+    for self.each_leaf |_|
+    { count += 1; }
+    count
+  }
+}
+
+impl Node
+{
+  pub fn each_leaf(&self, f: &fn(&Node) -> bool) -> bool
+  {
+    if self.children.len() == 0
+    { return f(self); }
+
+    for self.children.iter().advance |child|
+    {
+      if !child.each_leaf(|n| f(n))
+      { return false; }
+    }
+    true
+  }
+}
+
+priv fn build(tris: &[Triangle], ranges: &[(Vec3i, Vec3i)], indices: ~[uint],
+              min: Vec3i, size: i32, resolution: u32, voxel_size: f32, tests: &mut uint) -> Node
+{
+  if size <= Leaf_Size
+  { return Node { min: min, size: size, children: ~[], tris: indices }; }
+
+  let half = size / 2;
+  let mut children = ~[];
+  for uint::range(0, 8) |octant|
+  {
+    let child_min = Vec3i::new( min.x + (if octant & 1 != 0 { half } else { 0 }),
+                                min.y + (if octant & 2 != 0 { half } else { 0 }),
+                                min.z + (if octant & 4 != 0 { half } else { 0 }));
+
+    /* A cell's test box reaches one cell out from its center (see
+       tri_cube_intersect), so the child's box is grown to cover its
+       cells' boxes: half a cell past each side. */
+    let first = cell_center(child_min.x, child_min.y, child_min.z, resolution, voxel_size);
+    let offset = ((half - 1) as f32) * voxel_size / 2.0;
+    let center = first + Vec3f::new(offset, offset, offset);
+    let box_size = ((half + 1) as f32) / 2.0 * voxel_size * Node_Slack;
+
+    let mut reaching = ~[];
+    for indices.iter().advance |&t|
+    {
+      let (start, end) = ranges[t];
+      if start.x >= child_min.x + half || end.x <= child_min.x ||
+         start.y >= child_min.y + half || end.y <= child_min.y ||
+         start.z >= child_min.z + half || end.z <= child_min.z
+      { loop; }
+
+      /* Most triangles are small enough to sit entirely within
+         the child, which needs no test. */
+      let inside = start.x >= child_min.x && end.x <= child_min.x + half &&
+                   start.y >= child_min.y && end.y <= child_min.y + half &&
+                   start.z >= child_min.z && end.z <= child_min.z + half;
+      if inside
+      { reaching.push(t); loop; }
+
+      *tests += 1;
+      if tri_cube_intersect(center, box_size, &tris[t])
+      { reaching.push(t); }
+    }
+
+    if reaching.len() > 0
+    { children.push(build(tris, ranges, reaching, child_min, half, resolution, voxel_size, tests)); }
+  }
+
+  Node { min: min, size: size, children: children, tris: ~[] }
+}
+
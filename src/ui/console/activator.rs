@@ -163,6 +163,8 @@ impl Console_Activator
   { self.accessors.insert(name.to_owned(), accessor); }
   pub fn add_mutator(&mut self, name: &str, mutator: Property_Mutator)
   { self.mutators.insert(name.to_owned(), mutator); }
+  pub fn add_function(&mut self, name: &str, function: Function)
+  { self.functions.insert(name.to_owned(), function); }
   pub fn add_log(&self, text: &str)
   { self.console.body = self.console.body + "\n" + text; }
 }
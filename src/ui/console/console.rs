@@ -35,15 +35,15 @@ impl Console
   {
     let c = Console
     {
-      tex_left: Texture::new(gl::TEXTURE_2D, "data/img/console/left.png"),
-      tex_right: Texture::new(gl::TEXTURE_2D, "data/img/console/right.png"),
-      tex_middle: Texture::new(gl::TEXTURE_2D, "data/img/console/middle.png"),
+      tex_left: Texture::new(gl::TEXTURE_2D, "img/console/left.png"),
+      tex_right: Texture::new(gl::TEXTURE_2D, "img/console/right.png"),
+      tex_middle: Texture::new(gl::TEXTURE_2D, "img/console/middle.png"),
 
       position: Vec2f::zero(),
       velocity: 0.01,
 
       /* Text. */
-      font: Font::new("data/fonts/test.ttf", 16),
+      font: Font::new("fonts/test.ttf", 16),
       body: ~"Welcome to Q^3\nVersion: 0.1",
       prefix: ~"> ",
       input: ~"", 
@@ -54,7 +54,7 @@ impl Renderer
       vao: 0,
       vbo: 0,
 
-      shader: Shader_Builder::new_with_files("data/shaders/ui.vert", "data/shaders/ui.frag"),
+      shader: Shader_Builder::new_with_files("shaders/ui.vert", "shaders/ui.frag"),
       world: Mat4x4::new(),
       tex_world: Mat4x4::new(),
 
@@ -9,11 +9,12 @@
       TrueType font atlas.
 */
 
-use std::{ str, vec, cmp, ptr };
+use std::{ vec, cmp, ptr };
 use std::hashmap::HashMap;
-use std::libc::{ c_uint };
+use std::libc::{ c_uint, c_long };
 use std::iterator::IteratorUtil;
 use math::*;
+use vfs::Vfs;
 use self::glyph::Glyph;
 
 #[path = "../../gl/mod.rs"]
@@ -31,6 +32,8 @@ mod ft;
 struct Font
 {
   file: ~str,
+  /* Freetype reads from this for as long as the face lives. */
+  data: ~[u8],
   library: ft::Library,
   face: ft::Face,
   texture_atlas: gl::GLuint,
@@ -46,6 +49,11 @@ impl Font
     let mut font = Font
     {
       file: filename.to_owned(),
+      data: match Vfs::get().read(filename)
+      {
+        Some(data) => { data }
+        None => { fail!(fmt!("Failed to read font %s", filename)) }
+      },
       library: ptr::null(),
       face: ptr::null(),
       texture_atlas: 0,
@@ -58,11 +66,9 @@ impl Font
     {
       ft::FT_Init_FreeType(&font.library);
 
-      do str::as_c_str(filename) |c_str|
-      {
-        if ft::FT_New_Face(font.library, c_str, 0, &font.face) != 0
-        { fail!(~"Failed to create TTF face."); }
-      }
+      if ft::FT_New_Memory_Face(font.library, vec::raw::to_ptr(font.data),
+                                font.data.len() as c_long, 0, &font.face) != 0
+      { fail!(~"Failed to create TTF face."); }
    
       ft::FT_Set_Pixel_Sizes(font.face, 0, size as c_uint);
       check!(gl::active_texture(gl::TEXTURE0));
@@ -39,7 +39,7 @@ impl Renderer
     {
         vao: 0,
         vbo: 0,
-        shader: Shader_Builder::new_with_files("data/shaders/text.vert", "data/shaders/text.frag"),
+        shader: Shader_Builder::new_with_files("shaders/text.vert", "shaders/text.frag"),
         proj_loc: 0,
     };
     renderer.proj_loc = renderer.shader.get_uniform_location("proj");
@@ -0,0 +1,17 @@
+/*
+    Copyright 2013 Jesse 'Jeaye' Wilkerson
+    See licensing in LICENSE file, or at:
+        http://www.opensource.org/licenses/BSD-3-Clause
+
+    File: vfs/mod.rs
+    Author: Jesse 'Jeaye' Wilkerson
+    Description:
+      An aggregator of virtual filesystem items.
+*/
+
+pub use self::vfs::{ Vfs, glob_match, to_lowercase };
+pub use self::pk3::Pk3;
+
+mod vfs;
+mod pk3;
+
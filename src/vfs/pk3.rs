@@ -0,0 +1,213 @@
+/*
+    Copyright 2013 Jesse 'Jeaye' Wilkerson
+    See licensing in LICENSE file, or at:
+        http://www.opensource.org/licenses/BSD-3-Clause
+
+    File: vfs/pk3.rs
+    Author: Jesse 'Jeaye' Wilkerson
+    Description:
+      Read-only access to the files within a
+      .pk3 (zip) archive. Only stored and deflated
+      entries are supported, which is all Q3 uses.
+*/
+
+use std::{ io, uint };
+use std::hashmap::HashMap;
+use std::iterator::IteratorUtil;
+use extra::flate;
+
+/* Zip record signatures. */
+static End_Signature: u32 = 0x06054b50;
+static Central_Signature: u32 = 0x02014b50;
+static Local_Signature: u32 = 0x04034b50;
+
+static End_Size: uint = 22;
+static Central_Size: uint = 46;
+static Local_Size: uint = 30;
+/* The end record may be followed by a comment of up to 64K. */
+static Max_Comment: uint = 0xFFFF;
+
+static Method_Stored: u16 = 0;
+static Method_Deflated: u16 = 8;
+
+struct Entry
+{
+  method: u16,
+  compressed_size: uint,
+  size: uint,
+  /* Offset of the entry's local header. */
+  header_offset: uint,
+}
+
+pub struct Pk3
+{
+  file: ~str,
+  /* Keyed by lowercase name; Q3 paths aren't case sensitive. */
+  entries: HashMap<~str, Entry>,
+}
+
+impl Pk3
+{
+  /* Reads the central directory; the entries themselves are read on demand. */
+  pub fn open(file: &str) -> Result<Pk3, ~str>
+  {
+    let fio = match io::file_reader(&Path(file))
+    {
+      Ok(fio) => { fio }
+      Err(err) => { return Err(err); }
+    };
+
+    fio.seek(0, io::SeekEnd);
+    let file_size = fio.tell();
+    if file_size < End_Size
+    { return Err(fmt!("%s is too small to be a pk3", file)); }
+
+    /* Search backward for the end of central directory record. */
+    let tail_size = uint::min(file_size, End_Size + Max_Comment);
+    fio.seek((file_size - tail_size) as int, io::SeekSet);
+    let tail = fio.read_bytes(tail_size);
+
+    let mut end = None;
+    let mut i = tail_size - End_Size;
+    loop
+    {
+      if read_u32(tail, i) == End_Signature
+      { end = Some(i); break; }
+      if i == 0
+      { break; }
+      i -= 1;
+    }
+    let end = match end
+    {
+      Some(end) => { end }
+      None => { return Err(fmt!("%s has no zip directory", file)); }
+    };
+
+    let count = read_u16(tail, end + 10) as uint;
+    let dir_size = read_u32(tail, end + 12) as uint;
+    let dir_offset = read_u32(tail, end + 16) as uint;
+    if dir_offset + dir_size > file_size
+    { return Err(fmt!("%s has a zip directory out of range", file)); }
+
+    fio.seek(dir_offset as int, io::SeekSet);
+    let dir = fio.read_bytes(dir_size);
+
+    let mut pk3 = Pk3 { file: file.to_owned(), entries: HashMap::new::<~str, Entry>() };
+    let mut at = 0;
+    for uint::range(0, count) |_|
+    {
+      if at + Central_Size > dir.len() || read_u32(dir, at) != Central_Signature
+      { return Err(fmt!("%s has a corrupt zip directory", file)); }
+
+      let name_len = read_u16(dir, at + 28) as uint;
+      let extra_len = read_u16(dir, at + 30) as uint;
+      let comment_len = read_u16(dir, at + 32) as uint;
+      if at + Central_Size + name_len > dir.len()
+      { return Err(fmt!("%s has a corrupt zip directory", file)); }
+
+      let name = to_name(dir.slice(at + Central_Size, at + Central_Size + name_len));
+      let entry = Entry
+      {
+        method: read_u16(dir, at + 10),
+        compressed_size: read_u32(dir, at + 20) as uint,
+        size: read_u32(dir, at + 24) as uint,
+        header_offset: read_u32(dir, at + 42) as uint,
+      };
+
+      /* Directories are implied by the file names. */
+      if !name.ends_with("/")
+      { pk3.entries.insert(name, entry); }
+
+      at += Central_Size + name_len + extra_len + comment_len;
+    }
+
+    debug!("VFS: Opened %s with %? files.", file, pk3.entries.len());
+    Ok(pk3)
+  }
+
+  #[inline(always)]
+  pub fn contains(&self, name: &str) -> bool
+  { self.entries.contains_key(&name.to_owned()) }
+
+  /* Names of every file, lowercase. */
+  pub fn names(&self) -> ~[~str]
+  {
+    let mut names = ~[];
+    for self.entries.iter().advance |(name, _)|
+    { names.push(copy *name); }
+    names
+  }
+
+  /* The uncompressed contents of the named (lowercase) file. */
+  pub fn read(&self, name: &str) -> Option<~[u8]>
+  {
+    let entry = match self.entries.find(&name.to_owned())
+    {
+      Some(entry) => { *entry }
+      None => { return None; }
+    };
+
+    let fio = match io::file_reader(&Path(self.file))
+    {
+      Ok(fio) => { fio }
+      Err(err) => { warn!("VFS: Unable to reopen %s: %s", self.file, err); return None; }
+    };
+
+    /* The local header's name and extra lengths may differ from the central directory's. */
+    fio.seek(entry.header_offset as int, io::SeekSet);
+    let header = fio.read_bytes(Local_Size);
+    if header.len() != Local_Size || read_u32(header, 0) != Local_Signature
+    { warn!("VFS: Corrupt entry %s in %s", name, self.file); return None; }
+
+    let skip = (read_u16(header, 26) as uint) + (read_u16(header, 28) as uint);
+    fio.seek((entry.header_offset + Local_Size + skip) as int, io::SeekSet);
+    let data = fio.read_bytes(entry.compressed_size);
+    if data.len() != entry.compressed_size
+    { warn!("VFS: Truncated entry %s in %s", name, self.file); return None; }
+
+    let data = match entry.method
+    {
+      Method_Stored => { data }
+      Method_Deflated => { flate::inflate_bytes(data) }
+      method =>
+      {
+        warn!("VFS: Unsupported compression method %? for %s in %s", method, name, self.file);
+        return None;
+      }
+    };
+
+    if data.len() != entry.size
+    { warn!("VFS: %s in %s inflated to %? bytes; expected %?", name, self.file, data.len(), entry.size); }
+    Some(data)
+  }
+}
+
+#[inline(always)]
+priv fn read_u16(data: &[u8], at: uint) -> u16
+{ (data[at] as u16) | ((data[at + 1] as u16) << 8) }
+
+#[inline(always)]
+priv fn read_u32(data: &[u8], at: uint) -> u32
+{
+  (data[at] as u32) | ((data[at + 1] as u32) << 8) |
+  ((data[at + 2] as u32) << 16) | ((data[at + 3] as u32) << 24)
+}
+
+/* Zip names are forward slashed already; only the case needs fixing. */
+priv fn to_name(bytes: &[u8]) -> ~str
+{
+  let mut name = ~"";
+  for bytes.iter().advance |&b|
+  { name.push_char(to_lower(b as char)); }
+  name
+}
+
+#[inline(always)]
+pub fn to_lower(c: char) -> char
+{
+  if c >= 'A' && c <= 'Z'
+  { ((c as u8) + 32) as char }
+  else
+  { c }
+}
+
@@ -0,0 +1,536 @@
+/*
+    Copyright 2013 Jesse 'Jeaye' Wilkerson
+    See licensing in LICENSE file, or at:
+        http://www.opensource.org/licenses/BSD-3-Clause
+
+    File: vfs/vfs.rs
+    Author: Jesse 'Jeaye' Wilkerson
+    Description:
+      A virtual filesystem of mounted directories
+      and .pk3 archives. Mounts are searched newest
+      first, so later mounts override earlier ones,
+      like Q3's pak ordering.
+*/
+
+use std::{ io, os, str, cast, local_data, libc };
+use std::iterator::IteratorUtil;
+use extra::sort;
+use super::pk3::{ Pk3, to_lower };
+
+enum Mount
+{
+  Directory(~str),
+  Archive(Pk3),
+}
+
+pub struct Vfs
+{
+  /* In mount order; searched in reverse. */
+  mounts: ~[Mount],
+}
+
+impl Vfs
+{
+  /*  Key function used to index our singleton in
+      task-local storage. */
+  priv fn tls_key(_: @@Vfs) { }
+
+  pub fn new() -> @mut Vfs
+  {
+    let vfs = @mut Vfs { mounts: ~[] };
+
+    /* Store the VFS in task-local storage. (singleton) */
+    unsafe
+    {
+      local_data::local_data_set
+      (
+        Vfs::tls_key,
+        @cast::transmute::<@mut Vfs, @Vfs>(vfs)
+      );
+    }
+
+    vfs
+  }
+
+  /* Accesses the singleton VFS from task-local storage. */
+  pub fn get() -> @mut Vfs
+  {
+    unsafe 
+    {
+      cast::transmute::<@Vfs, @mut Vfs>
+      (*local_data::local_data_get(Vfs::tls_key).get())
+    }
+  }
+
+  pub fn mount_dir(&mut self, dir: &str) -> bool
+  {
+    if !os::path_is_dir(&Path(dir))
+    { warn!("VFS: Unable to mount %s; not a directory", dir); return false; }
+
+    debug!("VFS: Mounted %s", dir);
+    self.mounts.push(Directory(trim_root(dir)));
+    true
+  }
+
+  pub fn mount_pk3(&mut self, file: &str) -> bool
+  {
+    match Pk3::open(file)
+    {
+      Ok(pk3) => { self.mounts.push(Archive(pk3)); true }
+      Err(err) => { warn!("VFS: Unable to mount %s: %s", file, err); false }
+    }
+  }
+
+  /* Mounts the directory, then each .pk3 within it in name order,
+     so pak1.pk3 overrides pak0.pk3 which overrides loose files.
+     Fails if the directory or any of its paks can't be mounted,
+     though the rest are still mounted. */
+  pub fn mount_game_dir(&mut self, dir: &str) -> bool
+  {
+    if !self.mount_dir(dir)
+    { return false; }
+
+    let mut paks = ~[];
+    for os::list_dir(&Path(dir)).iter().advance |name|
+    {
+      if to_lowercase(*name).ends_with(".pk3")
+      { paks.push(fmt!("%s/%s", trim_root(dir), *name)); }
+    }
+    sort::quick_sort(paks, |a, b| *a <= *b);
+
+    let mut mounted = true;
+    for paks.iter().advance |pak|
+    {
+      if !self.mount_pk3(*pak)
+      { mounted = false; }
+    }
+    mounted
+  }
+
+  /* The whole contents of the file, from the newest mount that has it. */
+  pub fn read(&self, path: &str) -> Option<~[u8]>
+  {
+    let path = match normalize(path)
+    {
+      Some(path) => { path }
+      None => { warn!("VFS: Refusing to read %s", path); return None; }
+    };
+    for self.mounts.rev_iter().advance |mount|
+    {
+      match *mount
+      {
+        Directory(ref dir) =>
+        {
+          let full = Path(fmt!("%s/%s", *dir, path));
+          if os::path_exists(&full) && !os::path_is_dir(&full)
+          {
+            match io::read_whole_file(&full)
+            {
+              Ok(data) => { return Some(data); }
+              Err(err) => { warn!("VFS: Unable to read %s: %s", full.to_str(), err); }
+            }
+          }
+        }
+        Archive(ref pk3) =>
+        {
+          let name = to_lowercase(path);
+          if pk3.contains(name)
+          { return pk3.read(name); }
+        }
+      }
+    }
+    None
+  }
+
+  pub fn read_str(&self, path: &str) -> Option<~str>
+  {
+    match self.read(path)
+    {
+      Some(data) =>
+      {
+        if str::is_utf8(data)
+        { Some(str::from_bytes(data)) }
+        else
+        { warn!("VFS: %s is not valid UTF-8", path); None }
+      }
+      None => { None }
+    }
+  }
+
+  pub fn exists(&self, path: &str) -> bool
+  {
+    let path = match normalize(path)
+    {
+      Some(path) => { path }
+      None => { return false; }
+    };
+    for self.mounts.rev_iter().advance |mount|
+    {
+      match *mount
+      {
+        Directory(ref dir) =>
+        {
+          let full = Path(fmt!("%s/%s", *dir, path));
+          if os::path_exists(&full) && !os::path_is_dir(&full)
+          { return true; }
+        }
+        Archive(ref pk3) =>
+        {
+          if pk3.contains(to_lowercase(path))
+          { return true; }
+        }
+      }
+    }
+    false
+  }
+
+  /* Modification time of a loose file, for reloading while running;
+     files within archives don't have one. */
+  pub fn modified_time(&self, path: &str) -> Option<libc::time_t>
+  {
+    let path = match normalize(path)
+    {
+      Some(path) => { path }
+      None => { return None; }
+    };
+    for self.mounts.rev_iter().advance |mount|
+    {
+      match *mount
+      {
+        Directory(ref dir) =>
+        {
+          match Path(fmt!("%s/%s", *dir, path)).stat()
+          {
+            Some(ref st) => { return Some(st.st_mtime); }
+            None => { }
+          }
+        }
+        Archive(ref pk3) =>
+        {
+          if pk3.contains(to_lowercase(path))
+          { return None; }
+        }
+      }
+    }
+    None
+  }
+
+  /* Every file matching the pattern, sorted and without duplicates.
+     '*' matches within a single directory and '?' matches one character.
+     Ex: list("maps/*.bsp") */
+  pub fn list(&self, pattern: &str) -> ~[~str]
+  {
+    let pattern = match normalize(pattern)
+    {
+      Some(pattern) => { to_lowercase(pattern) }
+      None => { return ~[]; }
+    };
+    let mut found = ~[];
+    for self.mounts.iter().advance |mount|
+    {
+      match *mount
+      {
+        Directory(ref dir) =>
+        {
+          let root = Path(*dir);
+          do os::walk_dir(&root) |file|
+          {
+            let full = file.to_str();
+            if !os::path_is_dir(file) && full.len() > dir.len() + 1
+            {
+              let name = full.slice_from(dir.len() + 1).to_owned();
+              if glob_match(pattern, to_lowercase(name))
+              { found.push(name); }
+            }
+            true
+          };
+        }
+        Archive(ref pk3) =>
+        {
+          for pk3.names().iter().advance |name|
+          {
+            if glob_match(pattern, *name)
+            { found.push(copy *name); }
+          }
+        }
+      }
+    }
+
+    sort::quick_sort(found, |a, b| to_lowercase(*a) <= to_lowercase(*b));
+    let mut unique: ~[~str] = ~[];
+    for found.iter().advance |name|
+    {
+      let len = unique.len();
+      if len == 0 || to_lowercase(unique[len - 1]) != to_lowercase(*name)
+      { unique.push(copy *name); }
+    }
+    unique
+  }
+}
+
+/* Whether the name matches the glob; both are expected to be normalized. */
+pub fn glob_match(pattern: &str, name: &str) -> bool
+{
+  let pattern = pattern.as_bytes();
+  let name = name.as_bytes();
+  glob_match_r(pattern, 0, name, 0)
+}
+
+priv fn glob_match_r(pattern: &[u8], p: uint, name: &[u8], n: uint) -> bool
+{
+  if p == pattern.len()
+  { return n == name.len(); }
+
+  match pattern[p] as char
+  {
+    '*' =>
+    {
+      /* Try every length of match that stays within one directory. */
+      let mut end = n;
+      loop
+      {
+        if glob_match_r(pattern, p + 1, name, end)
+        { return true; }
+        if end == name.len() || name[end] as char == '/'
+        { return false; }
+        end += 1;
+      }
+    }
+    '?' =>
+    { n < name.len() && name[n] as char != '/' && glob_match_r(pattern, p + 1, name, n + 1) }
+    c =>
+    { n < name.len() && name[n] as char == c && glob_match_r(pattern, p + 1, name, n + 1) }
+  }
+}
+
+/* Forward slashes and no leading "./" or "/". Names can come from
+   maps and scripts, so any ".." is refused rather than resolved;
+   otherwise they could reach outside of the mounted directories. */
+priv fn normalize(path: &str) -> Option<~str>
+{
+  let mut path = path.replace("\\", "/");
+  while path.starts_with("./")
+  { path = path.slice_from(2).to_owned(); }
+
+  for path.split_iter('/').advance |part|
+  {
+    if part == ".."
+    { return None; }
+  }
+  Some(trim_slashes(path))
+}
+
+/* Mount roots keep their leading '/', so absolute ones stay absolute. */
+priv fn trim_root(dir: &str) -> ~str
+{
+  let mut end = dir.len();
+  while end > 1 && dir[end - 1] as char == '/'
+  { end -= 1; }
+  dir.slice(0, end).to_owned()
+}
+
+priv fn trim_slashes(path: &str) -> ~str
+{
+  let mut start = 0;
+  let mut end = path.len();
+  while start < end && path[start] as char == '/'
+  { start += 1; }
+  while end > start + 1 && path[end - 1] as char == '/'
+  { end -= 1; }
+  path.slice(start, end).to_owned()
+}
+
+/* ASCII only, which is all Q3 paths use. */
+pub fn to_lowercase(s: &str) -> ~str
+{
+  let mut out = ~"";
+  for s.iter().advance |c|
+  { out.push_char(to_lower(c)); }
+  out
+}
+
+
+#[cfg(test)]
+mod test
+{
+  use std::{ io, os, str };
+  use std::iterator::IteratorUtil;
+  use extra::{ flate, tempfile };
+  use super::{ Vfs, glob_match, normalize, trim_root };
+
+  /* Unique per run, so parallel and repeated runs don't collide. */
+  fn temp_dir(name: &str) -> Path
+  { tempfile::mkdtemp(&os::tmpdir(), name).expect("Unable to create a temporary directory") }
+
+  fn push_u16(out: &mut ~[u8], value: uint)
+  { out.push(value as u8); out.push((value >> 8) as u8); }
+
+  fn push_u32(out: &mut ~[u8], value: uint)
+  { push_u16(out, value & 0xFFFF); push_u16(out, value >> 16); }
+
+  /* A minimal zip of (name, contents, deflated) entries; nothing reads the CRCs. */
+  fn write_pk3(file: &str, entries: &[(&str, &str, bool)])
+  {
+    let mut out = ~[];
+    let mut dir = ~[];
+    for entries.iter().advance |&(name, contents, deflated)|
+    {
+      let data = if deflated { flate::deflate_bytes(contents.as_bytes()) }
+                 else { contents.as_bytes().to_owned() };
+      let method = if deflated { 8 } else { 0 };
+      let offset = out.len();
+
+      push_u32(&mut out, 0x04034b50);
+      push_u16(&mut out, 20); push_u16(&mut out, 0); push_u16(&mut out, method);
+      push_u32(&mut out, 0); push_u32(&mut out, 0);
+      push_u32(&mut out, data.len()); push_u32(&mut out, contents.len());
+      push_u16(&mut out, name.len()); push_u16(&mut out, 0);
+      out.push_all(name.as_bytes());
+      out.push_all(data);
+
+      push_u32(&mut dir, 0x02014b50);
+      push_u16(&mut dir, 20); push_u16(&mut dir, 20); push_u16(&mut dir, 0); push_u16(&mut dir, method);
+      push_u32(&mut dir, 0); push_u32(&mut dir, 0);
+      push_u32(&mut dir, data.len()); push_u32(&mut dir, contents.len());
+      push_u16(&mut dir, name.len()); push_u16(&mut dir, 0); push_u16(&mut dir, 0);
+      push_u16(&mut dir, 0); push_u16(&mut dir, 0); push_u32(&mut dir, 0);
+      push_u32(&mut dir, offset);
+      dir.push_all(name.as_bytes());
+    }
+
+    let dir_offset = out.len();
+    out.push_all(dir);
+    push_u32(&mut out, 0x06054b50);
+    push_u16(&mut out, 0); push_u16(&mut out, 0);
+    push_u16(&mut out, entries.len()); push_u16(&mut out, entries.len());
+    push_u32(&mut out, dir.len()); push_u32(&mut out, dir_offset);
+    push_u16(&mut out, 0);
+
+    match io::file_writer(&Path(file), [io::Create, io::Truncate])
+    {
+      Ok(writer) => { writer.write(out); }
+      Err(err) => { fail!(err); }
+    }
+  }
+
+  fn write_file(file: &Path, contents: &str)
+  {
+    assert!(os::mkdir_recursive(&file.dir_path(), 0x1ed)); /* 755 */
+    match io::file_writer(file, [io::Create, io::Truncate])
+    {
+      Ok(writer) => { writer.write_str(contents); }
+      Err(err) => { fail!(err); }
+    }
+  }
+
+  fn read(vfs: &Vfs, path: &str) -> Option<~str>
+  {
+    match vfs.read(path)
+    {
+      Some(data) => { Some(str::from_bytes(data)) }
+      None => { None }
+    }
+  }
+
+  #[test]
+  fn glob()
+  {
+    assert!(glob_match("maps/*.bsp", "maps/q3ctf1.bsp"));
+    assert!(glob_match("maps/*", "maps/"));
+    assert!(glob_match("maps/q3ctf?.bsp", "maps/q3ctf1.bsp"));
+    assert!(glob_match("*/*.shader", "scripts/base.shader"));
+    assert!(!glob_match("maps/q3ctf?.bsp", "maps/q3ctf.bsp"));
+    assert!(!glob_match("maps/*.bsp", "maps/q3ctf1.bsp.bak"));
+
+    /* Neither wildcard crosses a directory. */
+    assert!(!glob_match("maps/*.bsp", "maps/old/q3ctf1.bsp"));
+    assert!(!glob_match("maps?q3ctf1.bsp", "maps/q3ctf1.bsp"));
+    assert!(!glob_match("*.bsp", "maps/q3ctf1.bsp"));
+  }
+
+  #[test]
+  fn roots()
+  {
+    assert_eq!(trim_root("/opt/quake3/baseq3/"), ~"/opt/quake3/baseq3");
+    assert_eq!(trim_root("data//"), ~"data");
+    assert_eq!(trim_root("/"), ~"/");
+
+    /* Absolute roots are used as they are, trailing slash or not. */
+    let root = temp_dir("q3-test-vfs-roots");
+    assert!(root.is_absolute);
+    write_file(&root.push("a.txt"), "a");
+    let vfs = Vfs::new();
+    assert!(vfs.mount_dir(root.to_str() + "/"));
+    assert_eq!(read(vfs, "a.txt"), Some(~"a"));
+
+    /* A broken pak fails the game directory, but the rest still mount. */
+    write_file(&root.push("pak0.pk3"), "not a zip");
+    write_pk3(root.push("pak1.pk3").to_str(), [("b.txt", "b", false)]);
+    let vfs = Vfs::new();
+    assert!(!vfs.mount_game_dir(root.to_str()));
+    assert_eq!(read(vfs, "a.txt"), Some(~"a"));
+    assert_eq!(read(vfs, "b.txt"), Some(~"b"));
+  }
+
+  #[test]
+  fn parent_paths()
+  {
+    assert_eq!(normalize("./maps\\q3ctf1.bsp"), Some(~"maps/q3ctf1.bsp"));
+    assert_eq!(normalize("/maps/"), Some(~"maps"));
+    assert_eq!(normalize("maps/..bsp"), Some(~"maps/..bsp"));
+    assert_eq!(normalize(".."), None);
+    assert_eq!(normalize("../secret"), None);
+    assert_eq!(normalize("maps/../../secret"), None);
+    assert_eq!(normalize("maps\\..\\secret"), None);
+
+    /* Nothing outside of the mount is reachable. */
+    let root = temp_dir("q3-test-vfs-parent");
+    write_file(&root.push("secret.cfg"), "secret");
+    write_file(&root.push_many([~"game", ~"maps", ~"a.txt"]), "a");
+
+    let vfs = Vfs::new();
+    assert!(vfs.mount_dir(root.push("game").to_str()));
+    assert_eq!(read(vfs, "./maps/a.txt"), Some(~"a"));
+    assert_eq!(read(vfs, "../secret.cfg"), None);
+    assert_eq!(read(vfs, "maps/../../secret.cfg"), None);
+    assert!(!vfs.exists("../secret.cfg"));
+    assert!(vfs.modified_time("../secret.cfg").is_none());
+    assert_eq!(vfs.list("../*.cfg").len(), 0);
+  }
+
+  #[test]
+  fn pk3()
+  {
+    let root = temp_dir("q3-test-vfs-pk3");
+    write_file(&root.push_many([~"scripts", ~"loose.shader"]), "loose");
+    write_file(&root.push_many([~"scripts", ~"base.shader"]), "loose base");
+    write_pk3(root.push("pak0.pk3").to_str(),
+              [("scripts/Base.shader", "pak0 base", true),
+               ("scripts/", "", false),
+               ("maps/q3ctf1.bsp", "pak0 map", false)]);
+    write_pk3(root.push("pak1.pk3").to_str(),
+              [("maps/Q3CTF1.BSP", "pak1 map", true)]);
+
+    let vfs = Vfs::new();
+    assert!(vfs.mount_game_dir(root.to_str()));
+
+    /* Later paks override earlier ones, which override loose files. */
+    assert_eq!(read(vfs, "maps/q3ctf1.bsp"), Some(~"pak1 map"));
+    assert_eq!(read(vfs, "scripts/base.shader"), Some(~"pak0 base"));
+    assert_eq!(read(vfs, "scripts/loose.shader"), Some(~"loose"));
+
+    /* Archive lookups ignore case. */
+    assert_eq!(read(vfs, "SCRIPTS\\BASE.shader"), Some(~"pak0 base"));
+    assert!(vfs.exists("Maps/Q3ctf1.bsp"));
+    assert!(!vfs.exists("maps/q3ctf2.bsp"));
+    assert!(!vfs.exists("scripts"));
+
+    /* Archives have no modification times. */
+    assert!(vfs.modified_time("maps/q3ctf1.bsp").is_none());
+    assert!(vfs.modified_time("scripts/loose.shader").is_some());
+
+    assert_eq!(vfs.list("scripts/*.shader"), ~[~"scripts/base.shader", ~"scripts/loose.shader"]);
+    assert_eq!(vfs.list("maps/*.bsp"), ~[~"maps/q3ctf1.bsp"]);
+    assert_eq!(vfs.list("*.pk3"), ~[~"pak0.pk3", ~"pak1.pk3"]);
+  }
+}